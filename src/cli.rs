@@ -1,5 +1,18 @@
 use clap::Parser;
 
+use crate::format::ColorWhen;
+use crate::net::util::BackoffKind;
+
+/// The exit statuses listed by `--help`, as mapped by [`crate::net::exit_code`].
+const EXIT_STATUS_HELP: &str = "\
+Exit status:
+    0  the session completed; with -z, every port is open
+    1  any other error
+    2  the connection was refused; with -z, a port is closed
+    3  connecting or accepting timed out (-w); with -z, a probe timed out
+       or --scan-deadline passed
+  130  interrupted by SIGINT or SIGTERM";
+
 /// CLI args with a full set of OpenBSD-netcat-like flags.
 #[derive(Parser, Debug)]
 #[command(
@@ -8,11 +21,12 @@ use clap::Parser;
     about,
     long_about = None,
     disable_version_flag = true, // 把 `-V` 留给 rtable
+    after_long_help = EXIT_STATUS_HELP,
 )]
 pub struct Args {
     /* ---------- 地址族 ---------- */
     /// Force IPv4
-    #[arg(short = '4', long = "ipv4")]
+    #[arg(short = '4', long = "ipv4", conflicts_with = "ipv6")]
     pub ipv4: bool,
 
     /// Force IPv6
@@ -24,10 +38,48 @@ pub struct Args {
     #[arg(short, long)]
     pub udp: bool,
 
-    /// Use Unix-domain socket
+    /// In UDP client mode, drop datagrams not sent by the destination
+    #[arg(long = "udp-strict-peer")]
+    pub udp_strict_peer: bool,
+
+    /// In UDP client mode, leave the socket unconnected and show datagrams
+    /// from any source
+    #[arg(long = "udp-loose", conflicts_with = "udp_strict_peer")]
+    pub udp_loose: bool,
+
+    /// In UDP listen mode, send replies with the TOS of the last received datagram
+    #[arg(long = "dscp-passthrough")]
+    pub dscp_passthrough: bool,
+
+    /// In UDP listen mode with a multicast group as the address, join it on
+    /// this interface: an IPv4 address, or an interface index for IPv6 groups
+    #[arg(long = "multicast-iface", value_name = "ADDR|INDEX")]
+    pub multicast_iface: Option<String>,
+
+    /// In UDP client mode, send each stdin line as its own datagram
+    #[arg(long = "line-datagrams", visible_alias = "newline-delimited-datagrams")]
+    pub line_datagrams: bool,
+
+    /// Keep the trailing newline in each --line-datagrams datagram
+    #[arg(long = "keep-newline", requires = "line_datagrams")]
+    pub keep_newline: bool,
+
+    /// Use a Unix-domain socket; the destination is its path
     #[arg(short = 'U')]
     pub unix: bool,
 
+    /// With -U -l, print the uid, gid and pid of each connecting peer
+    #[arg(
+        long = "print-peer-cred",
+        visible_alias = "unix-credentials",
+        requires_all = ["unix", "listen"]
+    )]
+    pub print_peer_cred: bool,
+
+    /// Fail instead of ever sending data in plaintext
+    #[arg(long = "require-tls")]
+    pub require_tls: bool,
+
     /* ---------- 模式/行为 ---------- */
     /// Listen mode (inbound connections)
     #[arg(short, long)]
@@ -37,21 +89,160 @@ pub struct Args {
     #[arg(short = 'z')]
     pub zero: bool,
 
+    /// Listen mode: send everything a client sends straight back (stdin/stdout unused)
+    #[arg(long = "echo-server")]
+    pub echo_server: bool,
+
+    /// Listen mode with -k: relay every line a client sends to all the others,
+    /// prefixed with its nickname (stdin/stdout unused)
+    #[arg(
+        long = "chat",
+        requires_all = ["listen", "keep_open"],
+        conflicts_with_all = ["echo_server", "exec", "upload"]
+    )]
+    pub chat: bool,
+
+    /// Run CMD through `sh -c` with the connection as its stdin/stdout (needs
+    /// the `exec` build feature)
+    #[arg(
+        short = 'e',
+        long = "exec",
+        value_name = "CMD",
+        conflicts_with = "echo_server"
+    )]
+    pub exec: Option<String>,
+
+    /// Listen mode: wait SECS before sending anything (with --echo-server, before each echo)
+    #[arg(
+        long = "reply-delay",
+        visible_alias = "listen-reply-delay",
+        value_name = "SECS"
+    )]
+    pub reply_delay: Option<f64>,
+
     /// Keep inbound sockets open for multiple connects
     #[arg(short = 'k', long = "keep-open")]
     pub keep_open: bool,
 
+    /// With -k, exit after N connections; with --chat, hold at most N
+    /// clients at once
+    #[arg(
+        long = "max-conns",
+        value_name = "N",
+        requires = "keep_open",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_conns: Option<u32>,
+
+    /// With -k, accept at most N connections per second; later ones wait
+    /// in the listen backlog
+    #[arg(
+        long = "accept-rate",
+        value_name = "N",
+        requires = "keep_open",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub accept_rate: Option<u32>,
+
+    /// Listen mode: set SO_REUSEPORT so several listeners can share the port
+    #[arg(long = "reuseport")]
+    pub reuseport: bool,
+
+    /// Keep sending input after the peer stops sending; end once both sides are done
+    #[arg(long = "half-close")]
+    pub half_close: bool,
+
+    /// Don't shut down our side of the connection when input ends
+    #[arg(long = "no-shutdown")]
+    pub no_shutdown: bool,
+
+    /// Only send input; nothing is read from the connection
+    #[arg(
+        long = "send-only",
+        conflicts_with_all = ["recv_only", "echo_server", "exec"]
+    )]
+    pub send_only: bool,
+
+    /// Only print what the peer sends; stdin is never read
+    #[arg(long = "recv-only", conflicts_with_all = ["upload", "echo_server", "exec"])]
+    pub recv_only: bool,
+
     /// Do not resolve names (no DNS)
     #[arg(short = 'n', long = "numeric")]
     pub numeric: bool,
 
-    /// Verbose
-    #[arg(short, long)]
-    pub verbose: bool,
+    /// Verbose; repeat for more detail (-vv: byte counts and peers, -vvv:
+    /// socket events and timeouts)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Suppress all diagnostics on stderr (overrides -v)
+    #[arg(short = 'Q', long = "quiet")]
+    pub quiet: bool,
+
+    /// Print `READY <addr>` on stderr once the listener is bound
+    #[arg(long = "print-ready")]
+    pub print_ready: bool,
+
+    /// Write the ready line to this inherited file descriptor instead of stderr
+    #[arg(long = "ready-fd", value_name = "FD")]
+    pub ready_fd: Option<i32>,
+
+    /// Print the TCP handshake time (`connected in 1.2ms`) on stderr
+    #[arg(long = "report-rtt", visible_alias = "connect-report-rtt")]
+    pub report_rtt: bool,
+
+    /// Append a JSON (or CSV, for *.csv) record per finished session to FILE
+    #[arg(long = "stats-file", value_name = "FILE")]
+    pub stats_file: Option<std::path::PathBuf>,
+
+    /// Shell command run (not connected to the socket) when a client connects; peer in $RCAT_PEER
+    #[arg(long = "on-connect", value_name = "CMD")]
+    pub on_connect: Option<String>,
+
+    /// Shell command run when an accepted connection ends; peer in $RCAT_PEER
+    #[arg(long = "on-disconnect", value_name = "CMD")]
+    pub on_disconnect: Option<String>,
+
+    /// Shell command given the unconnected client socket as fd $RCAT_SOCKET_FD
+    #[arg(
+        long = "socket-hook",
+        visible_alias = "bind-before-connect-fd",
+        value_name = "CMD"
+    )]
+    pub socket_hook: Option<String>,
+
+    /// Print the effective socket options on stderr once the socket is set up
+    #[arg(long = "dump-sockopts")]
+    pub dump_sockopts: bool,
+
+    /// Show each sent (>>) and received (<<) chunk on stderr
+    #[arg(long = "annotate")]
+    pub annotate: bool,
+
+    /// Record TCP session traffic to FILE in pcap format
+    #[arg(long = "pcap", value_name = "FILE")]
+    pub pcap: Option<std::path::PathBuf>,
+
+    /// Append all sent (>) and received (<) data to FILE
+    #[arg(long = "log-file", value_name = "FILE")]
+    pub log_file: Option<std::path::PathBuf>,
+
+    /// Also copy sent TCP data to inherited file descriptor N
+    #[arg(long = "tee-sent-fd", value_name = "N")]
+    pub tee_sent_fd: Option<i32>,
+
+    /// Also copy received TCP data to inherited file descriptor N
+    #[arg(long = "tee-recv-fd", value_name = "N")]
+    pub tee_recv_fd: Option<i32>,
 
     /* ---------- 超时/间隔 ---------- */
     /// Interval between lines sent (seconds)
-    #[arg(short = 'i', long = "interval")]
+    #[arg(
+        short = 'i',
+        long = "interval",
+        conflicts_with_all = ["turn_based", "idle_banner"]
+    )]
     pub interval: Option<f64>,
 
     /// Timeout for connects and final net reads (seconds)
@@ -62,6 +253,38 @@ pub struct Args {
     #[arg(short = 'q', long = "quit-after")]
     pub quit_after: Option<u64>,
 
+    /// Batch received output, flushing stdout at most every MILLIS ms
+    #[arg(long = "flush-interval", value_name = "MILLIS")]
+    pub flush_interval: Option<u64>,
+
+    /// Send at most RATE bytes per second over TCP (K/M/G suffixes)
+    #[arg(long = "rate", value_name = "RATE", value_parser = parse_size)]
+    pub rate: Option<u64>,
+
+    /// Retry failed DNS resolution of the destination up to N times
+    #[arg(long = "retry-resolve", value_name = "N", default_value_t = 0)]
+    pub retry_resolve: u32,
+
+    /// Retry a refused or timed-out TCP connect up to N times
+    #[arg(long = "retry", value_name = "N", default_value_t = 0)]
+    pub retry: u32,
+
+    /// How the delay grows between retries
+    #[arg(long = "backoff", value_enum, default_value_t = BackoffKind::Exponential)]
+    pub backoff: BackoffKind,
+
+    /// First retry delay (seconds)
+    #[arg(long = "backoff-base", value_name = "SECS", default_value_t = 0.2)]
+    pub backoff_base: f64,
+
+    /// Longest single retry delay (seconds)
+    #[arg(long = "backoff-max", value_name = "SECS", default_value_t = 5.0)]
+    pub backoff_max: f64,
+
+    /// Randomise retry delays to avoid synchronised retries
+    #[arg(long = "backoff-jitter")]
+    pub backoff_jitter: bool,
+
     /* ---------- 源地址/端口 ---------- */
     /// Source address to bind
     #[arg(short = 's', long = "source")]
@@ -71,21 +294,31 @@ pub struct Args {
     #[arg(short = 'p', value_parser = clap::value_parser!(u16))]
     pub source_port: Option<u16>,
 
+    /// With -z or -r, bind each connection to a random source port in LO-HI;
+    /// see also --connect-source-cycle
+    #[arg(
+        long = "source-port-range",
+        value_name = "LO-HI",
+        conflicts_with = "source_port"
+    )]
+    pub source_port_range: Option<String>,
+
     /* ---------- 代理 ---------- */
-    /// Proxy protocol (e.g. "socks5", "http")
+    /// Proxy protocol: "5"/"socks5" (the default) or "connect"/"http"
     #[arg(short = 'X', long = "proxy-protocol")]
     pub proxy_proto: Option<String>,
 
-    /// Proxy address (host[:port])
+    /// Proxy address (host[:port]; port 1080 for SOCKS, 3128 for HTTP)
     #[arg(short = 'x', long = "proxy")]
     pub proxy: Option<String>,
 
-    /// Proxy username
+    /// Proxy username; the password is read from RCAT_PROXY_PASSWORD
     #[arg(short = 'P', long = "proxy-user")]
     pub proxy_username: Option<String>,
 
     /* ---------- 杂项 ---------- */
-    /// TOS keyword
+    /// IP TOS of outgoing connections: lowdelay, throughput, reliability,
+    /// mincost or a number such as 0x10 (Linux only)
     #[arg(short = 'T', long = "tos")]
     pub tos: Option<String>,
 
@@ -93,49 +326,442 @@ pub struct Args {
     #[arg(short = 'V', long = "rtable")]
     pub rtable: Option<String>,
 
-    /// Send buffer length (alias -I)
+    /// Bytes read from the input per send (default 8192)
     #[arg(short = 'I', long = "send-length")]
     pub send_length: Option<usize>,
 
-    /// Receive buffer length (alias -O)
+    /// Bytes read from the socket at a time (default 65536)
     #[arg(short = 'O', long = "recv-length")]
     pub recv_length: Option<usize>,
 
     /* ---------- 新增：OpenBSD 其余单字母选项 ---------- */
-    /// Allow broadcast
-    #[arg(short = 'b', long = "broadcast")]
+    /// Allow sending to (and replying to) broadcast addresses; replies are
+    /// taken from any host
+    #[arg(short = 'b', long = "broadcast", conflicts_with = "udp_strict_peer")]
     pub broadcast: bool,
 
     /// Send CR+LF on line-feed
-    #[arg(short = 'C', long = "crlf")]
+    #[arg(short = 'C', long = "crlf", conflicts_with = "strip_cr")]
     pub crlf: bool,
 
     /// Enable socket debugging
     #[arg(short = 'd', long = "debug")]
     pub debug: bool,
 
-    /// Disable TCP delayed ack
+    /// Disable Nagle's algorithm (TCP_NODELAY)
     #[arg(short = 'D', long = "no-delay-ack")]
     pub no_delay_ack: bool,
 
-    /// Randomize port numbers in range
+    /// Send TCP keepalive probes on idle connections (SO_KEEPALIVE)
+    #[arg(long = "keepalive")]
+    pub keepalive: bool,
+
+    /// Seconds of idleness before the first keepalive probe
+    #[arg(
+        long = "keepalive-idle",
+        value_name = "SECS",
+        requires = "keepalive",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub keepalive_idle: Option<u32>,
+
+    /// Seconds between keepalive probes
+    #[arg(
+        long = "keepalive-interval",
+        value_name = "SECS",
+        requires = "keepalive",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub keepalive_interval: Option<u32>,
+
+    /// Scan the ports given to -z in random order
     #[arg(short = 'r', long = "random")]
     pub random: bool,
 
+    /// With -z, send a lone SYN per port and never complete the handshake
+    /// (Linux, root or CAP_NET_RAW; needs the `syn-scan` build feature)
+    #[arg(long = "syn", visible_alias = "half-open-scan", requires = "zero")]
+    pub syn: bool,
+
+    /// Stop a -z scan after SECS in all, listing the ports left unscanned
+    #[arg(long = "scan-deadline", value_name = "SECS", requires = "zero")]
+    pub scan_deadline: Option<f64>,
+
     /// Enable TCP MD5 signature option (RFC 2385)
     #[arg(short = 'S', long = "md5sig")]
     pub md5sig: bool,
 
-    /// Send RFC 854 DON’T/WON’T on stdin EOF
+    /// Shared key for -S (up to 80 bytes)
+    #[arg(long = "md5-key", value_name = "KEY", requires = "md5sig")]
+    pub md5_key: Option<String>,
+
+    /// Answer telnet option negotiation with DON'T/WON'T, hiding it from output
     #[arg(short = 't', long = "telnet")]
     pub telnet: bool,
 
+    /// Guess the peer's protocol from its first bytes (reported with -v)
+    #[arg(long = "detect-protocol")]
+    pub detect_protocol: bool,
+
+    /// Print only the HTTP status line and headers, then close without reading the body
+    #[arg(long = "headers-only", visible_alias = "response-headers-only")]
+    pub headers_only: bool,
+
+    /// Discover the path MTU to the destination with don't-fragment UDP probes
+    #[arg(long = "mtu-probe")]
+    pub mtu_probe: bool,
+
+    /* ---------- 数据变换 ---------- */
+    /// Bytes sent right before half-closing on stdin EOF (escapes like `\r\n` allowed)
+    #[arg(
+        long = "eof-marker",
+        visible_alias = "stdin-eof-marker",
+        value_name = "BYTES"
+    )]
+    pub eof_marker: Option<String>,
+
+    /// Read input from FILE instead of stdin
+    #[arg(
+        long = "input-file",
+        value_name = "FILE",
+        conflicts_with_all = ["upload", "recv_only"]
+    )]
+    pub input_file: Option<std::path::PathBuf>,
+
+    /// Write received data to FILE instead of stdout, replacing its contents
+    #[arg(long = "output-file", value_name = "FILE")]
+    pub output_file: Option<std::path::PathBuf>,
+
+    /// Append to the --output-file instead of replacing it
+    #[arg(long = "append", requires = "output_file")]
+    pub append: bool,
+
+    /// Send FILE instead of stdin, reporting progress on stderr
+    #[arg(long = "upload", value_name = "FILE")]
+    pub upload: Option<std::path::PathBuf>,
+
+    /// Send FILE instead of stdin with zero-copy sendfile(2) where possible (Linux)
+    #[arg(long = "sendfile", value_name = "FILE", conflicts_with = "upload")]
+    pub sendfile: Option<std::path::PathBuf>,
+
+    /// Skip this many bytes of the upload file (to resume an interrupted upload)
+    #[arg(
+        long = "resume-from",
+        value_name = "BYTES",
+        requires = "upload",
+        default_value_t = 0
+    )]
+    pub resume_from: u64,
+
+    /// Replace FROM with TO in data sent to the peer (repeatable, escapes allowed)
+    #[arg(
+        long = "replace-send",
+        visible_alias = "replace",
+        value_name = "FROM=TO"
+    )]
+    pub replace_send: Vec<String>,
+
+    /// Turn CRLF into LF in data sent to the peer (the inverse of -C)
+    #[arg(long = "strip-cr", visible_alias = "input-eol-strip")]
+    pub strip_cr: bool,
+
+    /// Replace FROM with TO in data received from the peer (repeatable, escapes allowed)
+    #[arg(long = "replace-recv", value_name = "FROM=TO")]
+    pub replace_recv: Vec<String>,
+
+    /// Print received data as a hex+ASCII dump (like `hexdump -C`) instead of raw
+    #[arg(long = "hexdump")]
+    pub hexdump: bool,
+
+    /// Colour the --hexdump by byte kind: printable, control and non-ASCII
+    #[arg(long = "color-hexdump", requires = "hexdump")]
+    pub color_hexdump: bool,
+
+    /// When --color-hexdump may colour the output
+    #[arg(long = "color", value_name = "WHEN", value_enum, default_value_t = ColorWhen::Auto)]
+    pub color: ColorWhen,
+
+    /// Start each line of received output with the local time, as [HH:MM:SS.mmm]
+    #[arg(long = "timestamp")]
+    pub timestamp: bool,
+
+    /// Close the connection if the peer sends more than SIZE bytes (K/M/G suffixes) without a delimiter
+    #[arg(long = "max-message-size", value_name = "SIZE", value_parser = parse_size)]
+    pub max_message_size: Option<u64>,
+
+    /// Delimiter ending a message for --max-message-size (escapes allowed)
+    #[arg(
+        long = "message-delimiter",
+        value_name = "DELIM",
+        requires = "max_message_size",
+        default_value = "\\n"
+    )]
+    pub message_delimiter: String,
+
+    /// Text sent to an idle client in listen mode (escapes allowed)
+    #[arg(long = "idle-banner", value_name = "TEXT", requires = "listen")]
+    pub idle_banner: Option<String>,
+
+    /// Seconds of client silence before the idle banner is sent
+    #[arg(
+        long = "idle-banner-after",
+        value_name = "SECS",
+        default_value_t = 10.0
+    )]
+    pub idle_banner_after: f64,
+
+    /// Client mode: send one input line, then show the reply before the next
+    #[arg(long = "turn-based", visible_alias = "half-duplex-switch")]
+    pub turn_based: bool,
+
+    /// Seconds of peer silence that mark the end of a reply in --turn-based
+    #[arg(
+        long = "turn-idle",
+        value_name = "SECS",
+        requires = "turn_based",
+        default_value_t = 0.5
+    )]
+    pub turn_idle: f64,
+
+    /// Client mode: reconnect and resend stdin until a response contains PATTERN
+    #[arg(long = "repeat-until-match", value_name = "PATTERN")]
+    pub repeat_until_match: Option<String>,
+
+    /// Client mode: also send the input to HOST:PORT (repeatable); replies
+    /// are shown line by line behind the address they came from
+    #[arg(
+        long = "also",
+        value_name = "HOST:PORT",
+        conflicts_with_all = [
+            "zero", "upload", "sendfile", "exec", "proxy", "repeat_until_match",
+            "turn_based", "interval", "telnet", "send_only", "recv_only", "half_close",
+            "headers_only", "detect_protocol", "max_message_size", "replace_recv",
+            "hexdump", "timestamp", "flush_interval", "pcap", "stats_file",
+            "report_rtt", "retry",
+        ]
+    )]
+    pub also: Vec<String>,
+
+    /// Seconds between --repeat-until-match attempts
+    #[arg(
+        long = "repeat-delay",
+        value_name = "SECS",
+        requires = "repeat_until_match",
+        default_value_t = 1.0
+    )]
+    pub repeat_delay: f64,
+
+    /// Give up --repeat-until-match after N attempts (0: never)
+    #[arg(
+        long = "repeat-max",
+        value_name = "N",
+        requires = "repeat_until_match",
+        default_value_t = 0
+    )]
+    pub repeat_max: u32,
+
+    /// Send FILE on each --repeat-until-match attempt instead of the input,
+    /// with every `{{i}}` in it replaced by the attempt number
+    #[arg(
+        long = "input-template",
+        value_name = "FILE",
+        requires = "repeat_until_match",
+        conflicts_with_all = ["input_file", "upload", "sendfile"]
+    )]
+    pub input_template: Option<std::path::PathBuf>,
+
+    /// Bind each --repeat-until-match attempt to the next port of
+    /// --source-port-range in turn (in a shuffled order with -r)
+    #[arg(
+        long = "connect-source-cycle",
+        requires_all = ["repeat_until_match", "source_port_range"]
+    )]
+    pub connect_source_cycle: bool,
+
     /* ---------- 位置参数 ---------- */
     /// Destination host (positional)
     #[arg(value_name = "destination")]
     pub destination: Option<String>,
 
-    /// Destination port (positional)
-    #[arg(value_name = "port", value_parser = clap::value_parser!(u16))]
-    pub port: Option<u16>,
-}
\ No newline at end of file
+    /// Destination port; with -z also a range (20-25) or list (22,80,443)
+    #[arg(value_name = "port[s]")]
+    pub port: Option<String>,
+}
+
+/// Interpret backslash escapes in a CLI-supplied byte string.
+///
+/// Supports `\r`, `\n`, `\t`, `\0`, `\\` and `\xHH`; unknown escapes are rejected.
+pub fn unescape(s: &str) -> anyhow::Result<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let esc = *bytes
+            .get(i + 1)
+            .ok_or_else(|| anyhow::anyhow!("trailing backslash in '{}'", s))?;
+        match esc {
+            b'r' => out.push(b'\r'),
+            b'n' => out.push(b'\n'),
+            b't' => out.push(b'\t'),
+            b'0' => out.push(0),
+            b'\\' => out.push(b'\\'),
+            b'x' => {
+                let hex = s
+                    .get(i + 2..i + 4)
+                    .ok_or_else(|| anyhow::anyhow!("truncated \\x escape in '{}'", s))?;
+                let byte = u8::from_str_radix(hex, 16)
+                    .map_err(|_| anyhow::anyhow!("invalid \\x escape '\\x{}' in '{}'", hex, s))?;
+                out.push(byte);
+                i += 2;
+            }
+            other => anyhow::bail!("unknown escape '\\{}' in '{}'", other as char, s),
+        }
+        i += 2;
+    }
+    Ok(out)
+}
+
+/// Parse a `FROM=TO` substitution spec, interpreting escapes on both sides.
+///
+/// The first `=` separates the two halves; write a literal `=` in FROM as `\x3d`.
+pub fn parse_replacement(spec: &str) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let (from, to) = spec
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("replacement '{}' must look like FROM=TO", spec))?;
+    let from = unescape(from)?;
+    if from.is_empty() {
+        anyhow::bail!("replacement '{}' has an empty FROM pattern", spec);
+    }
+    Ok((from, unescape(to)?))
+}
+
+/// Parse a port spec: a single port (`80`), a range (`20-25`, either way
+/// round) or a comma-separated list of either (`22,80,8000-8010`).
+///
+/// Ports come back in the order given, ranges ascending; 0 is rejected.
+pub fn parse_ports(spec: &str) -> anyhow::Result<Vec<u16>> {
+    let port = |s: &str| match s.trim().parse::<u16>() {
+        Ok(0) => anyhow::bail!("port 0 is not a valid port in '{}'", spec),
+        Ok(p) => Ok(p),
+        Err(_) => anyhow::bail!("invalid port '{}' in '{}'", s.trim(), spec),
+    };
+    let mut ports = Vec::new();
+    for part in spec.split(',') {
+        match part.split_once('-') {
+            Some((lo, hi)) => {
+                let (lo, hi) = (port(lo)?, port(hi)?);
+                ports.extend(lo.min(hi)..=lo.max(hi));
+            }
+            None => ports.push(port(part)?),
+        }
+    }
+    Ok(ports)
+}
+
+/// Write `ports` the way [`parse_ports`] reads them, sorted and with runs
+/// collapsed into ranges: `22,80-82`.
+pub fn format_ports(ports: &[u16]) -> String {
+    let mut ports = ports.to_vec();
+    ports.sort_unstable();
+    ports.dedup();
+    let mut runs: Vec<(u16, u16)> = Vec::new();
+    for port in ports {
+        match runs.last_mut() {
+            Some((_, hi)) if *hi + 1 == port => *hi = port,
+            _ => runs.push((port, port)),
+        }
+    }
+    runs.iter()
+        .map(|&(lo, hi)| {
+            if lo == hi {
+                lo.to_string()
+            } else {
+                format!("{}-{}", lo, hi)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Parse the `LO-HI` of `--source-port-range`; the bounds may come in either
+/// order.
+pub fn parse_port_range(spec: &str) -> anyhow::Result<(u16, u16)> {
+    let invalid = || anyhow::anyhow!("invalid port range '{}': expected LO-HI", spec);
+    let (lo, hi) = spec.split_once('-').ok_or_else(invalid)?;
+    let port = |s: &str| match s.trim().parse::<u16>() {
+        Ok(0) | Err(_) => Err(invalid()),
+        Ok(p) => Ok(p),
+    };
+    let (lo, hi) = (port(lo)?, port(hi)?);
+    Ok((lo.min(hi), lo.max(hi)))
+}
+
+/// Shuffle `ports` into a random order for `-r`, seeded afresh each run.
+pub fn shuffle_ports(ports: &mut [u16]) {
+    shuffle_ports_seeded(ports, crate::net::util::random_u64());
+}
+
+/// Fisher-Yates shuffle of `ports` driven by a xorshift generator started
+/// from `seed`, so a given seed always yields the same order.
+pub fn shuffle_ports_seeded(ports: &mut [u16], seed: u64) {
+    // xorshift must not start from zero
+    let mut state = seed | 1;
+    for i in (1..ports.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let j = (state % (i as u64 + 1)) as usize;
+        ports.swap(i, j);
+    }
+}
+
+/// Parse a byte count with an optional binary suffix: `512`, `64K`, `1M`, `2G`.
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let (digits, shift) = match s.as_bytes().last().map(u8::to_ascii_uppercase) {
+        Some(b'K') => (&s[..s.len() - 1], 10),
+        Some(b'M') => (&s[..s.len() - 1], 20),
+        Some(b'G') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid size '{}'", s))?;
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| anyhow::anyhow!("size '{}' is too large", s))
+}
+
+/// The RFC 1349 TOS keywords accepted by `-T`, with their values.
+pub const TOS_KEYWORDS: [(&str, u8); 4] = [
+    ("lowdelay", 0x10),
+    ("throughput", 0x08),
+    ("reliability", 0x04),
+    ("mincost", 0x02),
+];
+
+/// Parse a `-T` value: a keyword from [`TOS_KEYWORDS`], or a byte in decimal
+/// or `0x` hex.
+pub fn parse_tos(s: &str) -> anyhow::Result<u8> {
+    if let Some(&(_, value)) = TOS_KEYWORDS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(s))
+    {
+        return Ok(value);
+    }
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| {
+        let names: Vec<_> = TOS_KEYWORDS.iter().map(|(name, _)| *name).collect();
+        anyhow::anyhow!(
+            "invalid TOS '{}': expected {} or a value from 0 to 255",
+            s,
+            names.join(", ")
+        )
+    })
+}
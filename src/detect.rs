@@ -0,0 +1,94 @@
+/*!
+r-cat/src/detect.rs
+
+Heuristic protocol classification from the first bytes a server sends
+(`--detect-protocol`). Only well-known, unambiguous banners and record
+headers are matched; anything else is reported as unknown.
+*/
+
+use std::fmt;
+
+/// A protocol recognised from its greeting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Http,
+    Ssh,
+    Smtp,
+    Ftp,
+    Pop3,
+    Imap,
+    Tls,
+    Telnet,
+    Vnc,
+    Mysql,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Protocol::Http => "HTTP",
+            Protocol::Ssh => "SSH",
+            Protocol::Smtp => "SMTP",
+            Protocol::Ftp => "FTP",
+            Protocol::Pop3 => "POP3",
+            Protocol::Imap => "IMAP",
+            Protocol::Tls => "TLS",
+            Protocol::Telnet => "Telnet",
+            Protocol::Vnc => "VNC (RFB)",
+            Protocol::Mysql => "MySQL",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Guess the protocol from an initial read buffer.
+pub fn classify(buf: &[u8]) -> Option<Protocol> {
+    if buf.starts_with(b"SSH-") {
+        return Some(Protocol::Ssh);
+    }
+    if buf.starts_with(b"HTTP/") {
+        return Some(Protocol::Http);
+    }
+    if buf.starts_with(b"+OK") {
+        return Some(Protocol::Pop3);
+    }
+    if buf.starts_with(b"* OK") || buf.starts_with(b"* PREAUTH") {
+        return Some(Protocol::Imap);
+    }
+    if buf.starts_with(b"RFB ") {
+        return Some(Protocol::Vnc);
+    }
+    // "220 host ESMTP ..." vs "220 ProFTPD ..." share the greeting code
+    if buf.starts_with(b"220 ") || buf.starts_with(b"220-") {
+        let line = first_line(buf).to_ascii_uppercase();
+        return Some(if contains(&line, b"FTP") {
+            Protocol::Ftp
+        } else {
+            Protocol::Smtp
+        });
+    }
+    // TLS record: handshake content type, major version 3, minor 0..=4
+    if let [0x16, 0x03, minor, ..] = buf
+        && *minor <= 0x04
+    {
+        return Some(Protocol::Tls);
+    }
+    // Telnet option negotiation: IAC followed by WILL/WONT/DO/DONT
+    if let [0xff, 0xfb..=0xfe, ..] = buf {
+        return Some(Protocol::Telnet);
+    }
+    // MySQL handshake: 3-byte length, sequence id 0, protocol version 10
+    if let [_, _, _, 0x00, 0x0a, ..] = buf {
+        return Some(Protocol::Mysql);
+    }
+    None
+}
+
+fn first_line(buf: &[u8]) -> &[u8] {
+    let end = buf.iter().position(|&b| b == b'\n').unwrap_or(buf.len());
+    &buf[..end]
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
@@ -0,0 +1,237 @@
+/*!
+r-cat/src/filter.rs
+
+Streaming byte filters applied to the data flowing through a session.
+
+A [`Filter`] sees the stream chunk by chunk as it is read and may hold back a
+few bytes when a pattern could continue in the next chunk. [`FilterWriter`]
+wraps any `AsyncWrite` and runs every write through a chain of filters, so the
+existing copy loops (`io::copy` and friends) pick filtering up for free.
+*/
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// A streaming byte transformation.
+pub trait Filter: Send {
+    /// Transform `input`, appending the result to `out`.
+    ///
+    /// Bytes that cannot be decided yet (e.g. a partial match at the end of
+    /// the chunk) may be held back until the next call.
+    fn feed(&mut self, input: &[u8], out: &mut Vec<u8>);
+
+    /// Emit whatever is still held back; called once at end of stream.
+    fn finish(&mut self, _out: &mut Vec<u8>) {}
+}
+
+/// Replace every occurrence of one byte string with another.
+///
+/// Matches split across chunk boundaries are still found: a tail that could
+/// be the start of the pattern is held back until the next chunk decides it.
+#[derive(Debug, Clone)]
+pub struct Replace {
+    from: Vec<u8>,
+    to: Vec<u8>,
+    held: Vec<u8>,
+}
+
+impl Replace {
+    /// Panics if `from` is empty.
+    pub fn new(from: Vec<u8>, to: Vec<u8>) -> Self {
+        assert!(!from.is_empty(), "replacement pattern must not be empty");
+        Replace {
+            from,
+            to,
+            held: Vec::new(),
+        }
+    }
+}
+
+impl Filter for Replace {
+    fn feed(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        let mut data = std::mem::take(&mut self.held);
+        data.extend_from_slice(input);
+
+        let mut i = 0;
+        while i < data.len() {
+            let rest = &data[i..];
+            if rest.starts_with(&self.from) {
+                out.extend_from_slice(&self.to);
+                i += self.from.len();
+            } else if rest.len() < self.from.len() && self.from.starts_with(rest) {
+                // Could still become a match once more data arrives
+                self.held = rest.to_vec();
+                return;
+            } else {
+                out.push(data[i]);
+                i += 1;
+            }
+        }
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.held);
+    }
+}
+
+/// Drop the `\r` of every `\r\n` pair, turning CRLF line endings into LF.
+///
+/// A lone `\r` is kept. A `\r` ending a chunk is held back until the next
+/// chunk shows whether a `\n` follows.
+#[derive(Debug, Clone, Default)]
+pub struct StripCr {
+    held_cr: bool,
+}
+
+impl Filter for StripCr {
+    fn feed(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        for &b in input {
+            if std::mem::take(&mut self.held_cr) && b != b'\n' {
+                out.push(b'\r');
+            }
+            if b == b'\r' {
+                self.held_cr = true;
+            } else {
+                out.push(b);
+            }
+        }
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if std::mem::take(&mut self.held_cr) {
+            out.push(b'\r');
+        }
+    }
+}
+
+/// Turn every bare `\n` into `\r\n` (`-C`); a `\n` already preceded by
+/// `\r`, even one at the end of the previous chunk, is left alone.
+#[derive(Debug, Clone, Default)]
+pub struct Crlf {
+    after_cr: bool,
+}
+
+impl Filter for Crlf {
+    fn feed(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        for &b in input {
+            if b == b'\n' && !self.after_cr {
+                out.push(b'\r');
+            }
+            out.push(b);
+            self.after_cr = b == b'\r';
+        }
+    }
+}
+
+/// An `AsyncWrite` adapter that runs all written bytes through a filter chain.
+///
+/// With an empty chain, writes go straight to the inner writer. Otherwise the
+/// filtered output is buffered and drained on subsequent writes and flushes;
+/// [`FilterWriter::end`] (or shutdown) also releases bytes held back by the
+/// filters.
+pub struct FilterWriter<W> {
+    inner: W,
+    filters: Vec<Box<dyn Filter>>,
+    /// Filtered bytes not yet accepted by `inner`.
+    pending: Vec<u8>,
+    finished: bool,
+}
+
+impl<W> FilterWriter<W> {
+    pub fn new(inner: W, filters: Vec<Box<dyn Filter>>) -> Self {
+        FilterWriter {
+            inner,
+            filters,
+            pending: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// The wrapped writer, bypassing the filters.
+    ///
+    /// Call [`FilterWriter::end`] first if the filtered data must precede
+    /// anything written here.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// The wrapped writer, dropping whatever the filters have not passed on.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn apply(&mut self, input: &[u8], finish: bool) {
+        let mut data = input.to_vec();
+        for filter in &mut self.filters {
+            let mut out = Vec::with_capacity(data.len());
+            filter.feed(&data, &mut out);
+            if finish {
+                filter.finish(&mut out);
+            }
+            data = out;
+        }
+        self.pending.extend_from_slice(&data);
+    }
+
+    fn finish_filters(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            self.apply(&[], true);
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> FilterWriter<W> {
+    /// Release everything the filters hold back and flush it to the inner writer.
+    pub async fn end(&mut self) -> io::Result<()> {
+        self.finish_filters();
+        self.flush().await
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for FilterWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.filters.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        ready!(this.poll_drain(cx))?;
+        this.apply(buf, false);
+        // Start draining right away; anything left goes out on the next poll
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.finish_filters();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,108 @@
+/*!
+r-cat/src/flush.rs
+
+Batched output for `--flush-interval`.
+
+Without it every chunk read from the socket is written to stdout straight
+away, one write per read. [`TimedFlush`] instead collects chunks and writes
+them out together once the oldest one has waited `interval`, trading a
+bounded amount of latency for far fewer writes when the peer sends a burst
+of small segments. Nothing arms a timer by itself: the owner polls
+[`TimedFlush::deadline`] and flushes when it passes, and a final flush (or
+shutdown) always writes out whatever is left.
+*/
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use tokio::io::AsyncWrite;
+use tokio::time::Instant;
+
+/// How much output is held back at most before it is written regardless of
+/// the deadline.
+const CAPACITY: usize = 64 * 1024;
+
+/// An `AsyncWrite` that coalesces writes until a deadline, or passes them
+/// straight through when no interval is set.
+#[derive(Debug)]
+pub struct TimedFlush<W> {
+    inner: W,
+    interval: Option<Duration>,
+    buf: Vec<u8>,
+    /// When the oldest buffered byte is due; `None` while the buffer is empty.
+    deadline: Option<Instant>,
+}
+
+impl<W> TimedFlush<W> {
+    pub fn new(inner: W, interval: Option<Duration>) -> Self {
+        TimedFlush {
+            inner,
+            interval,
+            buf: Vec::new(),
+            deadline: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// When buffered output has to be flushed, if any is waiting.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+}
+
+impl<W: AsyncWrite + Unpin> TimedFlush<W> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.buf.drain(..n);
+        }
+        self.deadline = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for TimedFlush<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(interval) = this.interval else {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        };
+        if this.buf.len() >= CAPACITY {
+            ready!(this.poll_drain(cx))?;
+        }
+        this.buf.extend_from_slice(data);
+        let now = Instant::now();
+        let due = *this.deadline.get_or_insert(now + interval);
+        // Start writing once due; anything left goes out on the next poll
+        if now >= due
+            && let Poll::Ready(Err(e)) = this.poll_drain(cx)
+        {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,241 @@
+/*!
+r-cat/src/format.rs
+
+Alternative renderings of received data for stdout.
+
+`--hexdump` shows the peer's bytes in the canonical hex+ASCII layout of
+`hexdump -C` instead of raw: an offset column, sixteen bytes per line in two
+groups of eight, and a gutter with the printable ones. Lines follow the
+stream rather than the reads it arrived in: a chunk's last few bytes wait
+for the next to fill their line, and only the end of the stream writes out
+a short one. With `--color-hexdump` (and `--color` allowing it) the offsets
+are dimmed and every byte, in both columns, is coloured by kind: printable
+ASCII green, control characters yellow, and bytes above 0x7f red.
+
+`--timestamp` starts every line of output with the local wall-clock time it
+arrived, `[HH:MM:SS.mmm] `. A chunk ending mid-line gets no stamp for its
+continuation; the next one is only written once a byte follows a newline.
+*/
+
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::filter::Filter;
+
+/// Bytes shown on each dump line.
+const LINE: usize = 16;
+
+/// ANSI escapes of the coloured dump.
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// When `--color` lets output be coloured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ColorWhen {
+    /// Only when stdout is a terminal
+    #[default]
+    Auto,
+    /// Also when output is piped or redirected
+    Always,
+    /// Not at all
+    Never,
+}
+
+/// Dump `buf` to `out` as `hexdump -C` lines, numbering its first byte
+/// `offset`.
+pub fn write_hexdump<W: Write>(out: &mut W, offset: u64, buf: &[u8]) -> io::Result<()> {
+    dump_lines(out, offset, buf, false)
+}
+
+/// [`write_hexdump`], with the offsets dimmed and the bytes coloured by kind.
+pub fn write_hexdump_colored<W: Write>(out: &mut W, offset: u64, buf: &[u8]) -> io::Result<()> {
+    dump_lines(out, offset, buf, true)
+}
+
+fn dump_lines<W: Write>(out: &mut W, offset: u64, buf: &[u8], color: bool) -> io::Result<()> {
+    for (i, chunk) in buf.chunks(LINE).enumerate() {
+        let mut ink = Ink::new(color);
+        let mut hex = String::with_capacity(3 * LINE + 1);
+        for j in 0..LINE {
+            if j == LINE / 2 {
+                hex.push(' ');
+            }
+            match chunk.get(j) {
+                Some(&b) => {
+                    ink.switch(&mut hex, Some(byte_color(b)));
+                    hex.push_str(&format!("{:02x} ", b));
+                }
+                None => {
+                    ink.switch(&mut hex, None);
+                    hex.push_str("   ");
+                }
+            }
+        }
+        ink.switch(&mut hex, None);
+        let mut ascii = String::with_capacity(LINE);
+        for &b in chunk {
+            ink.switch(&mut ascii, Some(byte_color(b)));
+            ascii.push(match b {
+                0x20..=0x7e => b as char,
+                _ => '.',
+            });
+        }
+        ink.switch(&mut ascii, None);
+        let at = offset + (i * LINE) as u64;
+        if color {
+            writeln!(out, "{}{:08x}{}  {} |{}|", DIM, at, RESET, hex, ascii)?;
+        } else {
+            writeln!(out, "{:08x}  {} |{}|", at, hex, ascii)?;
+        }
+    }
+    Ok(())
+}
+
+/// The colour `b` is shown in: green for printable ASCII, yellow for
+/// control characters, red for the rest.
+fn byte_color(b: u8) -> &'static str {
+    match b {
+        0x20..=0x7e => "\x1b[32m",
+        0x00..=0x1f | 0x7f => "\x1b[33m",
+        _ => "\x1b[31m",
+    }
+}
+
+/// Tracks the colour in effect so a run of same-kind bytes shares one
+/// escape; does nothing when colour is off.
+struct Ink {
+    enabled: bool,
+    current: Option<&'static str>,
+}
+
+impl Ink {
+    fn new(enabled: bool) -> Self {
+        Ink {
+            enabled,
+            current: None,
+        }
+    }
+
+    /// Continue `out` in `color`, or uncoloured for `None`.
+    fn switch(&mut self, out: &mut String, color: Option<&'static str>) {
+        if !self.enabled || self.current == color {
+            return;
+        }
+        out.push_str(color.unwrap_or(RESET));
+        self.current = color;
+    }
+}
+
+/// A receive-side [`Filter`] turning the stream into its hex dump.
+#[derive(Debug, Default)]
+pub struct Hexdump {
+    /// Offset of the first byte in `row`.
+    offset: u64,
+    /// The start of a line still waiting for bytes.
+    row: Vec<u8>,
+    /// Colour the dump (`--color-hexdump`).
+    color: bool,
+}
+
+impl Hexdump {
+    /// A dump coloured as [`write_hexdump_colored`] when `color` is set.
+    pub fn new(color: bool) -> Self {
+        Hexdump {
+            color,
+            ..Hexdump::default()
+        }
+    }
+
+    fn dump(&mut self, out: &mut Vec<u8>, bytes: &[u8]) {
+        // Writing to a Vec cannot fail
+        let _ = dump_lines(out, self.offset, bytes, self.color);
+        self.offset += bytes.len() as u64;
+    }
+}
+
+impl Filter for Hexdump {
+    fn feed(&mut self, mut input: &[u8], out: &mut Vec<u8>) {
+        if !self.row.is_empty() {
+            let take = (LINE - self.row.len()).min(input.len());
+            self.row.extend_from_slice(&input[..take]);
+            input = &input[take..];
+            if self.row.len() < LINE {
+                return;
+            }
+            let row = std::mem::take(&mut self.row);
+            self.dump(out, &row);
+        }
+        let whole = input.len() - input.len() % LINE;
+        self.dump(out, &input[..whole]);
+        self.row.extend_from_slice(&input[whole..]);
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        let row = std::mem::take(&mut self.row);
+        self.dump(out, &row);
+    }
+}
+
+/// A receive-side [`Filter`] stamping the start of every output line with
+/// [`clock_stamp`].
+#[derive(Debug)]
+pub struct Timestamp {
+    at_line_start: bool,
+}
+
+impl Default for Timestamp {
+    fn default() -> Self {
+        Timestamp {
+            at_line_start: true,
+        }
+    }
+}
+
+impl Filter for Timestamp {
+    fn feed(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        // One reading per chunk: its lines all arrived at the same time
+        let mut stamp = None;
+        for &b in input {
+            if self.at_line_start {
+                out.extend_from_slice(stamp.get_or_insert_with(clock_stamp).as_bytes());
+            }
+            out.push(b);
+            self.at_line_start = b == b'\n';
+        }
+    }
+}
+
+/// The current local time as `[HH:MM:SS.mmm] `.
+pub fn clock_stamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let millis = now.subsec_millis();
+    let (h, m, s) = local_hms(now.as_secs() as i64);
+    format!("[{:02}:{:02}:{:02}.{:03}] ", h, m, s, millis)
+}
+
+/// Hours, minutes and seconds of the Unix time `secs` in the local time zone.
+#[cfg(unix)]
+fn local_hms(secs: i64) -> (i32, i32, i32) {
+    let t = secs as libc::time_t;
+    // SAFETY: localtime_r only writes the tm we hand it.
+    unsafe {
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        if libc::localtime_r(&t, &mut tm).is_null() {
+            return utc_hms(secs);
+        }
+        (tm.tm_hour, tm.tm_min, tm.tm_sec)
+    }
+}
+
+/// Without a portable time zone lookup, fall back to UTC.
+#[cfg(not(unix))]
+fn local_hms(secs: i64) -> (i32, i32, i32) {
+    utc_hms(secs)
+}
+
+fn utc_hms(secs: i64) -> (i32, i32, i32) {
+    let day = secs.rem_euclid(86_400) as i32;
+    (day / 3600, day / 60 % 60, day % 60)
+}
@@ -3,9 +3,23 @@ r-cat library crate.
 
 This file exposes the CLI definitions (re-exporting the `cli` module)
 and the `net` modules (tcp, udp) so integration tests and other crates
-can access `r_cat::cli::Args` and `r_cat::net::{tcp, udp}`.
+can access `r_cat::cli::Args` and `r_cat::net::{tcp, udp}`. The `log` module
+holds the global `--quiet` gate behind the `diag!` macro, `filter` the
+streaming byte filters applied to session data, `detect` the banner-based
+protocol classifier, `tee` the traffic observers fed by every session,
+`pcap` the capture writer built on them, `flush` the batched stdout
+writer behind `--flush-interval`, and `format` the `--hexdump` (optionally
+coloured) and `--timestamp` renderings of received data.
 */
 
+pub mod detect;
+pub mod filter;
+pub mod flush;
+pub mod format;
+pub mod log;
+pub mod pcap;
+pub mod tee;
+
 pub mod cli;
 pub use cli::Args;
 
@@ -0,0 +1,31 @@
+/*!
+r-cat/src/log.rs
+
+Global gate for diagnostic (stderr) output. Every human-readable message goes
+through [`diag!`](crate::diag) so `--quiet` can silence them all at once,
+leaving only payload on stdout.
+*/
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Suppress (or re-enable) all diagnostic output.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Whether diagnostic output is currently suppressed.
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// `eprintln!` that respects `--quiet`.
+#[macro_export]
+macro_rules! diag {
+    ($($arg:tt)*) => {
+        if !$crate::log::is_quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
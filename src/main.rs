@@ -1,28 +1,91 @@
-use clap::Parser;
+use std::io::Write;
 use std::time::Duration;
 
-use r_cat::Args;
-use r_cat::net::{tcp, udp};
+use clap::Parser;
+
+use r_cat::net::{self, CancelToken, Config, SessionStats, fanout, tcp, udp};
+use r_cat::{Args, cli};
+use r_cat::{diag, log};
+
+/// Destination port used by `--mtu-probe` when none is given.
+const MTU_PROBE_PORT: u16 = 33434;
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+/// How long a run that does not watch the cancel token (a scan, say) may
+/// carry on after SIGINT or SIGTERM before it is dropped.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+fn main() {
     // Parse CLI args (clap-derived)
     let args = Args::parse();
 
-    // Convert optional timeout seconds into Duration
-    let timeout = args.timeout.map(Duration::from_secs_f64);
+    // `--quiet` silences every diagnostic, including the final error message,
+    // but the exit code still reports failure.
+    log::set_quiet(args.quiet);
 
-    if args.listen {
+    let runtime = tokio::runtime::Runtime::new().expect("failed to start the tokio runtime");
+    let res = runtime.block_on(run(args));
+    // A session can end while stdin is still open (e.g. `--headers-only`);
+    // tokio's blocking stdin read cannot be cancelled, so don't wait for it
+    runtime.shutdown_background();
+    let _ = std::io::stdout().flush();
+
+    match res {
+        Ok(0) => {}
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            diag!("r-cat: {:#}", e);
+            std::process::exit(net::exit_code(&e));
+        }
+    }
+}
+
+/// Run the requested mode, returning the process exit status.
+async fn run(args: Args) -> anyhow::Result<i32> {
+    // Every session is plaintext until a TLS transport exists, so the guard
+    // can only refuse; this must stay ahead of any socket being opened
+    if args.require_tls {
+        anyhow::bail!("--require-tls: no TLS transport is available, refusing plaintext");
+    }
+
+    let mut config = Config::try_from(&args)?;
+    let cancel = CancelToken::new();
+    config.cancel = Some(cancel.clone());
+    net::install_signal_handler(cancel.clone())?;
+
+    let interrupted = async {
+        cancel.cancelled().await;
+        tokio::time::sleep(SHUTDOWN_GRACE).await;
+    };
+    let status = tokio::select! {
+        status = run_mode(&args, &config) => status?,
+        // Sessions stop on the token by themselves; this is for the rest
+        _ = interrupted => 0,
+    };
+    Ok(if cancel.is_cancelled() {
+        net::EXIT_INTERRUPTED
+    } else {
+        status
+    })
+}
+
+/// Run the mode `args` ask for with `config`, returning the process exit
+/// status.
+async fn run_mode(args: &Args, config: &Config) -> anyhow::Result<i32> {
+    let stats = if args.unix {
+        unix_socket(args, config).await?
+    } else if args.listen {
         // Listen mode: need a port (positional `port` or `-p` `source_port`)
-        let port = args
-            .port
-            .or(args.source_port)
-            .ok_or_else(|| anyhow::anyhow!("listen mode requires a port (-p or positional)"))?;
+        let port = match args.port.as_deref() {
+            Some(spec) => single_port(spec)?,
+            None => args
+                .source_port
+                .ok_or_else(|| anyhow::anyhow!("listen mode requires a port (-p or positional)"))?,
+        };
 
         if args.udp {
-            udp::listen(port, timeout, args.verbose).await?;
+            udp::listen(port, config).await?
         } else {
-            tcp::listen(port, timeout, args.verbose).await?;
+            tcp::listen(port, config).await?
         }
     } else {
         // Client mode: need destination host and port
@@ -30,16 +93,114 @@ async fn main() -> anyhow::Result<()> {
             .destination
             .as_deref()
             .ok_or_else(|| anyhow::anyhow!("destination required in client mode"))?;
-        let port = args
-            .port
-            .ok_or_else(|| anyhow::anyhow!("port required in client mode"))?;
 
-        if args.udp {
-            udp::client(host, port, timeout, args.verbose).await?;
+        if args.zero {
+            if args.udp {
+                anyhow::bail!("-z only scans TCP ports");
+            }
+            let spec = args
+                .port
+                .as_deref()
+                .ok_or_else(|| anyhow::anyhow!("port required in client mode"))?;
+            let mut ports = cli::parse_ports(spec)?;
+            if args.random {
+                cli::shuffle_ports(&mut ports);
+            }
+            // Scanning several ports reports each one; a single port only under -v
+            let report = config.verbose() || ports.len() > 1;
+            let deadline = args
+                .scan_deadline
+                .map(|secs| {
+                    Duration::try_from_secs_f64(secs).map_err(|_| {
+                        anyhow::anyhow!("--scan-deadline: '{}' is not a number of seconds", secs)
+                    })
+                })
+                .transpose()?
+                .map(|budget| tokio::time::Instant::now() + budget);
+            let mut status = 0;
+            for (i, &port) in ports.iter().enumerate() {
+                let probe = tcp::scan(host, port, config);
+                let state = match deadline {
+                    Some(at) => match tokio::time::timeout_at(at, probe).await {
+                        Ok(state) => state?,
+                        // The port being probed counts as not scanned
+                        Err(_) => {
+                            let rest = &ports[i..];
+                            diag!(
+                                "scan deadline reached: {} port(s) not scanned: {}",
+                                rest.len(),
+                                cli::format_ports(rest)
+                            );
+                            return Ok(status.max(net::EXIT_TIMEOUT));
+                        }
+                    },
+                    None => probe.await?,
+                };
+                if report {
+                    diag!("{}", state.describe(host, port));
+                }
+                status = status.max(state.exit_code());
+            }
+            return Ok(status);
+        }
+
+        // The probe only needs *a* port; default to the traceroute base port
+        let port = match args.port.as_deref() {
+            Some(spec) => single_port(spec)?,
+            None if args.mtu_probe => MTU_PROBE_PORT,
+            None => anyhow::bail!("port required in client mode"),
+        };
+        if args.udp || args.mtu_probe {
+            udp::client(host, port, config).await?
+        } else if !args.also.is_empty() {
+            let mut destinations = vec![format!("{}:{}", host, port)];
+            destinations.extend(args.also.iter().cloned());
+            fanout::client(&destinations, config).await?
         } else {
-            tcp::client(host, port, timeout, args.verbose).await?;
+            tcp::client(host, port, config).await?
         }
+    };
+
+    // The probe exchanges no data worth counting
+    if config.verbose() && !args.mtu_probe {
+        diag!("sent {}, rcvd {}", stats.bytes_sent, stats.bytes_received);
+    }
+    Ok(0)
+}
+
+/// `-U`: the destination names a socket path, in both modes, and the port
+/// is ignored.
+#[cfg(unix)]
+async fn unix_socket(args: &Args, config: &Config) -> anyhow::Result<SessionStats> {
+    use r_cat::net::unix;
+
+    if args.udp {
+        anyhow::bail!("-U only supports stream sockets, not -u");
+    }
+    let path = args
+        .destination
+        .as_deref()
+        .map(std::path::Path::new)
+        .ok_or_else(|| anyhow::anyhow!("-U requires a socket path"))?;
+    if args.listen {
+        unix::listen(path, config).await
+    } else {
+        unix::client(path, config).await
     }
+}
+
+#[cfg(not(unix))]
+async fn unix_socket(_args: &Args, _config: &Config) -> anyhow::Result<SessionStats> {
+    anyhow::bail!("-U: Unix-domain sockets are not available on this platform")
+}
 
-    Ok(())
+/// The one port outside `-z`, where ranges and lists make no sense.
+fn single_port(spec: &str) -> anyhow::Result<u16> {
+    match cli::parse_ports(spec)?[..] {
+        [port] => Ok(port),
+        _ => anyhow::bail!(
+            "'{}' names several ports; only -z takes more than one",
+            spec
+        ),
+    }
 }
@@ -0,0 +1,162 @@
+/*!
+r-cat/src/net/chat.rs
+
+The `--chat` relay: a keep-open TCP listener serving all of its clients at
+once instead of one after another.
+
+Each accepted client is named `user<N>`, numbered in order of arrival. Every
+line one of them sends goes to all the others as `<nick>: <line>`, a last
+line without a newline getting one; senders never see their own lines. A
+client that hangs up, or cannot be written to, is dropped from the list and
+the rest carry on, as they do when accepting a client fails. With
+`--max-conns`, clients arriving while the relay is full are disconnected
+straight away; `--accept-rate` leaves clients beyond the rate waiting in the
+listen backlog. The relay has no local participant: stdin is not read and
+nothing is written to stdout.
+*/
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+
+use super::stats::Counters;
+use super::throttle::Bucket;
+use super::{Config, SessionStats, cancelled};
+use crate::diag;
+use crate::tee::{Direction, Tap};
+
+/// How long to wait after a failed accept before the next one, so running
+/// out of descriptors does not spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The write halves of the connected clients, by client number.
+type Peers = Arc<Mutex<BTreeMap<u64, Arc<Mutex<OwnedWriteHalf>>>>>;
+
+/// Relay lines between the clients of `listener` until `config.cancel`
+/// fires. The returned stats add up the traffic of every client.
+pub async fn serve(listener: TcpListener, config: &Config) -> anyhow::Result<SessionStats> {
+    let started = Instant::now();
+    let verbose = config.verbose();
+    let peers: Peers = Arc::default();
+    let counters = Arc::new(Counters::default());
+    // Dropping the set aborts the client tasks, so none outlives the relay
+    let mut clients = JoinSet::new();
+    // --max-conns: a client holds one of these until it leaves
+    let slots = config
+        .max_conns
+        .map(|max| Arc::new(Semaphore::new(max as usize)));
+    let mut gate = config.accept_rate.map(|rate| Bucket::new(rate.into()));
+    let mut next_id = 0u64;
+    loop {
+        // Taken before accepting, so reaping a client cannot waste a token
+        if let Some(gate) = &mut gate {
+            tokio::select! {
+                _ = gate.acquire() => {}
+                _ = cancelled(config.cancel.as_ref()) => break,
+            }
+        }
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = cancelled(config.cancel.as_ref()) => break,
+            // Reap finished clients so the set does not keep growing
+            Some(_) = clients.join_next() => continue,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            // Out of descriptors, or a client gone before it was taken: the
+            // listener itself is fine, so pause briefly and carry on
+            Err(e) => {
+                diag!("Chat: accept failed: {}", e);
+                time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let slot = match &slots {
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(slot) => Some(slot),
+                Err(_) => {
+                    if verbose {
+                        diag!("Chat: full, turning {} away", addr);
+                    }
+                    continue;
+                }
+            },
+            None => None,
+        };
+        if config.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        next_id += 1;
+        let id = next_id;
+        let nick = format!("user{}", id);
+        if verbose {
+            diag!("Chat: {} joined from {}", nick, addr);
+        }
+        let (reader, writer) = stream.into_split();
+        peers.lock().await.insert(id, Arc::new(Mutex::new(writer)));
+
+        let (peers, counters) = (peers.clone(), counters.clone());
+        clients.spawn(async move {
+            let _slot = slot;
+            let mut reader = BufReader::new(reader);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                match reader.read_until(b'\n', &mut line).await {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        if verbose {
+                            diag!("Chat: {}: {}", nick, e);
+                        }
+                        break;
+                    }
+                }
+                counters.record(Direction::Received, &line);
+                if line.last() != Some(&b'\n') {
+                    line.push(b'\n');
+                }
+                let mut message = format!("{}: ", nick).into_bytes();
+                message.extend_from_slice(&line);
+                relay(&peers, id, &message, &counters).await;
+            }
+            peers.lock().await.remove(&id);
+            if verbose {
+                diag!("Chat: {} left", nick);
+            }
+        });
+    }
+    Ok(SessionStats {
+        bytes_sent: counters.sent(),
+        bytes_received: counters.received(),
+        duration: started.elapsed(),
+    })
+}
+
+/// Send `message` to every client but `from`, dropping those it cannot
+/// reach.
+async fn relay(peers: &Peers, from: u64, message: &[u8], counters: &Counters) {
+    // Write outside the list lock, so a slow client holds up this line only
+    let targets: Vec<_> = peers
+        .lock()
+        .await
+        .iter()
+        .filter(|(id, _)| **id != from)
+        .map(|(id, writer)| (*id, writer.clone()))
+        .collect();
+    for (id, writer) in targets {
+        match writer.lock().await.write_all(message).await {
+            Ok(()) => counters.record(Direction::Sent, message),
+            Err(_) => {
+                peers.lock().await.remove(&id);
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+/*!
+r-cat/src/net/exec.rs
+
+`--exec`: hand a connection to a subprocess instead of stdin/stdout.
+
+Anyone who can reach the socket gets to talk to the command, so this is only
+compiled with the `exec` cargo feature.
+*/
+
+use std::process::Stdio;
+use std::time::Instant;
+
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::process::Command;
+
+use super::SessionStats;
+use crate::diag;
+
+/// Run `cmd` through `sh -c` with its stdin fed from `stream` and its stdout
+/// sent back on it; its stderr stays ours.
+///
+/// The connection is closed once the command's output ends, whether or not
+/// the peer is still sending, and the command is waited for before
+/// returning. The byte counts are those of the command's input and output.
+pub async fn exec_pipe<S>(stream: S, cmd: &str, verbose: bool) -> anyhow::Result<SessionStats>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let started = Instant::now();
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("cannot run '{}': {}", cmd, e))?;
+    let (Some(mut child_in), Some(mut child_out)) = (child.stdin.take(), child.stdout.take())
+    else {
+        anyhow::bail!("exec: child pipes unavailable");
+    };
+    if verbose {
+        diag!("exec: started '{}'", cmd);
+    }
+
+    let (mut reader, mut writer) = io::split(stream);
+    let mut fed = 0u64;
+    let to_child = async {
+        let res = io::copy(&mut reader, &mut child_in).await;
+        // Closing the pipe is how the command learns the peer is done
+        drop(child_in);
+        if let Ok(n) = res {
+            fed = n;
+        }
+        res
+    };
+    let from_child = async {
+        let res = io::copy(&mut child_out, &mut writer).await;
+        let _ = writer.shutdown().await;
+        res
+    };
+    let (mut to_child, mut from_child) = (Box::pin(to_child), Box::pin(from_child));
+
+    let mut input_done = false;
+    let output = loop {
+        tokio::select! {
+            res = &mut to_child, if !input_done => {
+                input_done = true;
+                if let (Err(e), true) = (res, verbose) {
+                    diag!("exec: feeding '{}' failed: {}", cmd, e);
+                }
+            }
+            res = &mut from_child => break res,
+        }
+    };
+    // Stop reading from the peer, closing the command's stdin if still open
+    drop(to_child);
+
+    let status = child.wait().await?;
+    if verbose {
+        diag!("exec: '{}' exited with {}", cmd, status);
+    }
+    Ok(SessionStats {
+        bytes_sent: output?,
+        bytes_received: fed,
+        duration: started.elapsed(),
+    })
+}
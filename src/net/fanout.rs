@@ -0,0 +1,261 @@
+/*!
+r-cat/src/net/fanout.rs
+
+One input, several TCP destinations (`--also`).
+
+[`client`] connects to every destination at once and copies each chunk of
+input to all the connections that came up. A destination that refuses or
+cannot be reached is reported and left out, and one whose connection fails
+later is dropped, without disturbing the others; only when none is left
+does the session fail. Replies are shown line by line, each behind the
+address of the peer that sent it (`127.0.0.1:80: HTTP/1.0 200 OK`), so
+interleaved answers stay attributable.
+
+What goes out is handled as on a single connection: the send filters (`-C`,
+`--strip-cr`, `--replace-send`), `--eof-marker` and `--rate` apply to each
+destination on its own, and the taps (`--log-file`, `--annotate`,
+`--tee-sent-fd`, ...) see the traffic of all of them.
+*/
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+
+use super::stats::Counters;
+use super::throttle::Throttle;
+use super::{Config, InputStream, OutputStream, SessionStats, cancelled, tcp};
+use crate::diag;
+use crate::filter::FilterWriter;
+use crate::tee::{Direction, Tee, TeeWriter};
+
+/// The sending side of one destination, filtered and tapped as in
+/// [`tcp::client`].
+type PeerWriter = FilterWriter<TeeWriter<Throttle<OwnedWriteHalf>>>;
+
+/// Send the input (`config.input`) to all of `destinations` (`host:port`
+/// each) and print their replies to `config.output`, every line prefixed
+/// with its sender's address.
+///
+/// Each connect is bounded by `config.timeout` and set up as in
+/// [`tcp::client`]. Once the input ends, every connection gets the EOF
+/// marker and is shut down for writing unless `config.no_shutdown` is set,
+/// and the session lasts until all peers have closed or been silent for
+/// `config.timeout`, `config.quit_after` has passed, or `config.cancel`
+/// fires.
+pub async fn client(destinations: &[String], config: &Config) -> anyhow::Result<SessionStats> {
+    let started = Instant::now();
+    let verbose = config.verbose();
+    let mut input = config.input.open().await?;
+    let output = Arc::new(Mutex::new(config.output.open().await?.writer()?));
+
+    let mut connecting = JoinSet::new();
+    for addr in destinations {
+        let (addr, config) = (addr.clone(), config.clone());
+        connecting.spawn(async move {
+            let attempt = tcp::connect(&addr, &config);
+            let res = match config.timeout {
+                Some(dur) => time::timeout(dur, attempt)
+                    .await
+                    .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into())),
+                None => attempt.await,
+            };
+            (addr, res)
+        });
+    }
+    let mut writers = Vec::new();
+    let mut readers = JoinSet::new();
+    let counters = Arc::new(Counters::default());
+    let tee = tcp::session_tee(config, counters.clone());
+    let mut last_err = None;
+    while let Some(joined) = connecting.join_next().await {
+        let (addr, res) = joined?;
+        let stream = match res {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                diag!("Connecting to {} failed: {}", addr, e);
+                last_err = Some(anyhow::Error::new(e).context(format!("connect to {}", addr)));
+                continue;
+            }
+        };
+        let peer = stream.peer_addr()?;
+        if verbose {
+            diag!("Connected to {}", peer);
+        }
+        tcp::set_stream_options(&stream, config)?;
+        let (reader, writer) = stream.into_split();
+        let writer = TeeWriter::new(Throttle::new(writer, config.rate), tee.clone());
+        writers.push((peer, FilterWriter::new(writer, config.send_filters())));
+        let idle = (config.timeout, verbose);
+        readers.spawn(show_replies(
+            peer,
+            reader,
+            output.clone(),
+            tee.clone(),
+            idle,
+        ));
+    }
+    if writers.is_empty() {
+        return Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no destinations given")));
+    }
+
+    let session = async {
+        send_to_all(&mut input, &mut writers, config).await?;
+        for (peer, writer) in &mut writers {
+            if let Err(e) = finish(writer, config).await {
+                diag!("Sending to {} failed: {}", peer, e);
+            }
+        }
+        if config.no_shutdown {
+            // Dropping the halves would send the FINs all the same
+            for (_, writer) in writers {
+                writer.into_inner().into_inner().into_inner().forget();
+            }
+        } else {
+            for (_, writer) in &mut writers {
+                let _ = writer.get_mut().shutdown().await;
+            }
+        }
+        let replies = async { while readers.join_next().await.is_some() {} };
+        match config.quit_after {
+            Some(dur) => {
+                let _ = time::timeout(dur, replies).await;
+            }
+            None => replies.await,
+        }
+        anyhow::Ok(())
+    };
+    tokio::select! {
+        res = session => res?,
+        _ = cancelled(config.cancel.as_ref()) => {}
+    }
+    if verbose {
+        diag!("Fan-out finished");
+    }
+    output.lock().await.flush().await?;
+    Ok(SessionStats {
+        bytes_sent: counters.sent(),
+        bytes_received: counters.received(),
+        duration: started.elapsed(),
+    })
+}
+
+/// Copy `input` to every connection in `writers` until it ends, dropping
+/// connections that fail. Running out of connections ends the copy early.
+async fn send_to_all(
+    input: &mut InputStream,
+    writers: &mut Vec<(SocketAddr, PeerWriter)>,
+    config: &Config,
+) -> std::io::Result<()> {
+    let mut buf = vec![0u8; config.send_len()];
+    while !writers.is_empty() {
+        let n = input.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        let mut failed = Vec::new();
+        for (i, (peer, writer)) in writers.iter_mut().enumerate() {
+            if let Err(e) = writer.write_all(&buf[..n]).await {
+                diag!("Sending to {} failed: {}", peer, e);
+                failed.push(i);
+            }
+        }
+        for i in failed.into_iter().rev() {
+            writers.swap_remove(i);
+        }
+    }
+    Ok(())
+}
+
+/// Release what the send filters of `writer` held back, then write the EOF
+/// marker, if any.
+async fn finish(writer: &mut PeerWriter, config: &Config) -> std::io::Result<()> {
+    writer.end().await?;
+    if let Some(marker) = &config.eof_marker {
+        writer.get_mut().write_all(marker).await?;
+    }
+    Ok(())
+}
+
+/// Write what `peer` sends to `output` a line at a time, each behind
+/// `<peer>: `; a last line without a newline is given one. `idle` holds `-w`
+/// and whether to report it: a peer silent that long is no longer read from,
+/// as in [`tcp::client`].
+async fn show_replies(
+    peer: SocketAddr,
+    reader: OwnedReadHalf,
+    output: Arc<Mutex<OutputStream>>,
+    tee: Tee,
+    (idle, verbose): (Option<Duration>, bool),
+) {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let done = match read_line(&mut reader, &mut line, idle).await {
+            Ok(0) => break,
+            Ok(_) => false,
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                if verbose {
+                    diag!("{} idle for {:?}, closing", peer, idle.unwrap_or_default());
+                }
+                true
+            }
+            Err(e) => {
+                diag!("Receiving from {} failed: {}", peer, e);
+                break;
+            }
+        };
+        if !line.is_empty() {
+            tee.record(Direction::Received, &line);
+            if line.last() != Some(&b'\n') {
+                line.push(b'\n');
+            }
+            let mut shown = format!("{}: ", peer).into_bytes();
+            shown.extend_from_slice(&line);
+            let mut output = output.lock().await;
+            if output.write_all(&shown).await.is_err() || output.flush().await.is_err() {
+                break;
+            }
+        }
+        if done {
+            break;
+        }
+    }
+}
+
+/// Append the next line of `reader` to `line`, as `read_until` does, but
+/// fail with `TimedOut` once `idle` passes without a byte arriving. Whatever
+/// arrived of the line by then stays in `line`.
+async fn read_line(
+    reader: &mut BufReader<OwnedReadHalf>,
+    line: &mut Vec<u8>,
+    idle: Option<Duration>,
+) -> std::io::Result<usize> {
+    loop {
+        let fill = reader.fill_buf();
+        let buf = match idle {
+            Some(dur) => time::timeout(dur, fill)
+                .await
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??,
+            None => fill.await?,
+        };
+        if buf.is_empty() {
+            return Ok(line.len());
+        }
+        let (end, found) = match buf.iter().position(|&b| b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (buf.len(), false),
+        };
+        line.extend_from_slice(&buf[..end]);
+        reader.consume(end);
+        if found {
+            return Ok(line.len());
+        }
+    }
+}
@@ -0,0 +1,149 @@
+/*!
+r-cat/src/net/idn.rs
+
+Internationalized domain names: turning `müller.example` into the
+`xn--mller-kva.example` the resolver understands.
+
+[`to_ascii`] lowercases the name and Punycode-encodes (RFC 3492) each label
+holding non-ASCII characters behind the `xn--` prefix; ASCII labels are left
+alone. This is the core of IDNA's ToASCII without the full UTS #46 mapping
+tables, so names are expected in their usual lowercase, composed form.
+*/
+
+use std::io;
+
+/// Punycode parameters (RFC 3492, section 5).
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 0x80;
+
+/// Prefix marking an encoded label.
+pub const ACE_PREFIX: &str = "xn--";
+/// Longest label DNS allows, in bytes.
+const MAX_LABEL: usize = 63;
+
+/// The ASCII form of the hostname `host`, which is returned unchanged if it
+/// is ASCII already. Fails with `InvalidInput` for a name no encoding can
+/// make valid: empty or over-long labels, or a label mixing non-ASCII
+/// characters with ASCII ones other than letters, digits and hyphens.
+pub fn to_ascii(host: &str) -> io::Result<String> {
+    if host.is_ascii() {
+        return Ok(host.to_string());
+    }
+    let invalid = |why: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid internationalized hostname '{}': {}", host, why),
+        )
+    };
+    let lower = host.to_lowercase();
+    // A trailing dot (the root) is kept
+    let (name, root) = match lower.strip_suffix('.') {
+        Some(name) => (name, "."),
+        None => (lower.as_str(), ""),
+    };
+    let mut labels = Vec::new();
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err(invalid("empty label".into()));
+        }
+        let ascii = if label.is_ascii() {
+            label.to_string()
+        } else {
+            if let Some(c) = label
+                .chars()
+                .find(|c| c.is_ascii() && !c.is_ascii_alphanumeric() && *c != '-')
+                .or_else(|| label.chars().find(|c| c.is_control() || c.is_whitespace()))
+            {
+                return Err(invalid(format!(
+                    "{:?} is not allowed in label '{}'",
+                    c, label
+                )));
+            }
+            let encoded =
+                encode(label).ok_or_else(|| invalid(format!("label '{}' is too long", label)))?;
+            format!("{}{}", ACE_PREFIX, encoded)
+        };
+        if ascii.len() > MAX_LABEL {
+            return Err(invalid(format!(
+                "label '{}' is longer than {} bytes once encoded",
+                label, MAX_LABEL
+            )));
+        }
+        labels.push(ascii);
+    }
+    Ok(labels.join(".") + root)
+}
+
+/// Punycode-encode `input` (without the `xn--` prefix), or `None` if it is
+/// too long for the arithmetic to stay in range.
+pub fn encode(input: &str) -> Option<String> {
+    let chars: Vec<u32> = input.chars().map(u32::from).collect();
+    let mut out: String = input.chars().filter(char::is_ascii).collect();
+    let basic = out.len() as u32;
+    let mut handled = basic;
+    if basic > 0 {
+        out.push('-');
+    }
+    let (mut n, mut delta, mut bias) = (INITIAL_N, 0u32, INITIAL_BIAS);
+    while (handled as usize) < chars.len() {
+        // The smallest code point not yet handled
+        let m = chars.iter().copied().filter(|&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+        for &c in &chars {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = if k <= bias {
+                        TMIN
+                    } else if k >= bias + TMAX {
+                        TMAX
+                    } else {
+                        k - bias
+                    };
+                    if q < t {
+                        break;
+                    }
+                    out.push(digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                out.push(digit(q));
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta = delta.checked_add(1)?;
+        n += 1;
+    }
+    Some(out)
+}
+
+/// The bias adaptation function (RFC 3492, section 6.1).
+fn adapt(mut delta: u32, points: u32, first: bool) -> u32 {
+    delta /= if first { DAMP } else { 2 };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > (BASE - TMIN) * TMAX / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
+
+fn digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    }
+}
@@ -5,8 +5,1209 @@ Networking module for r-cat. This module exposes the TCP and UDP helpers
 as submodules so callers can use `r_cat::net::tcp` and `r_cat::net::udp`.
 
 The actual implementations live in `tcp.rs` and `udp.rs` within the same
-directory.
+directory, with Unix-domain sockets (`-U`) in `unix.rs`, the proxy handshakes
+of the TCP client in `proxy.rs`, `--exec` (behind the `exec` feature) in
+`exec.rs`, the `--rate` and `--accept-rate` limiters in `throttle.rs`, the
+`-t` telnet negotiation in `telnet.rs`, the `--chat` relay in `chat.rs`,
+`--also`'s several destinations in `fanout.rs`, the `-z --syn` half-open
+scanner (behind the `syn-scan` feature) in `synscan.rs` and internationalized
+hostnames in `idn.rs`; `util.rs` holds socket-option plumbing shared by both
+and `stats.rs` the per-session byte accounting. All of them take their
+tunables from a shared [`Config`].
 */
 
+pub mod chat;
+#[cfg(feature = "exec")]
+pub mod exec;
+pub mod fanout;
+pub mod idn;
+pub mod proxy;
+pub mod stats;
+#[cfg(all(feature = "syn-scan", target_os = "linux"))]
+pub mod synscan;
 pub mod tcp;
+pub mod telnet;
+pub mod throttle;
 pub mod udp;
+#[cfg(unix)]
+pub mod unix;
+pub mod util;
+
+use std::fmt;
+use std::io::{IsTerminal, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+use tokio::task::{AbortHandle, JoinError, JoinHandle};
+
+use crate::cli::{self, Args};
+use crate::diag;
+use crate::filter::{Crlf, Filter, Replace, StripCr};
+use crate::format::{ColorWhen, Hexdump, Timestamp};
+use crate::pcap::PcapFile;
+use crate::tee::LogTap;
+
+/// Input read size when `-I` is not given.
+pub const DEFAULT_SEND_LENGTH: usize = 8192;
+/// Socket read size when `-O` is not given.
+pub const DEFAULT_RECV_LENGTH: usize = 65536;
+/// Largest UDP payload, and so the largest useful `-O` with `-u`.
+const MAX_DATAGRAM: usize = 65536;
+
+/// Process exit status of any failure without a more specific status.
+pub const EXIT_ERROR: i32 = 1;
+/// Process exit status when the destination refused the connection.
+pub const EXIT_REFUSED: i32 = 2;
+/// Process exit status when a connect or accept timed out (`-w`).
+pub const EXIT_TIMEOUT: i32 = 3;
+/// Process exit status after SIGINT or SIGTERM ended the run (128 + SIGINT,
+/// as shells report it).
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// The process exit status reporting the failure `e`: [`EXIT_REFUSED`] or
+/// [`EXIT_TIMEOUT`] when an IO error of that kind caused it, otherwise
+/// [`EXIT_ERROR`].
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+    let kind = e
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .map(std::io::Error::kind)
+        .find(|kind| {
+            matches!(
+                kind,
+                std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::TimedOut
+            )
+        });
+    match kind {
+        Some(std::io::ErrorKind::ConnectionRefused) => EXIT_REFUSED,
+        Some(_) => EXIT_TIMEOUT,
+        None => EXIT_ERROR,
+    }
+}
+
+/// Session tunables shared by the network functions.
+///
+/// Usually built from the command line with `Config::try_from(&args)`, which
+/// also validates escape sequences. Library users can start from `default()`
+/// and set the fields they need; every session function takes the same
+/// `&Config`, so new tunables never change their signatures:
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use r_cat::net::{Config, tcp};
+/// use tokio::io::AsyncWriteExt;
+/// use tokio::net::TcpListener;
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let runtime = tokio::runtime::Runtime::new()?;
+/// let stats = runtime.block_on(async {
+///     let listener = TcpListener::bind("127.0.0.1:0").await?;
+///     let port = listener.local_addr()?.port();
+///     tokio::spawn(async move {
+///         let (mut peer, _) = listener.accept().await?;
+///         // Hanging up afterwards ends the session
+///         peer.write_all(b"hello\n").await
+///     });
+///
+///     let config = Config {
+///         timeout: Some(Duration::from_secs(5)),
+///         ..Config::default()
+///     };
+///     tcp::client("127.0.0.1", port, &config).await
+/// })?;
+/// // The session may end with stdin still open; don't wait for its reader
+/// runtime.shutdown_background();
+/// assert_eq!(stats.bytes_received, 6);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Connect/accept timeout, and how long a session may go without
+    /// receiving anything (`-w`).
+    pub timeout: Option<Duration>,
+    /// IP family destinations are resolved to and listeners bind (`-4`/`-6`).
+    pub family: util::Family,
+    /// Only accept literal IP destinations, never looking names up (`-n`).
+    pub numeric: bool,
+    /// Proxy the TCP client tunnels through (`-X`, `-x`, `-P`).
+    pub proxy: Option<proxy::Proxy>,
+    /// Local address client sockets are bound to before connecting (`-s`).
+    pub source: Option<IpAddr>,
+    /// Local port client sockets are bound to (`-p` outside listen mode).
+    pub source_port: Option<u16>,
+    /// Ports each TCP connection binds a random one of, both ends included
+    /// (`--source-port-range`).
+    pub source_port_range: Option<(u16, u16)>,
+    /// How many times `-v` was given, 0 under `-Q`. Level 2 adds per-chunk
+    /// byte counts and peer addresses, level 3 socket events and timeouts.
+    pub verbosity: u8,
+    /// Bytes written after input EOF, right before the FIN (`--eof-marker`).
+    pub eof_marker: Option<Vec<u8>>,
+    /// Send a file instead of stdin, reporting progress on stderr (`--upload`,
+    /// `--sendfile`).
+    pub upload: Option<Upload>,
+    /// Where session input comes from (`--input-file`, stdin by default).
+    pub input: Input,
+    /// Where received data goes (`--output-file`, `--append`; stdout by
+    /// default).
+    pub output: Output,
+    /// Banner sent to a listen-mode client each time it has been silent for
+    /// the period; received data resets the timer (`--idle-banner`).
+    pub idle_banner: Option<(Vec<u8>, Duration)>,
+    /// Poll the destination until a response matches (`--repeat-until-match`).
+    pub until_match: Option<UntilMatch>,
+    /// Send the input a line at a time, each once the peer's reply has been
+    /// quiet this long (`--turn-based`).
+    pub turn_based: Option<Duration>,
+    /// Pause between input lines sent over TCP, in listen mode too (`-i`).
+    pub interval: Option<Duration>,
+    /// How long to keep reading once our input is done; `None` waits for the
+    /// peer to close (`-q`).
+    pub quit_after: Option<Duration>,
+    /// Bytes read from the input per send (`-I`); see [`Config::send_len`].
+    pub send_length: Option<usize>,
+    /// Bytes read from the socket at a time (`-O`); see [`Config::recv_len`].
+    pub recv_length: Option<usize>,
+    /// Probe `-z` ports with a lone SYN from a raw socket instead of a
+    /// full connect (`--syn`, Linux with the `syn-scan` feature).
+    pub syn_scan: bool,
+    /// Keep listening after a connection closes (`-k`).
+    pub keep_open: bool,
+    /// How many connections a keep-open listener serves before it exits,
+    /// or under `chat` how many clients it holds at once (`--max-conns`).
+    pub max_conns: Option<u32>,
+    /// Most connections per second a keep-open TCP listener accepts; the
+    /// rest wait in the kernel's accept queue (`--accept-rate`).
+    pub accept_rate: Option<u32>,
+    /// Let other sockets bind a listener's port too (`--reuseport`).
+    pub reuseport: bool,
+    /// Report the credentials of each peer a Unix-socket listener accepts
+    /// (`--print-peer-cred`).
+    pub print_peer_cred: bool,
+    /// Keep sending after the peer's EOF instead of ending the session
+    /// (`--half-close`).
+    pub half_close: bool,
+    /// Refuse the TCP peer's telnet option negotiation, hiding it from
+    /// output (`-t`).
+    pub telnet: bool,
+    /// Keep our side of a stream connection open after input ends instead
+    /// of sending a FIN (`--no-shutdown`).
+    pub no_shutdown: bool,
+    /// Leave the TCP connection's receive direction alone (`--send-only`).
+    pub send_only: bool,
+    /// Leave the TCP connection's send direction alone, keeping our side open
+    /// until the session ends (`--recv-only`).
+    pub recv_only: bool,
+    /// Set `TCP_NODELAY` on connected sockets (`-D`; OpenBSD's `-D` turns on
+    /// socket debugging instead).
+    pub nodelay: bool,
+    /// IP TOS (IPv6 traffic class) byte of client sockets (`-T`).
+    pub tos: Option<u8>,
+    /// Enable TCP keepalive on connected sockets (`--keepalive*`).
+    pub keepalive: Option<util::Keepalive>,
+    /// Reflect each accepted connection back to itself instead of using
+    /// stdin/stdout (`--echo-server`).
+    pub echo_server: bool,
+    /// Relay lines between all connected clients instead of running one
+    /// session at a time (`--chat`).
+    pub chat: bool,
+    /// Hand each TCP connection to this shell command instead of
+    /// stdin/stdout (`--exec`).
+    pub exec: Option<String>,
+    /// Longest time received output waits in a buffer before it is written
+    /// to stdout (`--flush-interval`); unbuffered when unset.
+    pub flush_interval: Option<Duration>,
+    /// Most bytes per second sent to a TCP peer (`--rate`); unlimited when unset.
+    pub rate: Option<u64>,
+    /// Simulated think time of a listener before its first outbound byte, or
+    /// before each echoed chunk (`--reply-delay`).
+    pub reply_delay: Option<Duration>,
+    /// Announce the bound address of a listener (`--print-ready`).
+    pub ready: Option<ReadySignal>,
+    /// `(from, to)` substitutions applied to outbound data (`--replace-send`).
+    pub replace_send: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Drop the CR of outbound CRLF pairs, ahead of the replacements (`--strip-cr`).
+    pub strip_cr: bool,
+    /// Send bare LFs of outbound data as CRLF, ahead of the replacements (`-C`).
+    pub crlf: bool,
+    /// `(from, to)` substitutions applied to inbound data (`--replace-recv`).
+    pub replace_recv: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Show received data as a hex dump rather than raw (`--hexdump`).
+    pub hexdump: bool,
+    /// Colour the hex dump by byte kind (`--color-hexdump`, as `--color`
+    /// allows).
+    pub hexdump_color: bool,
+    /// Start each line of received output with the time (`--timestamp`).
+    pub timestamp: bool,
+    /// Discover the path MTU instead of exchanging data (`--mtu-probe`).
+    pub mtu_probe: bool,
+    /// Classify the peer's first bytes and report the guess (`--detect-protocol`).
+    pub detect_protocol: bool,
+    /// Longest message (in bytes) the peer may send before a delimiter, and
+    /// that delimiter; a longer one closes the connection, failing the
+    /// session (`--max-message-size`, `--message-delimiter`).
+    pub max_message: Option<(u64, Vec<u8>)>,
+    /// Stop once the peer's HTTP header block has been received (`--headers-only`).
+    pub headers_only: bool,
+    /// Delay policy for retry loops (`--backoff*`).
+    pub backoff: util::Backoff,
+    /// How often a failed destination lookup is retried (`--retry-resolve`).
+    pub retry_resolve: u32,
+    /// How often a refused or timed-out TCP connect is retried (`--retry`).
+    pub retry_connect: u32,
+    /// Report the client's connect latency on stderr (`--report-rtt`).
+    pub report_rtt: bool,
+    /// Append a record per finished session to this file (`--stats-file`).
+    pub stats_file: Option<PathBuf>,
+    /// Only accept UDP replies from the address we send to, dropping (and
+    /// under `-v` logging) the rest (`--udp-strict-peer`).
+    pub udp_strict_peer: bool,
+    /// Leave the UDP client socket unconnected, taking datagrams from any
+    /// source (`--udp-loose`).
+    pub udp_loose: bool,
+    /// Send one UDP datagram per input line, its newline dropped
+    /// (`--line-datagrams`).
+    pub line_datagrams: bool,
+    /// Leave the newline on each line datagram (`--keep-newline`).
+    pub keep_newline: bool,
+    /// Reply with the TOS byte the last UDP datagram arrived with, Linux only
+    /// (`--dscp-passthrough`).
+    pub dscp_passthrough: bool,
+    /// Group a UDP listener joins (`-l -u GROUP PORT`, `--multicast-iface`).
+    pub multicast: Option<Multicast>,
+    /// Set `SO_BROADCAST` on UDP sockets, so the remote may be a broadcast
+    /// address and every host that answers is heard (`-b`).
+    pub broadcast: bool,
+    /// Capture of all TCP session traffic, created up front and shared by
+    /// every session (`--pcap`).
+    pub pcap: Option<Arc<PcapFile>>,
+    /// Log of all TCP and UDP traffic, opened up front and shared by every
+    /// session (`--log-file`).
+    pub log_file: Option<Arc<LogTap>>,
+    /// Receive buffers shared by every stream session, so successive
+    /// connections reuse them instead of allocating their own.
+    pub buffers: Arc<util::BufferPool>,
+    /// Report the socket options in effect once connected (`--dump-sockopts`).
+    pub dump_sockopts: bool,
+    /// Echo every chunk of TCP session traffic on stderr (`--annotate`).
+    pub annotate: bool,
+    /// Inherited descriptor receiving a copy of sent TCP data (`--tee-sent-fd`).
+    pub tee_sent_fd: Option<i32>,
+    /// Inherited descriptor receiving a copy of received TCP data
+    /// (`--tee-recv-fd`).
+    pub tee_recv_fd: Option<i32>,
+    /// Shell command started, without waiting for it, right after a
+    /// connection is accepted (`--on-connect`).
+    pub on_connect: Option<String>,
+    /// Shell command started, without waiting for it, once an accepted
+    /// connection's session is over (`--on-disconnect`).
+    pub on_disconnect: Option<String>,
+    /// RFC 2385 key signing every segment of a TCP client connection (`-S --md5-key`).
+    pub md5_key: Option<Vec<u8>>,
+    /// Shell command run on each TCP client socket before it connects (`--socket-hook`).
+    pub socket_hook: Option<String>,
+    /// Ends the connect, accept or session in progress once cancelled, the
+    /// function returning the stats gathered so far. `TryFrom<&Args>` leaves
+    /// it unset; the binary adds one cancelled by SIGINT and SIGTERM (see
+    /// [`install_signal_handler`]).
+    pub cancel: Option<CancelToken>,
+}
+
+/// What a finished session moved, as returned by the client and listen
+/// functions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub duration: Duration,
+}
+
+/// A handle for stopping sessions from the outside: every clone shares the
+/// same state, so one can go in [`Config::cancel`] while another is kept to
+/// call [`cancel`](Self::cancel) on.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<CancelState>);
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop every session using this token, now and in the future.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Wait until [`cancel`](Self::cancel) has been called.
+    pub async fn cancelled(&self) {
+        let notified = self.0.notify.notified();
+        tokio::pin!(notified);
+        // Register before checking, so a cancel in between is not missed
+        notified.as_mut().enable();
+        if !self.is_cancelled() {
+            notified.await;
+        }
+    }
+}
+
+/// Cancel `token` on the first SIGINT or SIGTERM (Ctrl-C where there are no
+/// Unix signals), so the running session winds down, flushing its output,
+/// instead of the process dying. Must be called within a tokio runtime; the
+/// handlers are in place once it returns.
+pub fn install_signal_handler(token: CancelToken) -> std::io::Result<JoinHandle<()>> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        Ok(tokio::spawn(async move {
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                _ = terminate.recv() => {}
+            }
+            token.cancel();
+        }))
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                token.cancel();
+            }
+        }))
+    }
+}
+
+/// The source of what a session sends.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Input {
+    #[default]
+    Stdin,
+    File(PathBuf),
+}
+
+/// An opened [`Input`].
+pub type InputStream = Box<dyn AsyncRead + Send + Unpin>;
+
+impl Input {
+    /// Open the input for reading. The network functions do this before any
+    /// socket is set up, so a missing or unreadable file fails at startup.
+    pub async fn open(&self) -> anyhow::Result<InputStream> {
+        match self {
+            Input::Stdin => Ok(Box::new(InputReader::new(tokio::io::stdin()))),
+            Input::File(path) => {
+                let err = |e| anyhow::anyhow!("cannot open input file '{}': {}", path.display(), e);
+                let file = tokio::fs::File::open(path).await.map_err(err)?;
+                // A directory opens fine and only fails once read
+                if file.metadata().await.map_err(err)?.is_dir() {
+                    return Err(err(std::io::Error::from(std::io::ErrorKind::IsADirectory)));
+                }
+                Ok(Box::new(InputReader::new(file)))
+            }
+        }
+    }
+}
+
+/// What an [`InputReader`] wraps its errors in, telling a failure to read
+/// the input apart from one on the socket it is copied to.
+#[derive(Debug)]
+pub struct InputError(pub std::io::Error);
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "reading input: {}", self.0)
+    }
+}
+
+impl std::error::Error for InputError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl InputError {
+    /// Whether `e` came from reading the input.
+    pub fn caused(e: &std::io::Error) -> bool {
+        e.get_ref().is_some_and(|inner| inner.is::<InputError>())
+    }
+}
+
+/// An `AsyncRead` whose errors are marked as [`InputError`]s, keeping their
+/// kind. Every [`InputStream`] is one, so the sessions can report a broken
+/// input instead of taking it for EOF.
+#[derive(Debug)]
+pub struct InputReader<R> {
+    inner: R,
+}
+
+impl<R> InputReader<R> {
+    pub fn new(inner: R) -> Self {
+        InputReader { inner }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for InputReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_read(cx, buf)
+            .map_err(|e| std::io::Error::new(e.kind(), InputError(e)))
+    }
+}
+
+/// Where a session's received data goes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Output {
+    #[default]
+    Stdout,
+    /// A file, emptied first unless `append` is set.
+    File { path: PathBuf, append: bool },
+}
+
+/// A writer of received data, see [`OpenOutput::writer`].
+pub type OutputStream = Box<dyn AsyncWrite + Send + Unpin>;
+
+impl Output {
+    /// Open the output once for all the sessions a network function runs,
+    /// before any socket is set up, so a bad path fails at startup.
+    pub async fn open(&self) -> anyhow::Result<OpenOutput> {
+        match self {
+            Output::Stdout => Ok(OpenOutput(None)),
+            Output::File { path, append } => {
+                let file = tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .append(*append)
+                    .truncate(!*append)
+                    .open(path)
+                    .await
+                    .map_err(|e| {
+                        anyhow::anyhow!("cannot open output file '{}': {}", path.display(), e)
+                    })?;
+                Ok(OpenOutput(Some(file.into_std().await)))
+            }
+        }
+    }
+}
+
+/// An opened [`Output`].
+#[derive(Debug)]
+pub struct OpenOutput(Option<std::fs::File>);
+
+impl OpenOutput {
+    /// A writer for one session. Sessions writing to a file share its offset,
+    /// so each one continues where the previous left off.
+    ///
+    /// The file only has all the data once the writer has been flushed.
+    pub fn writer(&self) -> std::io::Result<OutputStream> {
+        match &self.0 {
+            None => Ok(Box::new(OutputWriter::new(tokio::io::stdout()))),
+            Some(file) => Ok(Box::new(OutputWriter::new(tokio::fs::File::from_std(
+                file.try_clone()?,
+            )))),
+        }
+    }
+}
+
+/// What an [`OutputWriter`] wraps its errors in, telling a failure to write
+/// the output apart from one on the socket the data came from.
+#[derive(Debug)]
+pub struct OutputError(pub std::io::Error);
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "writing output: {}", self.0)
+    }
+}
+
+impl std::error::Error for OutputError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl OutputError {
+    /// Whether `e` came from writing the output.
+    pub fn caused(e: &std::io::Error) -> bool {
+        e.get_ref().is_some_and(|inner| inner.is::<OutputError>())
+    }
+}
+
+/// An `AsyncWrite` whose errors are marked as [`OutputError`]s, keeping
+/// their kind. Every [`OutputStream`] is one, so the sessions can fail when
+/// received data cannot be delivered.
+#[derive(Debug)]
+pub struct OutputWriter<W> {
+    inner: W,
+}
+
+impl<W> OutputWriter<W> {
+    pub fn new(inner: W) -> Self {
+        OutputWriter { inner }
+    }
+}
+
+fn output_error(e: std::io::Error) -> std::io::Error {
+    std::io::Error::new(e.kind(), OutputError(e))
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for OutputWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_write(cx, buf)
+            .map_err(output_error)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(output_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_shutdown(cx)
+            .map_err(output_error)
+    }
+}
+
+/// The local ends of a stream session: its input, unless it sends nothing
+/// (a later connection of `-k`), and the writer its received data goes to.
+pub(crate) struct Local {
+    pub input: Option<InputStream>,
+    pub output: OutputStream,
+}
+
+/// A file sent in place of stdin, starting `resume_from` bytes in.
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub path: PathBuf,
+    pub resume_from: u64,
+    /// Prefer `sendfile(2)` over copying through userspace (`--sendfile`).
+    pub zero_copy: bool,
+}
+
+/// Readiness polling: reconnect every `delay`, resending the same request,
+/// until a response contains `pattern`. `max_attempts` of 0 never gives up.
+/// With `source_ports`, attempt `n` binds the `n`th of them, starting over
+/// at the end. A `template` is sent in place of the input, each `{{i}}` in
+/// it replaced by the attempt number, counting from 1.
+#[derive(Debug, Clone)]
+pub struct UntilMatch {
+    pub pattern: Vec<u8>,
+    pub delay: Duration,
+    pub max_attempts: u32,
+    pub source_ports: Option<Vec<u16>>,
+    pub template: Option<Vec<u8>>,
+}
+
+/// A multicast group to receive on, and the interface to join it on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Multicast {
+    /// `INADDR_ANY` as the interface lets the kernel pick one.
+    V4 { group: Ipv4Addr, iface: Ipv4Addr },
+    /// Interface index 0 lets the kernel pick one.
+    V6 { group: Ipv6Addr, iface: u32 },
+}
+
+impl Multicast {
+    /// `group` with the interface named by `--multicast-iface`, if any.
+    fn new(group: IpAddr, iface: Option<&str>) -> anyhow::Result<Self> {
+        let bad_iface = |what| {
+            anyhow::anyhow!(
+                "--multicast-iface: '{}' is not {}",
+                iface.unwrap_or_default(),
+                what
+            )
+        };
+        Ok(match group {
+            IpAddr::V4(group) => Multicast::V4 {
+                group,
+                iface: match iface {
+                    Some(s) => s.parse().map_err(|_| bad_iface("an IPv4 address"))?,
+                    None => Ipv4Addr::UNSPECIFIED,
+                },
+            },
+            IpAddr::V6(group) => Multicast::V6 {
+                group,
+                iface: match iface {
+                    Some(s) => s.parse().map_err(|_| bad_iface("an interface index"))?,
+                    None => 0,
+                },
+            },
+        })
+    }
+
+    pub fn group(&self) -> IpAddr {
+        match *self {
+            Multicast::V4 { group, .. } => group.into(),
+            Multicast::V6 { group, .. } => group.into(),
+        }
+    }
+}
+
+impl TryFrom<&Args> for Config {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &Args) -> anyhow::Result<Self> {
+        // Interpret escapes up front so typos fail before any socket is opened
+        let eof_marker = args.eof_marker.as_deref().map(cli::unescape).transpose()?;
+        let idle_banner = args
+            .idle_banner
+            .as_deref()
+            .map(cli::unescape)
+            .transpose()?
+            .map(|banner| (banner, Duration::from_secs_f64(args.idle_banner_after)));
+
+        // `--ready-fd` implies `--print-ready`
+        let ready = match (args.ready_fd, args.print_ready) {
+            (Some(fd), _) => Some(ReadySignal::Fd(fd)),
+            (None, true) => Some(ReadySignal::Stderr),
+            (None, false) => None,
+        };
+
+        let max_message = match args.max_message_size {
+            Some(max) => {
+                let delimiter = cli::unescape(&args.message_delimiter)?;
+                if delimiter.is_empty() {
+                    anyhow::bail!("--message-delimiter must not be empty");
+                }
+                Some((max, delimiter))
+            }
+            None => None,
+        };
+
+        let source = args
+            .source
+            .as_deref()
+            .map(|s| {
+                s.parse::<IpAddr>()
+                    .map_err(|_| anyhow::anyhow!("-s: '{}' is not an IP address", s))
+            })
+            .transpose()?;
+
+        if args.exec.is_some() {
+            if !cfg!(feature = "exec") {
+                anyhow::bail!("--exec: this r-cat was built without the `exec` feature");
+            }
+            if args.udp || args.unix {
+                anyhow::bail!("--exec only works with TCP connections");
+            }
+        }
+
+        if args.syn {
+            if !cfg!(all(feature = "syn-scan", target_os = "linux")) {
+                anyhow::bail!(
+                    "--syn: this r-cat was built without the `syn-scan` feature, which needs Linux"
+                );
+            }
+            if args.proxy.is_some() {
+                anyhow::bail!("--syn: a half-open scan cannot go through a proxy");
+            }
+            if args.ipv6 {
+                anyhow::bail!("--syn only scans IPv4 destinations");
+            }
+        }
+
+        if let Some(rate) = args.rate {
+            if rate == 0 {
+                anyhow::bail!("--rate must be at least 1 byte per second");
+            }
+            if args.udp || args.unix {
+                anyhow::bail!("--rate only applies to TCP connections");
+            }
+        }
+
+        if args.no_shutdown && args.udp {
+            anyhow::bail!("--no-shutdown only applies to stream connections");
+        }
+        if args.crlf && args.udp {
+            anyhow::bail!("-C only applies to stream connections");
+        }
+        if args.strip_cr && args.udp {
+            anyhow::bail!("--strip-cr only applies to stream connections");
+        }
+        if (!args.replace_send.is_empty() || !args.replace_recv.is_empty()) && args.udp {
+            anyhow::bail!("--replace-send and --replace-recv only apply to stream connections");
+        }
+        if args.telnet && (args.udp || args.unix) {
+            anyhow::bail!("-t only applies to TCP connections");
+        }
+        if (args.send_only || args.recv_only) && (args.udp || args.unix) {
+            anyhow::bail!("--send-only and --recv-only only apply to TCP connections");
+        }
+        if args.udp_loose && (!args.udp || args.listen) {
+            anyhow::bail!("--udp-loose only applies to UDP clients");
+        }
+        if args.chat && (args.udp || args.unix) {
+            anyhow::bail!("--chat only applies to TCP listeners");
+        }
+        if args.max_conns.is_some() && (args.udp || args.unix) {
+            anyhow::bail!("--max-conns only applies to TCP listeners");
+        }
+        if args.accept_rate.is_some() && (args.udp || args.unix) {
+            anyhow::bail!("--accept-rate only applies to TCP listeners");
+        }
+        if !args.also.is_empty() && (args.udp || args.unix || args.listen) {
+            anyhow::bail!("--also only applies to TCP clients");
+        }
+        let source_port_range = args
+            .source_port_range
+            .as_deref()
+            .map(cli::parse_port_range)
+            .transpose()?;
+        if source_port_range.is_some() {
+            if args.udp || args.unix || args.listen {
+                anyhow::bail!("--source-port-range only applies to TCP clients");
+            }
+            if !(args.zero || args.random || args.connect_source_cycle) {
+                anyhow::bail!("--source-port-range needs -z, -r or --connect-source-cycle");
+            }
+        }
+
+        let until_match = match &args.repeat_until_match {
+            Some(pattern) => {
+                let pattern = cli::unescape(pattern)?;
+                if pattern.is_empty() {
+                    anyhow::bail!("--repeat-until-match must not be empty");
+                }
+                let source_ports = match source_port_range {
+                    Some((lo, hi)) if args.connect_source_cycle => {
+                        let mut ports: Vec<u16> = (lo..=hi).collect();
+                        if args.random {
+                            cli::shuffle_ports(&mut ports);
+                        }
+                        Some(ports)
+                    }
+                    _ => None,
+                };
+                let template = args
+                    .input_template
+                    .as_deref()
+                    .map(|path| {
+                        std::fs::read(path).map_err(|e| {
+                            anyhow::anyhow!(
+                                "--input-template: cannot read {}: {}",
+                                path.display(),
+                                e
+                            )
+                        })
+                    })
+                    .transpose()?;
+                Some(UntilMatch {
+                    pattern,
+                    delay: Duration::from_secs_f64(args.repeat_delay),
+                    max_attempts: args.repeat_max,
+                    source_ports,
+                    template,
+                })
+            }
+            None => None,
+        };
+
+        for (flag, secs) in [
+            ("--keepalive-idle", args.keepalive_idle),
+            ("--keepalive-interval", args.keepalive_interval),
+        ] {
+            if let Some(secs) = secs
+                && secs > util::MAX_KEEPALIVE_SECS
+            {
+                anyhow::bail!(
+                    "{} must be at most {} seconds",
+                    flag,
+                    util::MAX_KEEPALIVE_SECS
+                );
+            }
+        }
+
+        let tos = args.tos.as_deref().map(cli::parse_tos).transpose()?;
+        if tos.is_some() && (args.listen || args.unix) {
+            anyhow::bail!("-T only applies to TCP and UDP clients");
+        }
+
+        let proxy = match (&args.proxy, &args.proxy_proto) {
+            (Some(_), _) if args.udp => anyhow::bail!("-x: UDP cannot go through a proxy"),
+            (Some(addr), proto) => Some(proxy::Proxy::new(
+                proto.as_deref(),
+                addr,
+                args.proxy_username.as_deref(),
+                std::env::var(proxy::PASSWORD_ENV).ok(),
+            )?),
+            (None, Some(_)) => anyhow::bail!("-X needs a proxy address (-x)"),
+            (None, None) => None,
+        };
+
+        // A UDP listener given a group address receives that group's traffic
+        let multicast = match args.destination.as_deref().map(str::parse::<IpAddr>) {
+            Some(Ok(group)) if args.listen && args.udp && group.is_multicast() => {
+                if (args.ipv4 && group.is_ipv6()) || (args.ipv6 && group.is_ipv4()) {
+                    anyhow::bail!("multicast group {} is of the wrong IP family", group);
+                }
+                Some(Multicast::new(group, args.multicast_iface.as_deref())?)
+            }
+            _ if args.multicast_iface.is_some() => anyhow::bail!(
+                "--multicast-iface needs a multicast group to listen on (-l -u GROUP PORT)"
+            ),
+            _ => None,
+        };
+
+        for (flag, len) in [("-I", args.send_length), ("-O", args.recv_length)] {
+            if len == Some(0) {
+                anyhow::bail!("{} must be at least 1 byte", flag);
+            }
+        }
+        if args.udp
+            && let Some(len) = args.recv_length
+            && len > MAX_DATAGRAM
+        {
+            anyhow::bail!(
+                "-O {} is larger than any UDP datagram ({} bytes)",
+                len,
+                MAX_DATAGRAM
+            );
+        }
+
+        let md5_key = match (args.md5sig, &args.md5_key) {
+            (true, None) => anyhow::bail!("-S needs a key (--md5-key)"),
+            (true, Some(key)) if key.len() > util::MD5SIG_MAX_KEY_LEN => anyhow::bail!(
+                "--md5-key is longer than {} bytes",
+                util::MD5SIG_MAX_KEY_LEN
+            ),
+            (true, Some(key)) => Some(key.clone().into_bytes()),
+            (false, _) => None,
+        };
+
+        let parse_all = |specs: &[String]| {
+            specs
+                .iter()
+                .map(|spec| cli::parse_replacement(spec))
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+
+        Ok(Config {
+            timeout: args.timeout.map(Duration::from_secs_f64),
+            source,
+            // In listen mode -p is the port to listen on, which main handles
+            source_port: args.source_port.filter(|_| !args.listen),
+            source_port_range,
+            // Quiet wins over verbose
+            verbosity: if args.quiet { 0 } else { args.verbose },
+            eof_marker,
+            input: match &args.input_file {
+                Some(path) => Input::File(path.clone()),
+                None => Input::Stdin,
+            },
+            output: match &args.output_file {
+                Some(path) => Output::File {
+                    path: path.clone(),
+                    append: args.append,
+                },
+                None => Output::Stdout,
+            },
+            upload: match (&args.upload, &args.sendfile) {
+                (Some(path), _) => Some(Upload {
+                    path: path.clone(),
+                    resume_from: args.resume_from,
+                    zero_copy: false,
+                }),
+                (None, Some(path)) => Some(Upload {
+                    path: path.clone(),
+                    resume_from: 0,
+                    zero_copy: true,
+                }),
+                (None, None) => None,
+            },
+            idle_banner,
+            until_match,
+            turn_based: args
+                .turn_based
+                .then(|| Duration::from_secs_f64(args.turn_idle)),
+            quit_after: args.quit_after.map(Duration::from_secs),
+            send_length: args.send_length,
+            recv_length: args.recv_length,
+            family: match (args.ipv4, args.ipv6) {
+                (true, _) => util::Family::V4,
+                (_, true) => util::Family::V6,
+                _ => util::Family::Any,
+            },
+            numeric: args.numeric,
+            proxy,
+            syn_scan: args.syn,
+            keep_open: args.keep_open,
+            max_conns: args.max_conns,
+            accept_rate: args.accept_rate,
+            reuseport: args.reuseport,
+            print_peer_cred: args.print_peer_cred,
+            half_close: args.half_close,
+            telnet: args.telnet,
+            no_shutdown: args.no_shutdown,
+            send_only: args.send_only,
+            recv_only: args.recv_only,
+            nodelay: args.no_delay_ack,
+            tos,
+            keepalive: args.keepalive.then_some(util::Keepalive {
+                idle: args.keepalive_idle,
+                interval: args.keepalive_interval,
+            }),
+            echo_server: args.echo_server,
+            chat: args.chat,
+            exec: args.exec.clone(),
+            interval: args.interval.map(Duration::from_secs_f64),
+            reply_delay: args.reply_delay.map(Duration::from_secs_f64),
+            ready,
+            replace_send: parse_all(&args.replace_send)?,
+            strip_cr: args.strip_cr,
+            crlf: args.crlf,
+            replace_recv: parse_all(&args.replace_recv)?,
+            hexdump: args.hexdump,
+            hexdump_color: args.color_hexdump
+                && match args.color {
+                    ColorWhen::Always => true,
+                    ColorWhen::Never => false,
+                    ColorWhen::Auto => {
+                        args.output_file.is_none() && std::io::stdout().is_terminal()
+                    }
+                },
+            timestamp: args.timestamp,
+            mtu_probe: args.mtu_probe,
+            detect_protocol: args.detect_protocol,
+            max_message,
+            headers_only: args.headers_only,
+            backoff: util::Backoff {
+                kind: args.backoff,
+                base: Duration::from_secs_f64(args.backoff_base),
+                max: Duration::from_secs_f64(args.backoff_max),
+                jitter: args.backoff_jitter,
+            },
+            retry_resolve: args.retry_resolve,
+            retry_connect: args.retry,
+            report_rtt: args.report_rtt,
+            flush_interval: args.flush_interval.map(Duration::from_millis),
+            rate: args.rate,
+            stats_file: args.stats_file.clone(),
+            udp_strict_peer: args.udp_strict_peer,
+            udp_loose: args.udp_loose,
+            line_datagrams: args.line_datagrams,
+            keep_newline: args.keep_newline,
+            dscp_passthrough: args.dscp_passthrough,
+            multicast,
+            broadcast: args.broadcast,
+            pcap: args
+                .pcap
+                .as_deref()
+                .map(|path| PcapFile::create(path).map(Arc::new))
+                .transpose()?,
+            log_file: args
+                .log_file
+                .as_deref()
+                .map(|path| LogTap::open(path).map(Arc::new))
+                .transpose()?,
+            buffers: Arc::default(),
+            annotate: args.annotate,
+            dump_sockopts: args.dump_sockopts,
+            tee_sent_fd: args
+                .tee_sent_fd
+                .map(|fd| check_fd("--tee-sent-fd", fd))
+                .transpose()?,
+            tee_recv_fd: args
+                .tee_recv_fd
+                .map(|fd| check_fd("--tee-recv-fd", fd))
+                .transpose()?,
+            on_connect: args.on_connect.clone(),
+            on_disconnect: args.on_disconnect.clone(),
+            md5_key,
+            socket_hook: args.socket_hook.clone(),
+            cancel: None,
+        })
+    }
+}
+
+impl Config {
+    /// Whether any `-v` was given, i.e. `verbosity >= 1`.
+    pub fn verbose(&self) -> bool {
+        self.verbosity >= 1
+    }
+
+    /// Read size for the input side of a session.
+    pub fn send_len(&self) -> usize {
+        self.send_length.unwrap_or(DEFAULT_SEND_LENGTH)
+    }
+
+    /// Read size for the socket side of a session.
+    pub fn recv_len(&self) -> usize {
+        self.recv_length.unwrap_or(DEFAULT_RECV_LENGTH)
+    }
+
+    /// Fresh filter chain for data read from the input and sent to the peer.
+    pub(crate) fn send_filters(&self) -> Vec<Box<dyn Filter>> {
+        let mut chain: Vec<Box<dyn Filter>> = Vec::new();
+        if self.strip_cr {
+            chain.push(Box::new(StripCr::default()));
+        }
+        if self.crlf {
+            chain.push(Box::new(Crlf::default()));
+        }
+        chain.extend(replace_chain(&self.replace_send));
+        chain
+    }
+
+    /// Fresh filter chain for data received from the peer.
+    pub(crate) fn recv_filters(&self) -> Vec<Box<dyn Filter>> {
+        let mut chain = replace_chain(&self.replace_recv);
+        chain.extend(self.display_filters());
+        chain
+    }
+
+    /// The filters rendering received data for stdout (`--hexdump`,
+    /// `--timestamp`), which come after any that change the data itself.
+    /// Timestamps go last so that they prefix the dump's lines.
+    pub(crate) fn display_filters(&self) -> Vec<Box<dyn Filter>> {
+        let mut chain: Vec<Box<dyn Filter>> = Vec::new();
+        if self.hexdump {
+            chain.push(Box::new(Hexdump::new(self.hexdump_color)));
+        }
+        if self.timestamp {
+            chain.push(Box::new(Timestamp::default()));
+        }
+        chain
+    }
+}
+
+fn replace_chain(pairs: &[(Vec<u8>, Vec<u8>)]) -> Vec<Box<dyn Filter>> {
+    pairs
+        .iter()
+        .map(|(from, to)| Box::new(Replace::new(from.clone(), to.clone())) as Box<dyn Filter>)
+        .collect()
+}
+
+/// Where a listener announces that it is bound (`--print-ready`/`--ready-fd`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadySignal {
+    Stderr,
+    /// An inherited file descriptor, left open after writing.
+    Fd(i32),
+}
+
+/// Make sure `fd` was actually inherited, so a missing redirection fails
+/// before any socket is opened rather than on the first write.
+fn check_fd(flag: &str, fd: i32) -> anyhow::Result<i32> {
+    #[cfg(unix)]
+    {
+        // SAFETY: F_GETFD only queries the descriptor flags.
+        if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            anyhow::bail!("{}: file descriptor {} is not open", flag, fd);
+        }
+        Ok(fd)
+    }
+    #[cfg(not(unix))]
+    anyhow::bail!("{} is only supported on Unix", flag)
+}
+
+/// Write `READY <addr>` to the configured destination.
+///
+/// This is a machine-readable signal for harnesses, so it is emitted even
+/// under `--quiet`.
+pub fn announce_ready(signal: ReadySignal, addr: SocketAddr) -> anyhow::Result<()> {
+    let line = format!("READY {}\n", addr);
+    match signal {
+        ReadySignal::Stderr => {
+            let mut stderr = std::io::stderr();
+            stderr.write_all(line.as_bytes())?;
+            stderr.flush()?;
+        }
+        #[cfg(unix)]
+        ReadySignal::Fd(fd) => {
+            use std::os::fd::FromRawFd;
+
+            // SAFETY: the fd is owned by whoever spawned us; ManuallyDrop keeps
+            // it open so repeated announcements (or the spawner) can reuse it.
+            let mut file = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd) });
+            file.write_all(line.as_bytes())
+                .map_err(|e| anyhow::anyhow!("cannot write ready signal to fd {}: {}", fd, e))?;
+        }
+        #[cfg(not(unix))]
+        ReadySignal::Fd(_) => anyhow::bail!("--ready-fd is only supported on Unix"),
+    }
+    Ok(())
+}
+
+/// Wait for the two IO tasks of a session.
+///
+/// If either task panics (or is cancelled), the other one is aborted and an
+/// error is returned so the session fails loudly instead of hanging.
+pub async fn join_tasks<A, B>(
+    mut a: JoinHandle<A>,
+    mut b: JoinHandle<B>,
+    verbose: bool,
+) -> anyhow::Result<(A, B)> {
+    tokio::select! {
+        res = &mut a => {
+            let a_out = task_output(res, &b.abort_handle(), verbose)?;
+            let b_out = task_output(b.await, &a.abort_handle(), verbose)?;
+            Ok((a_out, b_out))
+        }
+        res = &mut b => {
+            let b_out = task_output(res, &a.abort_handle(), verbose)?;
+            let a_out = task_output(a.await, &b.abort_handle(), verbose)?;
+            Ok((a_out, b_out))
+        }
+    }
+}
+
+fn task_output<T>(
+    res: Result<T, JoinError>,
+    peer: &AbortHandle,
+    verbose: bool,
+) -> anyhow::Result<T> {
+    res.map_err(|e| {
+        peer.abort();
+        if verbose {
+            diag!("IO task failed: {}", e);
+        }
+        anyhow::anyhow!("IO task failed: {}", e)
+    })
+}
+
+/// Wait for `token` to be cancelled, or forever when there is none.
+pub(crate) async fn cancelled(token: Option<&CancelToken>) {
+    match token {
+        Some(token) => token.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sleep until `due`, or forever when there is no deadline.
+pub(crate) async fn sleep_until(due: Option<tokio::time::Instant>) {
+    match due {
+        Some(due) => tokio::time::sleep_until(due).await,
+        None => std::future::pending().await,
+    }
+}
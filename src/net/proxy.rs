@@ -0,0 +1,292 @@
+/*!
+r-cat/src/net/proxy.rs
+
+Client-side proxy support (`-X`, `-x`, `-P`).
+
+The TCP client connects to the proxy like to any other destination (so `-s`,
+`-p` and the socket hooks apply to that connection) and then asks it for a
+tunnel to the real destination here, with a SOCKS5 `CONNECT` request or an
+HTTP `CONNECT` method. Once the handshake is done the stream
+carries the session as if it were a direct connection.
+*/
+
+use std::net::IpAddr;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Environment variable holding the password sent along with `-P`.
+pub const PASSWORD_ENV: &str = "RCAT_PROXY_PASSWORD";
+
+/// Port assumed when `-x` names a SOCKS proxy without one.
+const SOCKS_PORT: u16 = 1080;
+
+/// Port assumed when `-x` names an HTTP proxy without one.
+const HTTP_PORT: u16 = 3128;
+
+/// Longest response header block accepted from an HTTP proxy.
+const MAX_HTTP_HEADER: usize = 16 * 1024;
+
+/// Which protocol the proxy speaks (`-X`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// SOCKS version 5 (RFC 1928), the default.
+    Socks5,
+    /// An HTTP proxy's `CONNECT` method (RFC 9110 §9.3.6).
+    HttpConnect,
+}
+
+/// A proxy to tunnel client connections through.
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    /// `host:port` of the proxy itself.
+    pub addr: String,
+    /// Username/password authentication (RFC 1929), if the proxy wants it.
+    pub auth: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// Build the proxy settings from `-X proto`, `-x host[:port]` and `-P user`.
+    pub fn new(
+        proto: Option<&str>,
+        addr: &str,
+        user: Option<&str>,
+        password: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let (kind, default_port) = match proto.unwrap_or("5") {
+            "5" | "socks5" => (ProxyKind::Socks5, SOCKS_PORT),
+            "connect" | "http" => (ProxyKind::HttpConnect, HTTP_PORT),
+            other => anyhow::bail!("-X: unsupported proxy protocol '{}'", other),
+        };
+        let has_port = match addr.rsplit_once(':') {
+            // A bare IPv6 literal has colons but no port
+            Some((host, port)) => {
+                (!host.contains(':') || host.ends_with(']')) && port.parse::<u16>().is_ok()
+            }
+            None => false,
+        };
+        let addr = if has_port {
+            addr.to_string()
+        } else if addr.contains(':') && !addr.starts_with('[') {
+            format!("[{}]:{}", addr, default_port)
+        } else {
+            format!("{}:{}", addr, default_port)
+        };
+        let auth = user.map(|u| (u.to_string(), password.unwrap_or_default()));
+        if let Some((user, pass)) = &auth
+            && kind == ProxyKind::Socks5
+            && (user.len() > 255 || pass.len() > 255)
+        {
+            anyhow::bail!("-P: proxy username and password are limited to 255 bytes each");
+        }
+        Ok(Proxy { kind, addr, auth })
+    }
+}
+
+/// Ask the proxy on `stream` for a tunnel to `host:port`, per `proxy.kind`.
+pub async fn handshake<S>(
+    stream: &mut S,
+    proxy: &Proxy,
+    host: &str,
+    port: u16,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match proxy.kind {
+        ProxyKind::Socks5 => socks5_connect(stream, proxy.auth.as_ref(), host, port).await,
+        ProxyKind::HttpConnect => http_connect(stream, proxy.auth.as_ref(), host, port).await,
+    }
+}
+
+/// The SOCKS5 greeting, optional username/password login and CONNECT
+/// request for `host:port`.
+///
+/// Host names are passed to the proxy unresolved, so it does the lookup.
+pub async fn socks5_connect<S>(
+    stream: &mut S,
+    auth: Option<&(String, String)>,
+    host: &str,
+    port: u16,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Offer "no authentication", plus username/password when we have one
+    let greeting: &[u8] = if auth.is_some() {
+        &[5, 2, 0, 2]
+    } else {
+        &[5, 1, 0]
+    };
+    stream.write_all(greeting).await?;
+    let mut choice = [0u8; 2];
+    read_reply(stream, &mut choice).await?;
+    if choice[0] != 5 {
+        anyhow::bail!("proxy does not speak SOCKS5");
+    }
+    match (choice[1], auth) {
+        (0, _) => {}
+        (2, Some((user, pass))) => {
+            let mut login = vec![1, user.len() as u8];
+            login.extend_from_slice(user.as_bytes());
+            login.push(pass.len() as u8);
+            login.extend_from_slice(pass.as_bytes());
+            stream.write_all(&login).await?;
+            let mut status = [0u8; 2];
+            read_reply(stream, &mut status).await?;
+            if status[1] != 0 {
+                anyhow::bail!("SOCKS5 proxy rejected username '{}'", user);
+            }
+        }
+        _ => anyhow::bail!("SOCKS5 proxy accepts none of our authentication methods"),
+    }
+
+    let mut request = vec![5, 1, 0];
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                anyhow::bail!("host name '{}' is too long for SOCKS5", host);
+            }
+            request.extend_from_slice(&[3, host.len() as u8]);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    read_reply(stream, &mut reply).await?;
+    if reply[1] != 0 {
+        anyhow::bail!(
+            "SOCKS5 proxy could not connect to {}:{}: {}",
+            host,
+            port,
+            socks5_error(reply[1])
+        );
+    }
+    // Skip the address the proxy bound for us
+    let skip = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        other => anyhow::bail!("SOCKS5 proxy replied with unknown address type {}", other),
+    };
+    let mut bound = vec![0u8; skip + 2];
+    read_reply(stream, &mut bound).await?;
+    Ok(())
+}
+
+/// Ask an HTTP proxy to `CONNECT` to `host:port`, logging in with Basic
+/// authentication when `auth` is given.
+///
+/// Only a `200` response opens the tunnel. The response is read a byte at a
+/// time up to its blank line, so whatever the destination sends first is
+/// left in the stream.
+pub async fn http_connect<S>(
+    stream: &mut S,
+    auth: Option<&(String, String)>,
+    host: &str,
+    port: u16,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let authority = if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let Some((user, pass)) = auth {
+        let credentials = base64(format!("{}:{}", user, pass).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HTTP_HEADER {
+            anyhow::bail!(
+                "HTTP proxy response header is over {} bytes",
+                MAX_HTTP_HEADER
+            );
+        }
+        let mut byte = [0u8; 1];
+        read_reply(stream, &mut byte).await?;
+        header.push(byte[0]);
+    }
+    let header = String::from_utf8_lossy(&header);
+    let status_line = header.lines().next().unwrap_or_default();
+    let mut parts = status_line.splitn(3, ' ');
+    let (version, code, reason) = (parts.next(), parts.next(), parts.next());
+    if !version.is_some_and(|v| v.starts_with("HTTP/")) {
+        anyhow::bail!("HTTP proxy sent a malformed status line: '{}'", status_line);
+    }
+    match code {
+        Some("200") => Ok(()),
+        Some(code) => anyhow::bail!(
+            "HTTP proxy refused CONNECT to {}: {} {}",
+            authority,
+            code,
+            reason.unwrap_or_default()
+        ),
+        None => anyhow::bail!("HTTP proxy sent a malformed status line: '{}'", status_line),
+    }
+}
+
+/// Standard base64 with padding, for `Proxy-Authorization`.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | ((b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Fill `buf` from the proxy, treating an early close as a handshake failure.
+async fn read_reply<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut [u8]) -> anyhow::Result<()> {
+    stream
+        .read_exact(buf)
+        .await
+        .map_err(|e| anyhow::anyhow!("proxy closed the connection during the handshake: {}", e))?;
+    Ok(())
+}
+
+/// The meaning of a SOCKS5 reply code (RFC 1928 §6).
+fn socks5_error(code: u8) -> String {
+    match code {
+        1 => "general SOCKS server failure".to_string(),
+        2 => "connection not allowed by ruleset".to_string(),
+        3 => "network unreachable".to_string(),
+        4 => "host unreachable".to_string(),
+        5 => "connection refused".to_string(),
+        6 => "TTL expired".to_string(),
+        7 => "command not supported".to_string(),
+        8 => "address type not supported".to_string(),
+        other => format!("unknown reply code {}", other),
+    }
+}
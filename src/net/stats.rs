@@ -0,0 +1,137 @@
+/*!
+r-cat/src/net/stats.rs
+
+Per-session traffic accounting and the `--stats-file` record writer.
+
+Byte counts are kept in shared atomics updated from the tee path as data
+moves, so a session cut short by `-w` still reports what it transferred.
+*/
+
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::tee::{Direction, Tap};
+
+/// Live byte counters for both directions of a session.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub sent: AtomicU64,
+    pub received: AtomicU64,
+}
+
+impl Tap for Counters {
+    fn record(&self, dir: Direction, data: &[u8]) {
+        let counter = match dir {
+            Direction::Sent => &self.sent,
+            Direction::Received => &self.received,
+        };
+        counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+    }
+
+    fn needs_data(&self) -> bool {
+        false
+    }
+}
+
+impl Counters {
+    /// Count bytes sent without the tee path seeing them (zero-copy sends).
+    pub fn add_sent(&self, n: u64) {
+        self.sent.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+}
+
+/// One line of `--stats-file` output, describing a finished session.
+#[derive(Debug, Clone)]
+pub struct SessionRecord {
+    pub peer: Option<SocketAddr>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub duration: Duration,
+    /// `completed`, `timeout`, or `error: <reason>`.
+    pub exit: String,
+}
+
+const CSV_HEADER: &str = "timestamp,peer,bytes_sent,bytes_received,duration_ms,exit";
+
+/// Append `record` to `path`: CSV if the name ends in `.csv`, JSON Lines otherwise.
+///
+/// A CSV header is written first when the file is new or empty.
+pub fn append_record(path: &Path, record: &SessionRecord) -> io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let peer = record.peer.map(|p| p.to_string()).unwrap_or_default();
+    let duration_ms = record.duration.as_millis();
+
+    let line = if path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("csv"))
+    {
+        let mut line = String::new();
+        if file.metadata()?.len() == 0 {
+            line.push_str(CSV_HEADER);
+            line.push('\n');
+        }
+        line.push_str(&format!(
+            "{},{},{},{},{},{}",
+            timestamp,
+            peer,
+            record.bytes_sent,
+            record.bytes_received,
+            duration_ms,
+            csv_field(&record.exit)
+        ));
+        line
+    } else {
+        format!(
+            "{{\"timestamp\":{},\"peer\":\"{}\",\"bytes_sent\":{},\"bytes_received\":{},\"duration_ms\":{},\"exit\":\"{}\"}}",
+            timestamp,
+            peer,
+            record.bytes_sent,
+            record.bytes_received,
+            duration_ms,
+            json_escape(&record.exit)
+        )
+    };
+    writeln!(file, "{}", line)
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
@@ -0,0 +1,203 @@
+/*!
+r-cat/src/net/synscan.rs
+
+Half-open port scanning for `-z --syn` (Linux, behind the `syn-scan` build
+feature).
+
+Instead of connecting, each probe sends a lone SYN from a raw socket and
+reads the answer off the same socket: a SYN-ACK means the port is open, and
+is answered with a RST so the handshake never completes; a RST means it is
+closed; silence for `-w` (or [`DEFAULT_WAIT`]) counts as a timeout. Raw
+sockets need root or `CAP_NET_RAW`. Only IPv4 destinations are scanned, and
+proxies do not apply.
+*/
+
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::unix::AsyncFd;
+use tokio::time::{self, Instant};
+
+use super::tcp::PortState;
+use super::{Config, util};
+use crate::diag;
+
+/// How long a probe waits for an answer when `-w` is not given.
+pub const DEFAULT_WAIT: Duration = Duration::from_secs(2);
+
+/// Ports probes are sent from without `-p` or `--source-port-range`: the
+/// top of Linux's default ephemeral range.
+const SOURCE_PORTS: (u16, u16) = (50000, 60999);
+
+const TCP_HEADER_LEN: usize = 20;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const ACK: u8 = 0x10;
+
+/// Probe `host:port` with a SYN, without completing the handshake.
+///
+/// Errors mirror [`super::tcp::scan`]'s: failures other than a RST or
+/// silence are returned, a raw socket the process may not open among them.
+pub async fn scan(host: &str, port: u16, config: &Config) -> anyhow::Result<PortState> {
+    let addr = format!("{}:{}", host, port);
+    let target = util::resolve_destination(&addr, config)
+        .await?
+        .into_iter()
+        .find_map(|a| match a.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("--syn: {} has no IPv4 address", host))?;
+    let source = match config.source {
+        Some(IpAddr::V4(ip)) => ip,
+        Some(IpAddr::V6(ip)) => anyhow::bail!("--syn: source address {} is not IPv4", ip),
+        None => route_source(target, port)?,
+    };
+    let source_port = match (config.source_port, config.source_port_range) {
+        (Some(p), _) => p,
+        (None, Some((lo, hi))) => util::random_port(lo, hi),
+        (None, None) => util::random_port(SOURCE_PORTS.0, SOURCE_PORTS.1),
+    };
+
+    let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::TCP)).map_err(|e| {
+        if e.kind() == io::ErrorKind::PermissionDenied {
+            anyhow::anyhow!(
+                "--syn needs a raw socket: run as root or with CAP_NET_RAW ({})",
+                e
+            )
+        } else {
+            anyhow::anyhow!("--syn: cannot open a raw socket: {}", e)
+        }
+    })?;
+    socket.set_nonblocking(true)?;
+    let socket = AsyncFd::new(socket)?;
+    let dest = SocketAddr::new(target.into(), 0).into();
+
+    let seq = util::random_u64() as u32;
+    let syn = segment((source, source_port), (target, port), seq, 0, SYN);
+    socket.get_ref().send_to(&syn, &dest)?;
+    if config.verbosity >= 3 {
+        diag!("SYN sent to {}:{} from port {}", target, port, source_port);
+    }
+
+    let deadline = Instant::now() + config.timeout.unwrap_or(DEFAULT_WAIT);
+    // Room for an IP header with options and a TCP header with options
+    let mut packet = [0u8; 120];
+    loop {
+        let Ok(ready) = time::timeout_at(deadline, socket.readable()).await else {
+            return Ok(PortState::TimedOut);
+        };
+        let n = match ready?.try_io(|s| s.get_ref().read(&mut packet)) {
+            Ok(res) => res?,
+            Err(_would_block) => continue,
+        };
+        let Some(reply) = Reply::parse(&packet[..n]) else {
+            continue;
+        };
+        // Every inbound TCP segment reaches a raw socket; only the
+        // target's answer to this probe counts
+        if reply.from != (target, port)
+            || reply.to_port != source_port
+            || reply.ack != seq.wrapping_add(1)
+        {
+            continue;
+        }
+        if reply.flags & RST != 0 {
+            return Ok(PortState::Refused);
+        }
+        if reply.flags & (SYN | ACK) == SYN | ACK {
+            let rst = segment(
+                (source, source_port),
+                (target, port),
+                seq.wrapping_add(1),
+                0,
+                RST,
+            );
+            // The kernel resets the stray SYN-ACK too, so this is a courtesy
+            let _ = socket.get_ref().send_to(&rst, &dest);
+            return Ok(PortState::Open);
+        }
+    }
+}
+
+/// The local address the kernel would send from to reach `target`, found by
+/// connecting (which sends nothing) a throwaway UDP socket.
+fn route_source(target: Ipv4Addr, port: u16) -> io::Result<Ipv4Addr> {
+    let probe = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    probe.connect((target, port))?;
+    match probe.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => Err(io::ErrorKind::AddrNotAvailable.into()),
+    }
+}
+
+/// An option-less TCP header from `from` to `to`, checksummed over the IPv4
+/// pseudo-header. With an `IPPROTO_TCP` raw socket the kernel adds the IP
+/// header.
+fn segment(
+    from: (Ipv4Addr, u16),
+    to: (Ipv4Addr, u16),
+    seq: u32,
+    ack: u32,
+    flags: u8,
+) -> [u8; TCP_HEADER_LEN] {
+    let mut tcp = [0u8; TCP_HEADER_LEN];
+    tcp[0..2].copy_from_slice(&from.1.to_be_bytes());
+    tcp[2..4].copy_from_slice(&to.1.to_be_bytes());
+    tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+    tcp[8..12].copy_from_slice(&ack.to_be_bytes());
+    tcp[12] = ((TCP_HEADER_LEN / 4) as u8) << 4;
+    tcp[13] = flags;
+    tcp[14..16].copy_from_slice(&1024u16.to_be_bytes());
+
+    let mut pseudo = Vec::with_capacity(12 + TCP_HEADER_LEN);
+    pseudo.extend_from_slice(&from.0.octets());
+    pseudo.extend_from_slice(&to.0.octets());
+    pseudo.extend_from_slice(&[0, libc::IPPROTO_TCP as u8]);
+    pseudo.extend_from_slice(&(TCP_HEADER_LEN as u16).to_be_bytes());
+    pseudo.extend_from_slice(&tcp);
+    tcp[16..18].copy_from_slice(&checksum(&pseudo).to_be_bytes());
+    tcp
+}
+
+/// The internet checksum (RFC 1071) of `data`.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The parts of an inbound IPv4 TCP segment a probe looks at.
+struct Reply {
+    from: (Ipv4Addr, u16),
+    to_port: u16,
+    ack: u32,
+    flags: u8,
+}
+
+impl Reply {
+    /// Split a packet as read from the raw socket, IP header included;
+    /// `None` for anything too short to be a TCP segment.
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let ihl = usize::from(*packet.first()? & 0x0f) * 4;
+        if packet.get(9) != Some(&(libc::IPPROTO_TCP as u8)) {
+            return None;
+        }
+        let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+        let tcp = packet.get(ihl..ihl + TCP_HEADER_LEN)?;
+        let port = |at: usize| u16::from_be_bytes([tcp[at], tcp[at + 1]]);
+        Some(Reply {
+            from: (Ipv4Addr::from(src), port(0)),
+            to_port: port(2),
+            ack: u32::from_be_bytes(tcp[8..12].try_into().ok()?),
+            flags: tcp[13],
+        })
+    }
+}
@@ -1,145 +1,1467 @@
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{self, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::time;
+use tokio::fs::File;
+use tokio::io::{
+    self, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+    BufReader,
+};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{Notify, mpsc};
+use tokio::time::{self, Instant};
 
-/// TCP related helpers for r-cat.
-///
-/// This module exposes two async functions:
-/// - `tcp_client` - connect to a remote TCP server and shuttle stdin <-> socket.
-/// - `tcp_listen` - bind a TCP listener, accept one connection and shuttle stdin <-> socket.
+use super::chat;
+#[cfg(feature = "exec")]
+use super::exec;
+use super::idn;
+use super::stats::{self, Counters, SessionRecord};
+use super::telnet::{ReplyWriter, Telnet};
+use super::throttle::{Bucket, Throttle};
+use super::{
+    CancelToken, Config, EXIT_REFUSED, EXIT_TIMEOUT, InputError, InputStream, Local, OutputError,
+    OutputStream, SessionStats, UntilMatch, Upload, announce_ready, cancelled, join_tasks, proxy,
+    sleep_until, util,
+};
+use crate::filter::FilterWriter;
+use crate::flush::TimedFlush;
+use crate::pcap::PcapTap;
+#[cfg(unix)]
+use crate::tee::FdTap;
+use crate::tee::{Annotate, ChunkSizes, Direction, Tee, TeeWriter};
+use crate::{detect, diag};
+
+/// Environment variable carrying the peer address to `--on-connect` hooks.
+const PEER_ENV: &str = "RCAT_PEER";
+
+/// Environment variable naming the socket fd passed to `--socket-hook`.
+const SOCKET_FD_ENV: &str = "RCAT_SOCKET_FD";
+
+/// Random source ports tried per connection under `--source-port-range`
+/// before giving up on finding a free one.
+const SOURCE_PORT_ATTEMPTS: u32 = 4;
+
+/// How often upload progress is reported on stderr.
+const PROGRESS_EVERY: Duration = Duration::from_secs(1);
+
+/// An upload file opened and positioned at its resume offset.
+struct OpenUpload {
+    file: File,
+    offset: u64,
+    len: u64,
+    zero_copy: bool,
+}
+
+/// How stdin is fed to the socket when no upload file replaces it.
+enum Pacing {
+    /// Copy as fast as input arrives.
+    Stream,
+    /// Also send a banner after each period without inbound data.
+    IdleBanner(Vec<u8>, Duration),
+    /// One line at a time, each after the peer has been quiet for a period.
+    Turns(Duration),
+    /// One line at a time, a fixed period apart (`-i`).
+    Interval(Duration),
+    /// Nothing: stdin already belongs to an earlier `-k` connection.
+    Silent,
+}
+
+/// Connect to `host:port` and shuttle the input (`config.input`) <-> socket,
+/// writing received data to `config.output`.
 ///
-/// These functions mirror the basic behavior previously implemented inline in main.
-/// They return `anyhow::Result<()>` to simplify error propagation from the binary.
-pub async fn client(
-    host: &str,
-    port: u16,
-    timeout: Option<Duration>,
-    verbose: bool,
-) -> anyhow::Result<()> {
+/// The input and output are opened before connecting, so a bad path fails at
+/// once. The connect goes through `config.proxy` when one is set, is bounded
+/// by `-w` and retried per `config.retry_connect`; with `config.until_match`
+/// the client polls the destination instead (`poll_until_match`). The rest of
+/// the session is set up from `config` as its fields describe, and runs as
+/// in `shuttle`. Returns the session's [`SessionStats`].
+pub async fn client(host: &str, port: u16, config: &Config) -> anyhow::Result<SessionStats> {
+    let verbose = config.verbose();
+
+    // Open the upload source and input before connecting so a bad path
+    // fails fast
+    let upload = match &config.upload {
+        Some(u) => Some(open_upload(u).await?),
+        None => None,
+    };
+    let input = config.input.open().await?;
+    let output = config.output.open().await?;
+
     let addr = format!("{}:{}", host, port);
+    if let Some(until) = &config.until_match {
+        return poll_until_match(host, port, until, input, output.writer()?, config).await;
+    }
     if verbose {
-        eprintln!("Connecting to {}", addr);
+        diag!("Connecting to {}", addr);
     }
 
-    let connect_fut = TcpStream::connect(addr);
-    let stream = if let Some(dur) = timeout {
-        match time::timeout(dur, connect_fut).await {
-            Ok(Ok(s)) => s,
-            Ok(Err(e)) => return Err(anyhow::anyhow!("connect error: {}", e)),
-            Err(_) => return Err(anyhow::anyhow!("connect timed out after {:?}", dur)),
+    let connecting = async {
+        let mut attempt = 0;
+        loop {
+            let connect_fut = dial(host, port, config);
+            let res = match config.timeout {
+                Some(dur) => match time::timeout(dur, connect_fut).await {
+                    Ok(res) => res.map_err(|e| e.context("connect error")),
+                    Err(_) => Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("connect timed out after {:?}", dur),
+                    )
+                    .into()),
+                },
+                None => connect_fut.await,
+            };
+            match res {
+                Ok(connected) => break Ok(connected),
+                // Only a destination that is not up (yet) is worth another try
+                Err(e) if attempt < config.retry_connect && is_connect_failure(&e) => {
+                    let delay = config.backoff.delay(attempt);
+                    attempt += 1;
+                    if verbose {
+                        diag!(
+                            "Connecting to {} failed ({:#}), retry {}/{} in {:?}",
+                            addr,
+                            e,
+                            attempt,
+                            config.retry_connect,
+                            delay
+                        );
+                    }
+                    time::sleep(delay).await;
+                }
+                Err(e) => return Err(e),
+            }
         }
-    } else {
-        connect_fut.await?
+    };
+    let (stream, handshake) = tokio::select! {
+        connected = connecting => connected?,
+        _ = cancelled(config.cancel.as_ref()) => return Ok(SessionStats::default()),
     };
 
+    if config.report_rtt {
+        diag!("connected in {:.1}ms", handshake.as_secs_f64() * 1000.0);
+    }
+    set_stream_options(&stream, config)?;
+    #[cfg(feature = "exec")]
+    if let Some(cmd) = &config.exec {
+        return exec::exec_pipe(stream, cmd, verbose).await;
+    }
     if verbose {
-        eprintln!("Connected, starting IO copy");
+        diag!("Connected, starting IO copy");
     }
 
-    // Split so we can read and write concurrently
-    let (mut reader, mut writer) = stream.into_split();
-    let mut stdin = io::stdin();
-    let mut stdout = io::stdout();
+    let pacing = match (config.turn_based, config.interval) {
+        (Some(idle), _) => Pacing::Turns(idle),
+        (None, Some(period)) => Pacing::Interval(period),
+        (None, None) => Pacing::Stream,
+    };
+    let local = Local {
+        input: Some(input),
+        output: output.writer()?,
+    };
+    shuttle(stream, config, local, upload, pacing, None, "Session").await
+}
 
-    // stdin -> socket
-    let write_task = tokio::spawn(async move {
-        let res = io::copy(&mut stdin, &mut writer).await;
-        // attempt to shutdown the write half gracefully
-        let _ = writer.shutdown().await;
-        res
-    });
+/// Outcome of probing one port in zero-I/O mode (`-z`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    /// Something accepted the connection.
+    Open,
+    /// The host answered with a reset (`ECONNREFUSED`).
+    Refused,
+    /// No answer within `-w` (or the kernel's own connect timeout).
+    TimedOut,
+}
 
-    // socket -> stdout
-    let read_task = tokio::spawn(async move {
-        let res = io::copy(&mut reader, &mut stdout).await;
-        let _ = stdout.flush().await;
-        res
-    });
+impl PortState {
+    /// Process exit status reporting this state: 0 open, [`EXIT_REFUSED`]
+    /// or [`EXIT_TIMEOUT`].
+    pub fn exit_code(self) -> i32 {
+        match self {
+            PortState::Open => 0,
+            PortState::Refused => EXIT_REFUSED,
+            PortState::TimedOut => EXIT_TIMEOUT,
+        }
+    }
 
-    if let Some(dur) = timeout {
-        match time::timeout(dur, async {
-            let _ = tokio::join!(write_task, read_task);
-        })
-        .await
-        {
-            Ok(_) => {
-                if verbose {
-                    eprintln!("Session finished");
+    /// The result line for `host:port`, in OpenBSD netcat's wording.
+    pub fn describe(self, host: &str, port: u16) -> String {
+        match self {
+            PortState::Open => format!("Connection to {} {} port [tcp/*] succeeded!", host, port),
+            PortState::Refused => format!(
+                "connect to {} port {} (tcp) failed: Connection refused",
+                host, port
+            ),
+            PortState::TimedOut => {
+                format!("connect to {} port {} (tcp) failed: timed out", host, port)
+            }
+        }
+    }
+}
+
+/// Probe `host:port` without exchanging any data (`-z`): connect, then close
+/// straight away.
+///
+/// The connect is bounded by `config.timeout`. Failures other than a refusal
+/// or a timeout (unreachable network, failed lookup, ...) are returned as
+/// errors; [`PortState::describe`] words the outcome for the user. With
+/// `config.source_port_range`, each probe comes from a random port in it.
+/// `config.syn_scan` hands the probe to the half-open scanner instead.
+pub async fn scan(host: &str, port: u16, config: &Config) -> anyhow::Result<PortState> {
+    #[cfg(all(feature = "syn-scan", target_os = "linux"))]
+    if config.syn_scan {
+        return super::synscan::scan(host, port, config).await;
+    }
+    let addr = format!("{}:{}", host, port);
+    let attempt = connect(&addr, config);
+    let res = match config.timeout {
+        Some(dur) => time::timeout(dur, attempt)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => attempt.await,
+    };
+    match res {
+        Ok(_) => Ok(PortState::Open),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(PortState::Refused),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(PortState::TimedOut),
+        Err(e) => anyhow::bail!("connect to {} port {} (tcp) failed: {}", host, port, e),
+    }
+}
+
+/// Send stdin to `host:port` over a fresh connection every `until.delay`
+/// until a response contains `until.pattern`, then print that response.
+///
+/// The input is read once up front, run through the send filters and the EOF
+/// marker, and resent on every attempt; `until.template` instead is filled
+/// in and filtered afresh for each one. Connections go through
+/// `config.proxy` like any other, from the ports of `until.source_ports` in
+/// turn when set. Failed connects count as attempts too; each one is bounded
+/// by `-w` when set.
+async fn poll_until_match(
+    host: &str,
+    port: u16,
+    until: &UntilMatch,
+    mut input: InputStream,
+    mut output: OutputStream,
+    config: &Config,
+) -> anyhow::Result<SessionStats> {
+    let started = Instant::now();
+    let mut request = match &until.template {
+        // Rebuilt for every attempt below
+        Some(_) => Vec::new(),
+        None => {
+            let mut raw = Vec::new();
+            input.read_to_end(&mut raw).await?;
+            filtered_request(&raw, config).await?
+        }
+    };
+
+    // --connect-source-cycle: each attempt binds the next port, in place of
+    // a random one from the range
+    let mut cycling = until.source_ports.as_deref().map(|ports| {
+        let config = Config {
+            source_port_range: None,
+            ..config.clone()
+        };
+        (ports, config)
+    });
+    let mut attempt = 0u32;
+    loop {
+        attempt += 1;
+        let config = match &mut cycling {
+            Some((ports, cycled)) => {
+                cycled.source_port = Some(ports[(attempt as usize - 1) % ports.len()]);
+                &*cycled
+            }
+            None => config,
+        };
+        if let Some(template) = &until.template {
+            let raw = substitute_attempt(template, attempt);
+            request = filtered_request(&raw, config).await?;
+        }
+        let probe = probe_once(host, port, &request, &until.pattern, config);
+        let outcome = match config.timeout {
+            Some(dur) => time::timeout(dur, probe)
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out"))),
+            None => probe.await,
+        };
+        match outcome {
+            Ok(Some(response)) => {
+                if config.verbose() {
+                    diag!("Attempt {}: response matched", attempt);
                 }
+                output.write_all(&response).await?;
+                output.flush().await?;
+                return Ok(SessionStats {
+                    bytes_sent: request.len() as u64,
+                    bytes_received: response.len() as u64,
+                    duration: started.elapsed(),
+                });
             }
-            Err(_) => {
-                if verbose {
-                    eprintln!("Session timed out after {:?}", dur);
+            Ok(None) if config.verbose() => diag!("Attempt {}: no match", attempt),
+            Err(e) if config.verbose() => diag!("Attempt {}: {:#}", attempt, e),
+            _ => {}
+        }
+        if until.max_attempts != 0 && attempt >= until.max_attempts {
+            anyhow::bail!(
+                "no response matched '{}' after {} attempts",
+                until.pattern.escape_ascii(),
+                attempt
+            );
+        }
+        time::sleep(until.delay).await;
+    }
+}
+
+/// `raw` as a polling round sends it: through the send filters, then the
+/// EOF marker.
+async fn filtered_request(raw: &[u8], config: &Config) -> io::Result<Vec<u8>> {
+    let mut request = FilterWriter::new(Vec::new(), config.send_filters());
+    request.write_all(raw).await?;
+    request.end().await?;
+    let mut request = request.into_inner();
+    if let Some(marker) = &config.eof_marker {
+        request.extend_from_slice(marker);
+    }
+    Ok(request)
+}
+
+/// `template` with every `{{i}}` replaced by `attempt`.
+fn substitute_attempt(template: &[u8], attempt: u32) -> Vec<u8> {
+    const VAR: &[u8] = b"{{i}}";
+    let value = attempt.to_string();
+    let mut out = Vec::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(at) = rest.windows(VAR.len()).position(|w| w == VAR) {
+        out.extend_from_slice(&rest[..at]);
+        out.extend_from_slice(value.as_bytes());
+        rest = &rest[at + VAR.len()..];
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+/// One polling round: connect, send `request`, and read until `pattern`
+/// shows up (returning the response so far) or the peer closes.
+async fn probe_once(
+    host: &str,
+    port: u16,
+    request: &[u8],
+    pattern: &[u8],
+    config: &Config,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let (mut stream, _) = dial(host, port, config).await?;
+    stream.write_all(request).await?;
+    stream.shutdown().await?;
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        // Only the new bytes and a pattern's worth before them can match
+        let from = response.len().saturating_sub(pattern.len() - 1);
+        response.extend_from_slice(&buf[..n]);
+        if response[from..]
+            .windows(pattern.len())
+            .any(|w| w == pattern)
+        {
+            return Ok(Some(response));
+        }
+    }
+}
+
+/// Connect to `host:port`, through `config.proxy` when one is set.
+///
+/// The returned handshake time is that of the connection to the proxy.
+async fn dial(host: &str, port: u16, config: &Config) -> anyhow::Result<(TcpStream, Duration)> {
+    let Some(proxy) = &config.proxy else {
+        return Ok(connect(&format!("{}:{}", host, port), config).await?);
+    };
+    if config.numeric {
+        // The proxy does the lookup, but -n still only allows addresses
+        util::parse_numeric(&format!("{}:{}", host, port))?;
+    }
+    // The proxy resolves the name, so it gets the ASCII form too
+    let host = idn::to_ascii(host)?;
+    if config.verbose() {
+        diag!("Connecting through proxy {}", proxy.addr);
+    }
+    let (mut stream, handshake) = connect(&proxy.addr, config)
+        .await
+        .map_err(|e| anyhow::Error::new(e).context(format!("cannot reach proxy {}", proxy.addr)))?;
+    proxy::handshake(&mut stream, proxy, &host, port).await?;
+    Ok((stream, handshake))
+}
+
+/// Whether `e` means the connection itself failed (refused, timed out,
+/// unreachable), as opposed to a lookup, proxy or setup error.
+fn is_connect_failure(e: &anyhow::Error) -> bool {
+    use io::ErrorKind::*;
+
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| {
+            matches!(
+                e.kind(),
+                ConnectionRefused
+                    | ConnectionReset
+                    | ConnectionAborted
+                    | TimedOut
+                    | HostUnreachable
+                    | NetworkUnreachable
+            )
+        })
+}
+
+/// Resolve `addr` (see [`util::resolve_destination`]) and connect to the
+/// first address that accepts, racing the attempts per
+/// [`util::happy_eyeballs`] when there are several. Destinations of another
+/// IP family than `config.source` are skipped.
+///
+/// Also returns how long the successful handshake took, excluding the
+/// lookup and any hook.
+pub(crate) async fn connect(addr: &str, config: &Config) -> io::Result<(TcpStream, Duration)> {
+    let mut targets = util::resolve_destination(addr, config).await?;
+    if let Some(source) = config.source {
+        let (usable, other): (Vec<_>, Vec<_>) = targets
+            .into_iter()
+            .partition(|t| t.is_ipv4() == source.is_ipv4());
+        if usable.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "source address {} and destination {} are of different IP families",
+                    source, other[0]
+                ),
+            ));
+        }
+        targets = usable;
+    }
+    match targets[..] {
+        [target] => connect_to(target, config).await,
+        _ => util::happy_eyeballs(targets, util::ATTEMPT_DELAY, |t| connect_to(t, config)).await,
+    }
+}
+
+/// One connection attempt to `target`, from a random port of
+/// `config.source_port_range` when that is set. A port that turns out to
+/// be taken is swapped for another, up to [`SOURCE_PORT_ATTEMPTS`] in all.
+async fn connect_to(target: SocketAddr, config: &Config) -> io::Result<(TcpStream, Duration)> {
+    let Some((lo, hi)) = config.source_port_range else {
+        return connect_from(target, config.source_port, config).await;
+    };
+    let mut attempt = 1;
+    loop {
+        let port = util::random_port(lo, hi);
+        match connect_from(target, Some(port), config).await {
+            Err(e)
+                if attempt < SOURCE_PORT_ATTEMPTS
+                    && matches!(
+                        e.kind(),
+                        io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+                    ) =>
+            {
+                attempt += 1;
+                if config.verbose() {
+                    diag!("Source port {} is taken, trying another", port);
                 }
             }
+            res => return res,
         }
+    }
+}
+
+/// Connect to `target` from source port `port`. The socket is bound to
+/// `config.source` and `port` first, then gets `config.tos` and
+/// `config.md5_key` and is handed to `config.socket_hook`, when those are
+/// set.
+async fn connect_from(
+    target: SocketAddr,
+    port: Option<u16>,
+    config: &Config,
+) -> io::Result<(TcpStream, Duration)> {
+    let socket = if target.is_ipv4() {
+        TcpSocket::new_v4()?
     } else {
-        let _ = tokio::join!(write_task, read_task);
+        TcpSocket::new_v6()?
+    };
+    let local = util::local_bind(target, config.source, port);
+    if let Some(local) = local {
+        // A fixed port would otherwise stay blocked by TIME_WAIT between runs
+        if local.port() != 0 {
+            socket.set_reuseaddr(true)?;
+        }
+        socket.bind(local).map_err(|e| util::bind_error(local, e))?;
+    }
+    if let Some(tos) = config.tos {
+        util::set_tos(&socket, tos)
+            .map_err(|e| io::Error::new(e.kind(), format!("IP_TOS: {}", e)))?;
+    }
+    if let Some(key) = &config.md5_key {
+        util::set_tcp_md5sig(&socket, target, key)
+            .map_err(|e| io::Error::new(e.kind(), format!("TCP_MD5SIG: {}", e)))?;
+    }
+    if let Some(hook) = &config.socket_hook {
+        run_socket_hook(hook, &socket).await?;
+    }
+    let started = Instant::now();
+    match socket.connect(target).await {
+        Ok(stream) => Ok((stream, started.elapsed())),
+        Err(e) => match local {
+            // The port is only claimed for good at connect time
+            Some(local) if e.kind() == io::ErrorKind::AddrInUse => Err(util::bind_error(local, e)),
+            _ => Err(e),
+        },
     }
+}
+
+/// Run `cmd` through `sh -c` with `socket` inherited as fd [`SOCKET_FD_ENV`],
+/// waiting for it and failing if it does not exit successfully.
+///
+/// The hook's stdout is discarded so it cannot leak into the session output.
+#[cfg(unix)]
+async fn run_socket_hook(cmd: &str, socket: &TcpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
 
+    let fd = socket.as_raw_fd();
+    let mut command = tokio::process::Command::new("sh");
+    command
+        .arg("-c")
+        .arg(cmd)
+        .env(SOCKET_FD_ENV, fd.to_string())
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null());
+    // SAFETY: only async-signal-safe fcntl calls run between fork and exec.
+    // Clearing FD_CLOEXEC there affects the child's copy of the fd alone.
+    unsafe {
+        command.pre_exec(move || {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let status = command.status().await?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "socket hook '{}' failed: {}",
+            cmd, status
+        )));
+    }
     Ok(())
 }
 
-pub async fn listen(port: u16, timeout: Option<Duration>, verbose: bool) -> anyhow::Result<()> {
-    let bind_addr = format!("0.0.0.0:{}", port);
+#[cfg(not(unix))]
+async fn run_socket_hook(_cmd: &str, _socket: &TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--socket-hook needs Unix fd inheritance",
+    ))
+}
+
+/// Accept a connection on `port` and shuttle the input <-> socket.
+///
+/// The listener binds the wildcard address of `config.family` (see
+/// `bind_listener`) and announces itself per `config.ready`. With
+/// `config.keep_open` it goes back to accepting after each connection: the
+/// input can only be read once, so it is wired to the first connection alone
+/// and later ones are only copied to the output, and a failed session is
+/// logged rather than ending the listener. `config.chat`,
+/// `config.echo_server` and `config.exec` serve connections with [`chat`],
+/// an echo or a command instead.
+pub async fn listen(port: u16, config: &Config) -> anyhow::Result<SessionStats> {
+    let verbose = config.verbose();
+    let mut upload = match &config.upload {
+        Some(u) => Some(open_upload(u).await?),
+        None => None,
+    };
+    let mut input = Some(config.input.open().await?);
+    let output = config.output.open().await?;
+
+    let bind_addr = SocketAddr::new(config.family.wildcard(), port);
     if verbose {
-        eprintln!("Listening on {}", bind_addr);
+        diag!("Listening on {}", bind_addr);
+    }
+    let listener = bind_listener(bind_addr, config.reuseport)?;
+    if let Some(signal) = config.ready {
+        announce_ready(signal, listener.local_addr()?)?;
+    }
+    if config.chat {
+        return chat::serve(listener, config).await;
     }
-    let listener = TcpListener::bind(bind_addr).await?;
-    let accept_fut = listener.accept();
 
-    let (stream, peer) = if let Some(dur) = timeout {
-        match time::timeout(dur, accept_fut).await {
-            Ok(Ok((s, p))) => (s, p),
-            Ok(Err(e)) => return Err(anyhow::anyhow!("accept failed: {}", e)),
-            Err(_) => return Err(anyhow::anyhow!("accept timed out after {:?}", dur)),
+    let mut first = true;
+    let mut served = 0;
+    let mut gate = config.accept_rate.map(|rate| Bucket::new(rate.into()));
+    loop {
+        let accepting = async {
+            // Waiting for a token is not part of the -w accept timeout
+            if let Some(gate) = &mut gate {
+                gate.acquire().await;
+            }
+            let accept_fut = listener.accept();
+            if let Some(dur) = config.timeout {
+                match time::timeout(dur, accept_fut).await {
+                    Ok(Ok(accepted)) => Ok(accepted),
+                    Ok(Err(e)) => Err(anyhow::anyhow!("accept failed: {}", e)),
+                    Err(_) => Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("accept timed out after {:?}", dur),
+                    )
+                    .into()),
+                }
+            } else {
+                Ok(accept_fut.await?)
+            }
+        };
+        let (stream, peer) = tokio::select! {
+            accepted = accepting => accepted?,
+            _ = cancelled(config.cancel.as_ref()) => return Ok(SessionStats::default()),
+        };
+
+        if verbose {
+            diag!("Accepted connection from {}", peer);
+        }
+        set_stream_options(&stream, config)?;
+
+        if let Some(cmd) = &config.on_connect {
+            spawn_hook(cmd, peer);
+        }
+        let pacing = match (config.idle_banner.clone(), config.interval) {
+            _ if !first => Pacing::Silent,
+            (Some((banner, after)), _) => Pacing::IdleBanner(banner, after),
+            (None, Some(period)) => Pacing::Interval(period),
+            (None, None) => Pacing::Stream,
+        };
+        first = false;
+        let res = match &config.exec {
+            #[cfg(feature = "exec")]
+            Some(cmd) => exec::exec_pipe(stream, cmd, verbose).await,
+            _ if config.echo_server => echo(stream, config).await,
+            _ => {
+                let local = Local {
+                    input: input.take(),
+                    output: output.writer()?,
+                };
+                shuttle(
+                    stream,
+                    config,
+                    local,
+                    upload.take(),
+                    pacing,
+                    config.reply_delay,
+                    "Connection",
+                )
+                .await
+            }
+        };
+        if let Some(cmd) = &config.on_disconnect {
+            spawn_hook(cmd, peer);
+        }
+        let stopped = config
+            .cancel
+            .as_ref()
+            .is_some_and(CancelToken::is_cancelled);
+        served += 1;
+        let all_served = config.max_conns.is_some_and(|max| served >= max);
+        if all_served && verbose {
+            diag!("Served {} connections, no longer listening", served);
         }
+        if !config.keep_open || stopped || all_served {
+            return res;
+        }
+        if let Err(e) = res {
+            diag!("Connection from {} failed: {}", peer, e);
+        }
+    }
+}
+
+/// Apply the options of `config` that concern a connected socket: `-D`,
+/// `--keepalive*` and `--dump-sockopts`.
+pub(crate) fn set_stream_options(stream: &TcpStream, config: &Config) -> anyhow::Result<()> {
+    if config.nodelay {
+        stream.set_nodelay(true)?;
+    }
+    if let Some(keepalive) = &config.keepalive {
+        util::set_keepalive(stream, keepalive)
+            .map_err(|e| anyhow::anyhow!("--keepalive: {}", e))?;
+    }
+    if config.dump_sockopts {
+        util::log_sockopts(stream);
+    }
+    Ok(())
+}
+
+/// Bind a listener on `addr` with `SO_REUSEADDR`, so a port left in
+/// `TIME_WAIT` by an earlier run can be reused at once, and `SO_REUSEPORT`
+/// when `reuseport` is set.
+fn bind_listener(addr: SocketAddr, reuseport: bool) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
     } else {
-        accept_fut.await?
+        TcpSocket::new_v6()?
     };
+    socket.set_reuseaddr(true)?;
+    if reuseport {
+        #[cfg(unix)]
+        socket.set_reuseport(true)?;
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--reuseport is only supported on Unix",
+        ));
+    }
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
 
-    if verbose {
-        eprintln!("Accepted connection from {}", peer);
+/// Send everything read from `stream` back on it until the client closes
+/// (or is silent for `-w`), waiting `config.reply_delay` before each chunk.
+async fn echo(mut stream: TcpStream, config: &Config) -> anyhow::Result<SessionStats> {
+    let started = Instant::now();
+    let (mut reader, mut writer) = stream.split();
+    let mut total = 0u64;
+    let copy = async {
+        let mut buf = config.buffers.get(config.recv_len());
+        loop {
+            let n = match config.timeout {
+                Some(dur) => time::timeout(dur, reader.read(&mut buf)).await??,
+                None => reader.read(&mut buf).await?,
+            };
+            if n == 0 {
+                return io::Result::Ok(());
+            }
+            if let Some(delay) = config.reply_delay {
+                time::sleep(delay).await;
+            }
+            writer.write_all(&buf[..n]).await?;
+            total += n as u64;
+        }
+    };
+    let res = tokio::select! {
+        res = copy => res,
+        _ = cancelled(config.cancel.as_ref()) => Ok(()),
+    };
+    match res {
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+            if config.verbose() {
+                diag!(
+                    "Connection idle for {:?}, closing",
+                    config.timeout.unwrap_or_default()
+                );
+            }
+        }
+        res => {
+            res?;
+            let _ = writer.shutdown().await;
+            if config.verbose() {
+                diag!("Echoed {} bytes", total);
+            }
+        }
     }
+    Ok(SessionStats {
+        bytes_sent: total,
+        bytes_received: total,
+        duration: started.elapsed(),
+    })
+}
 
-    // shuttle IO same as client
-    let (mut reader, mut writer) = stream.into_split();
-    let mut stdin = io::stdin();
-    let mut stdout = io::stdout();
+/// Start `cmd` through `sh -c` with the peer address in [`PEER_ENV`].
+///
+/// The hook is not waited for, and failing to start it only warns.
+fn spawn_hook(cmd: &str, peer: SocketAddr) {
+    let spawned = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env(PEER_ENV, peer.to_string())
+        .stdin(std::process::Stdio::null())
+        .spawn();
+    if let Err(e) = spawned {
+        diag!("hook '{}' failed to start: {}", cmd, e);
+    }
+}
 
-    let write_task = tokio::spawn(async move {
-        let res = io::copy(&mut stdin, &mut writer).await;
-        let _ = writer.shutdown().await;
-        res
-    });
+/// The taps every stream session gets from `config`: `counters`, then
+/// `--annotate`, the `-vv` chunk sizes, `--log-file` and the
+/// `--tee-sent-fd`/`--tee-recv-fd` copies. `--pcap` needs the connection's
+/// addresses, so it is left to the caller.
+pub(crate) fn session_tee(config: &Config, counters: Arc<Counters>) -> Tee {
+    let mut tee = Tee::default();
+    tee.add(counters);
+    if config.annotate {
+        tee.add(Arc::new(Annotate));
+    }
+    if config.verbosity >= 2 {
+        tee.add(Arc::new(ChunkSizes));
+    }
+    if let Some(log) = &config.log_file {
+        tee.add(log.clone());
+    }
+    #[cfg(unix)]
+    for (dir, fd) in [
+        (Direction::Sent, config.tee_sent_fd),
+        (Direction::Received, config.tee_recv_fd),
+    ] {
+        if let Some(fd) = fd {
+            tee.add(Arc::new(FdTap::new(dir, fd)));
+        }
+    }
+    tee
+}
 
-    let read_task = tokio::spawn(async move {
-        let res = io::copy(&mut reader, &mut stdout).await;
-        let _ = stdout.flush().await;
-        res
-    });
+/// Shuttle input <-> socket until the peer closes its side, or has sent
+/// nothing for `-w` (the idle timeout resets with every received chunk).
+///
+/// Once our input ends, our side is shut down with a FIN while the peer's
+/// data is still read. Which directions run, how the session outlasts either
+/// EOF and what is done to the data all follow `config`. Nothing is sent
+/// before `reply_delay` has passed. `what` names the session in verbose
+/// messages ("Session"/"Connection").
+async fn shuttle(
+    stream: TcpStream,
+    config: &Config,
+    local: Local,
+    upload: Option<OpenUpload>,
+    pacing: Pacing,
+    reply_delay: Option<Duration>,
+    what: &str,
+) -> anyhow::Result<SessionStats> {
+    let verbose = config.verbose();
+    let started = Instant::now();
+    let peer = stream.peer_addr().ok();
+    let counters = Arc::new(Counters::default());
+    let mut tee = session_tee(config, counters.clone());
+    if let (Some(pcap), Some(peer)) = (&config.pcap, peer) {
+        tee.add(Arc::new(PcapTap::new(
+            pcap.clone(),
+            stream.local_addr()?,
+            peer,
+        )));
+    }
+    if config.verbosity >= 2
+        && let (Ok(local), Some(peer)) = (stream.local_addr(), peer)
+    {
+        diag!("{} between {} and {}", what, local, peer);
+    }
 
-    if let Some(dur) = timeout {
-        match time::timeout(dur, async {
-            let _ = tokio::join!(write_task, read_task);
-        })
-        .await
-        {
-            Ok(_) => {
-                if verbose {
-                    eprintln!("Connection finished");
+    // sendfile(2) bypasses userspace, so only use it if nothing needs the
+    // bytes, has to meter them or has telnet replies to slip in
+    let send_filters = config.send_filters();
+    let wants_zero_copy = upload.as_ref().is_some_and(|up| up.zero_copy);
+    let zero_copy = wants_zero_copy
+        && cfg!(target_os = "linux")
+        && send_filters.is_empty()
+        && !tee.needs_data()
+        && config.rate.is_none()
+        && !config.telnet;
+    if verbose && wants_zero_copy && !zero_copy {
+        diag!("sendfile: not usable here, copying through userspace instead");
+    }
+
+    // Split so we can read and write concurrently; the tee sits below the
+    // filters so taps see the bytes actually put on the wire, and above the
+    // `--rate` throttle so they see them when they go
+    let (mut reader, writer) = stream.into_split();
+    let writer = Throttle::new(writer, config.rate);
+    let mut writer = FilterWriter::new(TeeWriter::new(writer, tee.clone()), send_filters);
+    let mut source = local.input.unwrap_or_else(|| Box::new(io::empty()));
+    let mut stdout = FilterWriter::new(
+        TimedFlush::new(local.output, config.flush_interval),
+        config.recv_filters(),
+    );
+    // The marker follows *our* end of input, which a silent session never had
+    let eof_marker = match pacing {
+        Pacing::Silent => None,
+        _ => config.eof_marker.clone(),
+    };
+    // The guess is only ever reported under -v, so skip the peek otherwise
+    let detect_protocol = config.detect_protocol && verbose;
+
+    let headers_only = config.headers_only;
+    let mut telnet = config.telnet.then(Telnet::new);
+    // The read side finds what to answer, the write side sends it
+    let (reply_tx, reply_rx) = mpsc::unbounded_channel();
+    let replies = config.telnet.then_some(reply_rx);
+    let half_close = config.half_close;
+    let no_shutdown = config.no_shutdown;
+    let idle_timeout = config.timeout;
+    let (send_len, recv_len) = (config.send_len(), config.recv_len());
+    let quit_after = config.quit_after;
+    let cancel = config.cancel.clone();
+    let trace = config.verbosity >= 3;
+    let mut message_limit = config
+        .max_message
+        .clone()
+        .map(|(max, delimiter)| MessageLimit::new(max, delimiter));
+
+    // Inbound activity signal, consulted by the idle banner and turn timers
+    let activity = Arc::new(Notify::new());
+    // Raised by the read side when it ends the session early (peer EOF,
+    // `--headers-only`, `--max-message-size`, `config.cancel`)
+    let stop = Arc::new(Notify::new());
+    // Raised by the write side once all input is sent, starting `-q`'s clock
+    let input_done = Arc::new(Notify::new());
+    // Raised by the write side when reading the input fails, which ends the
+    // session
+    let input_failed = Arc::new(Notify::new());
+
+    // stdin (or the upload file) -> socket. Under --recv-only there is no
+    // such task, and the write half stays open, unused, until the end
+    let write_activity = activity.clone();
+    let write_stop = stop.clone();
+    let write_counters = counters.clone();
+    let write_done = input_done.clone();
+    let write_failed = input_failed.clone();
+    let write_cancel = config.cancel.clone();
+    let write_task = match (config.recv_only, replies) {
+        // Nothing to send but the telnet replies
+        (true, Some(replies)) => tokio::spawn(async move {
+            ReplyWriter::new(&mut writer, Some(replies))
+                .pump()
+                .await
+                .map(|()| 0)
+        }),
+        (true, None) => tokio::spawn(async { Ok(0) }),
+        (false, replies) => tokio::spawn(async move {
+            let copy = async {
+                let (upload, pacing) = match (upload, pacing) {
+                    (Some(up), _) if zero_copy => {
+                        if let Some(delay) = reply_delay {
+                            time::sleep(delay).await;
+                        }
+                        let stream = writer.get_mut().get_ref().get_ref().as_ref();
+                        return send_zero_copy(&up, stream, &write_counters).await;
+                    }
+                    paced => paced,
+                };
+                let mut writer = ReplyWriter::new(&mut writer, replies);
+                let pump = writer.pump();
+                let paced = async {
+                    if let Some(delay) = reply_delay {
+                        time::sleep(delay).await;
+                    }
+                    match (upload, pacing) {
+                        (Some(mut up), _) => {
+                            copy_with_progress(
+                                &mut up.file,
+                                &mut writer,
+                                up.offset,
+                                up.len,
+                                send_len,
+                            )
+                            .await
+                        }
+                        (None, Pacing::IdleBanner(banner, after)) => {
+                            let idle = (after, &*write_activity);
+                            copy_with_idle_banner(&mut source, &mut writer, &banner, idle, send_len)
+                                .await
+                        }
+                        (None, Pacing::Turns(idle)) => {
+                            copy_in_turns(&mut source, &mut writer, idle, &write_activity).await
+                        }
+                        (None, Pacing::Interval(period)) => {
+                            copy_lines_spaced(&mut source, &mut writer, period).await
+                        }
+                        (None, Pacing::Stream) => {
+                            let mut input = BufReader::with_capacity(send_len, &mut source);
+                            io::copy_buf(&mut input, &mut writer).await
+                        }
+                        (None, Pacing::Silent) => Ok(0),
+                    }
+                };
+                // The pump is only done early if a reply could not be sent
+                tokio::select! {
+                    res = paced => res,
+                    Err(e) = pump => Err(e),
                 }
+            };
+            // Only a copy that ran to the end of the input has reached EOF
+            let (mut res, at_eof) = tokio::select! {
+                res = copy => (res, true),
+                _ = write_stop.notified() => (Ok(0), false),
+                // Also seen here for --send-only, which has no read side to
+                // pass it on
+                _ = cancelled(write_cancel.as_ref()) => (Ok(0), false),
+            };
+            if matches!(&res, Err(e) if InputError::caused(e)) {
+                write_failed.notify_one();
+            }
+            // Release bytes the filters held back, then the raw marker
+            if res.is_ok()
+                && let Err(e) = writer.end().await
+            {
+                res = Err(e);
             }
-            Err(_) => {
-                if verbose {
-                    eprintln!("Connection timed out after {:?}", dur);
+            if let (Ok(&n), Some(marker), true) = (res.as_ref(), eof_marker.as_deref(), at_eof) {
+                res = writer
+                    .get_mut()
+                    .write_all(marker)
+                    .await
+                    .map(|_| n + marker.len() as u64);
+            }
+            if no_shutdown {
+                if trace {
+                    diag!("Input done, leaving the write half open");
+                }
+                // Dropping the half would send the FIN all the same
+                writer.into_inner().into_inner().into_inner().forget();
+            } else {
+                if trace {
+                    diag!("Input done, shutting down the write half");
                 }
+                // attempt to shutdown the write half gracefully
+                let _ = writer.get_mut().shutdown().await;
             }
-        }
+            write_done.notify_one();
+            res
+        }),
+    };
+
+    // socket -> stdout, unless --send-only
+    let read_task = if config.send_only {
+        tokio::spawn(async { Ok(0) })
     } else {
-        let _ = tokio::join!(write_task, read_task);
+        let mut buf = config.buffers.get(recv_len);
+        tokio::spawn(async move {
+            let mut total = 0u64;
+            if detect_protocol {
+                // peek leaves the bytes queued, so the loop below still sees them
+                match reader.peek(&mut buf).await {
+                    Ok(0) | Err(_) => {}
+                    Ok(n) => match detect::classify(&buf[..n]) {
+                        Some(proto) => diag!("Detected protocol: {}", proto),
+                        None => diag!("Protocol not recognised"),
+                    },
+                }
+            }
+            let mut header_state = 0;
+            let mut telnet_out = Vec::new();
+            let mut last_received = Instant::now();
+            let mut quit_due = None;
+            let res = loop {
+                // With --flush-interval, buffered output is due even if the peer
+                // goes quiet
+                let flush_due = stdout.get_mut().deadline();
+                let idle_due = idle_timeout.map(|dur| last_received + dur);
+                let read = tokio::select! {
+                    read = reader.read(&mut buf) => read,
+                    _ = sleep_until(flush_due) => {
+                        if let Err(e) = stdout.flush().await {
+                            // Nowhere to deliver the data, so end the session
+                            stop.notify_one();
+                            break Err(e);
+                        }
+                        continue;
+                    }
+                    _ = sleep_until(idle_due) => {
+                        if trace {
+                            diag!("Idle timer expired after {:?}", idle_timeout.unwrap_or_default());
+                        }
+                        // -w: the peer has been silent too long, end both directions
+                        stop.notify_one();
+                        break Err(io::ErrorKind::TimedOut.into());
+                    }
+                    _ = input_done.notified(), if quit_due.is_none() => {
+                        quit_due = quit_after.map(|dur| Instant::now() + dur);
+                        if let (true, Some(dur)) = (trace, quit_after) {
+                            diag!("Quitting in {:?} unless the peer closes first", dur);
+                        }
+                        continue;
+                    }
+                    // -q: our input is done and the grace period for replies is over
+                    _ = sleep_until(quit_due) => {
+                        if trace {
+                            diag!("Quit timer expired");
+                        }
+                        break Ok(total);
+                    }
+                    _ = input_failed.notified() => break Ok(total),
+                    _ = cancelled(cancel.as_ref()) => {
+                        stop.notify_one();
+                        break Ok(total);
+                    }
+                };
+                match read {
+                    Ok(0) => {
+                        if trace {
+                            diag!("Peer closed its write half");
+                        }
+                        // The peer is done, so stop sending too unless told to
+                        // carry on into a half-closed connection
+                        if !half_close {
+                            stop.notify_one();
+                        }
+                        break Ok(total);
+                    }
+                    Ok(n) => {
+                        last_received = Instant::now();
+                        activity.notify_one();
+                        tee.record(Direction::Received, &buf[..n]);
+                        if let Some(limit) = &mut message_limit
+                            && let Some(over) = limit.feed(&buf[..n])
+                        {
+                            // Deliver what fit, then hang up
+                            let _ = stdout.write_all(&buf[..over]).await;
+                            let reason = format!(
+                                "peer sent more than {} bytes without a message delimiter",
+                                limit.max
+                            );
+                            diag!("Closing connection: {}", reason);
+                            stop.notify_one();
+                            break Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+                        }
+                        let end = if headers_only {
+                            header_end(&mut header_state, &buf[..n])
+                        } else {
+                            None
+                        };
+                        let mut shown = &buf[..end.unwrap_or(n)];
+                        if let Some(telnet) = &mut telnet {
+                            telnet_out.clear();
+                            let mut reply = Vec::new();
+                            telnet.filter(shown, &mut telnet_out, &mut reply);
+                            // Once our side is shut down there is no
+                            // answering, which is no reason to stop reading
+                            if !reply.is_empty() {
+                                let _ = reply_tx.send(reply);
+                            }
+                            shown = &telnet_out;
+                        }
+                        // Unless --flush-interval holds it back, flush straight away: a
+                        // file or stdout only reports a failed write on the next call
+                        let written = match stdout.write_all(shown).await {
+                            Ok(()) if stdout.get_mut().deadline().is_none() => stdout.flush().await,
+                            written => written,
+                        };
+                        if let Err(e) = written {
+                            // Nowhere to deliver the data, so end the session
+                            stop.notify_one();
+                            break Err(e);
+                        }
+                        total += n as u64;
+                        if end.is_some() {
+                            // Leave the body unread and stop sending as well
+                            stop.notify_one();
+                            break Ok(total);
+                        }
+                    }
+                    Err(e) => break Err(e),
+                }
+            };
+            // A file output only has everything once flushed
+            match (stdout.end().await, res) {
+                (Err(e), Ok(_)) => Err(e),
+                (_, res) => res,
+            }
+        })
+    };
+
+    let joined = join_tasks(write_task, read_task, verbose).await;
+    let exit = match &joined {
+        Ok((_, Err(e))) if e.kind() == io::ErrorKind::TimedOut => {
+            if verbose {
+                diag!(
+                    "{} idle for {:?}, closing",
+                    what,
+                    config.timeout.unwrap_or_default()
+                );
+            }
+            "timeout".to_string()
+        }
+        Ok((Err(e), _) | (_, Err(e))) => format!("error: {}", e),
+        Ok(_) => {
+            if verbose {
+                diag!("{} finished", what);
+            }
+            "completed".to_string()
+        }
+        Err(e) => format!("error: {}", e),
+    };
+
+    let stats = SessionStats {
+        bytes_sent: counters.sent(),
+        bytes_received: counters.received(),
+        duration: started.elapsed(),
+    };
+    if let Some(path) = &config.stats_file {
+        let record = SessionRecord {
+            peer,
+            bytes_sent: stats.bytes_sent,
+            bytes_received: stats.bytes_received,
+            duration: stats.duration,
+            exit,
+        };
+        stats::append_record(path, &record)
+            .map_err(|e| anyhow::anyhow!("cannot write stats file '{}': {}", path.display(), e))?;
     }
 
-    Ok(())
+    match joined? {
+        // A broken input is the caller's problem, unlike the peer going away
+        (Err(e), _) if InputError::caused(&e) => Err(e.into()),
+        // The read side's only InvalidData is a --max-message-size overrun,
+        // which fails the session
+        (_, Err(e)) if e.kind() == io::ErrorKind::InvalidData => Err(e.into()),
+        (_, Err(e)) if OutputError::caused(&e) => Err(e.into()),
+        _ => Ok(stats),
+    }
+}
+
+/// Enforces `--max-message-size`: counts the bytes of the current message
+/// (those since the last delimiter) across chunk boundaries.
+struct MessageLimit {
+    max: u64,
+    delimiter: Vec<u8>,
+    /// KMP fallback table for `delimiter`.
+    fallback: Vec<usize>,
+    /// Bytes since the end of the last delimiter, including a partial match.
+    run: u64,
+    /// Length of the delimiter prefix matched so far.
+    matched: usize,
+}
+
+impl MessageLimit {
+    fn new(max: u64, delimiter: Vec<u8>) -> Self {
+        let mut fallback = vec![0; delimiter.len()];
+        let mut k = 0;
+        for i in 1..delimiter.len() {
+            while k > 0 && delimiter[i] != delimiter[k] {
+                k = fallback[k - 1];
+            }
+            if delimiter[i] == delimiter[k] {
+                k += 1;
+            }
+            fallback[i] = k;
+        }
+        MessageLimit {
+            max,
+            delimiter,
+            fallback,
+            run: 0,
+            matched: 0,
+        }
+    }
+
+    /// Account for `chunk`. Once a message grows past the limit, returns the
+    /// offset of the first byte beyond it.
+    fn feed(&mut self, chunk: &[u8]) -> Option<usize> {
+        for (i, &b) in chunk.iter().enumerate() {
+            while self.matched > 0 && b != self.delimiter[self.matched] {
+                self.matched = self.fallback[self.matched - 1];
+            }
+            if b == self.delimiter[self.matched] {
+                self.matched += 1;
+            }
+            self.run += 1;
+            if self.matched == self.delimiter.len() {
+                self.run = 0;
+                self.matched = 0;
+            } else if self.run - self.matched as u64 > self.max {
+                // Bytes in a partial match may still turn out to be the delimiter
+                return Some(i);
+            }
+        }
+        None
+    }
+}
+
+/// Find the end of an HTTP header block (the first `\r\n\r\n`) in a stream
+/// fed chunk by chunk.
+///
+/// `matched` carries how much of the terminator ended the previous chunk, so
+/// a split terminator is still found. Returns the offset just past it.
+fn header_end(matched: &mut usize, chunk: &[u8]) -> Option<usize> {
+    const END: &[u8] = b"\r\n\r\n";
+    for (i, &b) in chunk.iter().enumerate() {
+        *matched = if b == END[*matched] {
+            *matched + 1
+        } else {
+            usize::from(b == b'\r')
+        };
+        if *matched == END.len() {
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+/// Copy `input` to `writer` in reads of up to `chunk` bytes, sending `banner`
+/// each time `after` passes without a wake-up on `activity`.
+async fn copy_with_idle_banner<R, W>(
+    input: &mut R,
+    writer: &mut W,
+    banner: &[u8],
+    (after, activity): (Duration, &Notify),
+    chunk: usize,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; chunk];
+    let mut total = 0u64;
+    let idle = time::sleep(after);
+    tokio::pin!(idle);
+
+    loop {
+        tokio::select! {
+            res = input.read(&mut buf) => {
+                let n = res?;
+                if n == 0 {
+                    return Ok(total);
+                }
+                writer.write_all(&buf[..n]).await?;
+                total += n as u64;
+            }
+            _ = activity.notified() => idle.as_mut().reset(Instant::now() + after),
+            _ = &mut idle => {
+                writer.write_all(banner).await?;
+                idle.as_mut().reset(Instant::now() + after);
+            }
+        }
+    }
+}
+
+/// Copy `input` to `writer` line by line, waiting after each line until
+/// `idle` passes without a wake-up on `activity` (the reply is complete).
+async fn copy_in_turns<R, W>(
+    input: &mut R,
+    writer: &mut W,
+    idle: Duration,
+    activity: &Notify,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut input = BufReader::new(input);
+    let mut line = Vec::new();
+    let mut total = 0u64;
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(total);
+        }
+        writer.write_all(&line).await?;
+        writer.flush().await?;
+        total += line.len() as u64;
+
+        let quiet = time::sleep(idle);
+        tokio::pin!(quiet);
+        loop {
+            tokio::select! {
+                _ = activity.notified() => quiet.as_mut().reset(Instant::now() + idle),
+                _ = &mut quiet => break,
+            }
+        }
+    }
+}
+
+/// Copy `input` to `writer` line by line, sleeping `period` between lines.
+/// A last line without a newline is sent as it is.
+async fn copy_lines_spaced<R, W>(input: &mut R, writer: &mut W, period: Duration) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut input = BufReader::new(input);
+    let mut line = Vec::new();
+    let mut total = 0u64;
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(total);
+        }
+        if total > 0 {
+            time::sleep(period).await;
+        }
+        writer.write_all(&line).await?;
+        writer.flush().await?;
+        total += line.len() as u64;
+    }
+}
+
+/// Open an upload file positioned at its resume offset.
+async fn open_upload(upload: &Upload) -> anyhow::Result<OpenUpload> {
+    let path = upload.path.display();
+    let mut file = File::open(&upload.path)
+        .await
+        .map_err(|e| anyhow::anyhow!("cannot open upload file '{}': {}", path, e))?;
+    let len = file.metadata().await?.len();
+    if upload.resume_from > len {
+        anyhow::bail!(
+            "resume offset {} is past the end of '{}' ({} bytes)",
+            upload.resume_from,
+            path,
+            len
+        );
+    }
+    file.seek(SeekFrom::Start(upload.resume_from)).await?;
+    Ok(OpenUpload {
+        file,
+        offset: upload.resume_from,
+        len,
+        zero_copy: upload.zero_copy,
+    })
+}
+
+/// Send the rest of an upload with `sendfile(2)`, then report the throughput.
+async fn send_zero_copy(
+    up: &OpenUpload,
+    stream: &TcpStream,
+    counters: &Counters,
+) -> io::Result<u64> {
+    let started = Instant::now();
+    let sent = util::sendfile(stream, &up.file, up.offset, up.len - up.offset).await?;
+    counters.add_sent(sent);
+    let secs = started.elapsed().as_secs_f64();
+    diag!(
+        "sendfile: {} bytes in {:.2}s ({:.1} MiB/s)",
+        sent,
+        secs,
+        sent as f64 / secs.max(1e-6) / (1024.0 * 1024.0)
+    );
+    Ok(sent)
+}
+
+/// Copy `input` to `writer` in reads of up to `chunk` bytes, reporting
+/// `offset + sent` out of `len` bytes on stderr at most once per
+/// [`PROGRESS_EVERY`] and once more when done.
+async fn copy_with_progress<R, W>(
+    input: &mut R,
+    writer: &mut W,
+    offset: u64,
+    len: u64,
+    chunk: usize,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let report = |sent: u64| {
+        let done = offset + sent;
+        let pct = (done * 100).checked_div(len).unwrap_or(100);
+        diag!("upload: {}/{} bytes ({}%)", done, len, pct);
+    };
+
+    let mut buf = vec![0u8; chunk];
+    let mut sent = 0u64;
+    let mut last_report = Instant::now();
+    loop {
+        let n = input.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buf[..n]).await?;
+        sent += n as u64;
+        if last_report.elapsed() >= PROGRESS_EVERY {
+            report(sent);
+            last_report = Instant::now();
+        }
+    }
+    report(sent);
+    Ok(sent)
 }
@@ -0,0 +1,205 @@
+/*!
+r-cat/src/net/telnet.rs
+
+Telnet option negotiation for `-t`.
+
+A telnet server opens by offering and requesting options (`IAC WILL ECHO`,
+`IAC DO TERMINAL-TYPE`, ...) and waits for answers before it gets going.
+[`Telnet`] refuses every one, answering `DO` with `WONT` and `WILL` with
+`DONT` as RFC 854 allows, so the session stays a plain NVT byte stream. The
+peer's own refusals (`WONT`, `DONT`) are already our state and get no
+answer, which keeps two refusing ends from looping.
+
+All commands are kept out of the received output: negotiations, two-byte
+commands such as `NOP` or `GA`, and whole `SB ... SE` subnegotiations. An
+escaped `IAC IAC` comes out as a single 0xFF data byte. Commands may be split
+across reads; the parser picks up where the last chunk left off.
+
+The refusals are found on the read side but belong to the write side, so
+they travel over a channel to a [`ReplyWriter`] the write task copies
+through. Each goes out whole, after the data already handed over and
+through the same throttle and tee, including while the input is quiet.
+*/
+
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, ready};
+
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::filter::FilterWriter;
+
+/// Interpret As Command: starts every telnet command.
+pub const IAC: u8 = 255;
+pub const DONT: u8 = 254;
+pub const DO: u8 = 253;
+pub const WONT: u8 = 252;
+pub const WILL: u8 = 251;
+/// Subnegotiation begin and end.
+pub const SB: u8 = 250;
+pub const SE: u8 = 240;
+
+/// Where the parser is within the received stream.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Data,
+    /// Just after an `IAC`.
+    Command,
+    /// After `IAC` and a negotiation verb, waiting for the option.
+    Option(u8),
+    /// Inside `IAC SB`, skipping until `IAC SE`.
+    Sub,
+    /// An `IAC` inside a subnegotiation.
+    SubCommand,
+}
+
+/// Stateful splitter of received data into output bytes and the refusals to
+/// send back.
+#[derive(Debug, Default)]
+pub struct Telnet {
+    state: State,
+}
+
+impl Telnet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append the data bytes of `buf` to `out` and the answers its
+    /// negotiations call for to `reply`.
+    pub fn filter(&mut self, buf: &[u8], out: &mut Vec<u8>, reply: &mut Vec<u8>) {
+        for &b in buf {
+            self.state = match (self.state, b) {
+                (State::Data, IAC) => State::Command,
+                (State::Data, _) => {
+                    out.push(b);
+                    State::Data
+                }
+                (State::Command, IAC) => {
+                    out.push(IAC);
+                    State::Data
+                }
+                (State::Command, WILL | WONT | DO | DONT) => State::Option(b),
+                (State::Command, SB) => State::Sub,
+                // Any other command carries no option and needs no answer
+                (State::Command, _) => State::Data,
+                (State::Option(verb), option) => {
+                    match verb {
+                        DO => reply.extend_from_slice(&[IAC, WONT, option]),
+                        WILL => reply.extend_from_slice(&[IAC, DONT, option]),
+                        _ => {}
+                    }
+                    State::Data
+                }
+                (State::Sub, IAC) => State::SubCommand,
+                (State::Sub, _) => State::Sub,
+                (State::SubCommand, SE) => State::Data,
+                (State::SubCommand, _) => State::Sub,
+            };
+        }
+    }
+}
+
+/// The session's writer with the replies of the read side's [`Telnet`]
+/// merged in. Data goes through the send filters; replies skip them, as they
+/// are commands rather than input.
+pub struct ReplyWriter<'a, W> {
+    shared: Arc<Mutex<Shared<'a, W>>>,
+}
+
+struct Shared<'a, W> {
+    writer: &'a mut FilterWriter<W>,
+    /// Closed once the read side is done, and absent without `-t`.
+    queue: Option<UnboundedReceiver<Vec<u8>>>,
+    /// What is left of the reply being written.
+    reply: Vec<u8>,
+}
+
+impl<'a, W: AsyncWrite + Unpin> ReplyWriter<'a, W> {
+    pub fn new(writer: &'a mut FilterWriter<W>, queue: Option<UnboundedReceiver<Vec<u8>>>) -> Self {
+        let shared = Shared {
+            writer,
+            queue,
+            reply: Vec::new(),
+        };
+        ReplyWriter {
+            shared: Arc::new(Mutex::new(shared)),
+        }
+    }
+
+    /// Write replies as they arrive, also while nothing is written through
+    /// `self`. Done once the queue closes, or when a reply cannot be sent.
+    pub fn pump(&self) -> impl Future<Output = io::Result<()>> + use<'a, W> {
+        let shared = self.shared.clone();
+        poll_fn(move |cx| {
+            let mut shared = shared.lock().unwrap_or_else(PoisonError::into_inner);
+            ready!(shared.poll_replies(cx))?;
+            match shared.queue {
+                Some(_) => Poll::Pending,
+                None => Poll::Ready(Ok(())),
+            }
+        })
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut Shared<'a, W>) -> T) -> T {
+        f(&mut self.shared.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Shared<'_, W> {
+    /// Send the queued replies; ready once the queue is empty for now.
+    fn poll_replies(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if !self.reply.is_empty() {
+                // Behind whatever the filters still hold of earlier data
+                ready!(Pin::new(&mut *self.writer).poll_flush(cx))?;
+                while !self.reply.is_empty() {
+                    let n = ready!(Pin::new(self.writer.get_mut()).poll_write(cx, &self.reply))?;
+                    if n == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                    }
+                    self.reply.drain(..n);
+                }
+            }
+            let Some(queue) = &mut self.queue else {
+                return Poll::Ready(Ok(()));
+            };
+            match queue.poll_recv(cx) {
+                Poll::Ready(Some(reply)) => self.reply = reply,
+                Poll::Ready(None) => self.queue = None,
+                Poll::Pending => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ReplyWriter<'_, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.with(|shared| {
+            ready!(shared.poll_replies(cx))?;
+            Pin::new(&mut *shared.writer).poll_write(cx, buf)
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with(|shared| {
+            ready!(shared.poll_replies(cx))?;
+            Pin::new(&mut *shared.writer).poll_flush(cx)
+        })
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with(|shared| {
+            ready!(shared.poll_replies(cx))?;
+            Pin::new(&mut *shared.writer).poll_shutdown(cx)
+        })
+    }
+}
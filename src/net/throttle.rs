@@ -0,0 +1,135 @@
+/*!
+r-cat/src/net/throttle.rs
+
+Outbound rate limiting for `--rate`, and the accept gate of `--accept-rate`.
+
+[`Throttle`] meters writes through a token bucket: tokens accrue at the rate
+in bytes per second up to a small burst allowance, every byte written spends
+one, and a write that finds too few tokens sleeps until enough have accrued.
+Writes are cut down to the burst size, so a slow rate sends small chunks
+often rather than large ones in bursts. Listeners meter accepts through the
+same bucket, one token per connection.
+*/
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use tokio::io::AsyncWrite;
+use tokio::time::{self, Instant, Sleep};
+
+/// How much sending time the bucket holds when full.
+const BURST: Duration = Duration::from_millis(100);
+
+/// An `AsyncWrite` that sends at most `rate` bytes per second, or passes
+/// writes straight through when no rate is set.
+#[derive(Debug)]
+pub struct Throttle<W> {
+    inner: W,
+    bucket: Option<Bucket>,
+}
+
+impl<W> Throttle<W> {
+    pub fn new(inner: W, rate: Option<u64>) -> Self {
+        Throttle {
+            inner,
+            bucket: rate.map(Bucket::new),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// A token bucket holding [`BURST`] worth of its rate, and at least one token.
+#[derive(Debug)]
+pub(crate) struct Bucket {
+    /// Tokens (bytes) added per second.
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant,
+    /// Pending wait for tokens, kept across polls.
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Bucket {
+    pub(crate) fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        // Never less than one byte, or nothing could ever be sent
+        let capacity = (rate * BURST.as_secs_f64()).max(1.0);
+        Bucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            refilled: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + earned).min(self.capacity);
+        self.refilled = now;
+    }
+
+    /// Wait for one token and spend it.
+    pub(crate) async fn acquire(&mut self) {
+        std::future::poll_fn(|cx| self.poll_ready(cx, 1)).await;
+        self.tokens -= 1.0;
+    }
+
+    /// Wait until `len` bytes (at most a full bucket) may be sent, returning
+    /// how many that is.
+    fn poll_ready(&mut self, cx: &mut Context<'_>, len: usize) -> Poll<usize> {
+        let want = (len as f64).min(self.capacity).floor();
+        loop {
+            if let Some(sleep) = &mut self.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            self.refill();
+            if self.tokens >= want {
+                return Poll::Ready(want as usize);
+            }
+            let wait = Duration::from_secs_f64((want - self.tokens) / self.rate);
+            self.sleep = Some(Box::pin(time::sleep(wait)));
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Throttle<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(bucket) = &mut this.bucket else {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        };
+        if data.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+        let allowed = ready!(bucket.poll_ready(cx, data.len()));
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &data[..allowed]))?;
+        bucket.tokens -= n as f64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -7,180 +7,521 @@
 //! - `udp_listen` - bind to a local port, print incoming datagrams to stdout and allow sending
 //!   stdin data to the last peer that sent a datagram.
 
-use std::net::SocketAddr;
+use std::collections::HashSet;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
-use std::time::Duration;
 
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
-use tokio::time;
-
-pub async fn client(
-    host: &str,
-    port: u16,
-    timeout: Option<Duration>,
-    verbose: bool,
-) -> anyhow::Result<()> {
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{self, Instant};
+
+use super::{Config, Multicast, SessionStats, announce_ready, cancelled, join_tasks, util};
+use crate::diag;
+use crate::filter::FilterWriter;
+use crate::tee::{Direction, Tap};
+
+/// Send the input (`config.input`) as datagrams to `host:port` and print
+/// replies to `config.output`.
+///
+/// The socket is connected to the remote, so the kernel drops datagrams from
+/// any other source, unless `config.udp_loose`, `config.udp_strict_peer` or
+/// `config.broadcast` need it left unconnected. With `config.mtu_probe`, no
+/// data is exchanged: the path MTU towards the remote is discovered with
+/// don't-fragment probes and printed instead.
+pub async fn client(host: &str, port: u16, config: &Config) -> anyhow::Result<SessionStats> {
+    let started = Instant::now();
+    let (timeout, verbose) = (config.timeout, config.verbose());
+    let (send_len, recv_len) = (config.send_len(), config.recv_len());
+    let input = config.input.open().await?;
+    let output = config.output.open().await?.writer()?;
     let remote = format!("{}:{}", host, port);
-    let remote_addr: SocketAddr = remote
-        .parse()
-        .map_err(|e| anyhow::anyhow!("invalid remote address '{}': {}", remote, e))?;
+    let remote_addr = util::resolve_destination(&remote, config).await?[0];
 
-    // Choose a wildcard bind address that matches the remote's IP family.
-    let bind_addr = if remote_addr.is_ipv4() {
-        "0.0.0.0:0"
-    } else {
-        "[::]:0"
+    // Bind the requested source, or a wildcard matching the remote's IP family.
+    if let Some(source) = config.source
+        && source.is_ipv4() != remote_addr.is_ipv4()
+    {
+        anyhow::bail!(
+            "source address {} and destination {} are of different IP families",
+            source,
+            remote_addr
+        );
+    }
+    let socket = match util::local_bind(remote_addr, config.source, config.source_port) {
+        Some(local) => UdpSocket::bind(local)
+            .await
+            .map_err(|e| util::bind_error(local, e))?,
+        None if remote_addr.is_ipv4() => UdpSocket::bind("0.0.0.0:0").await?,
+        None => UdpSocket::bind("[::]:0").await?,
     };
+    let socket = Arc::new(socket);
+    socket.set_broadcast(config.broadcast)?;
+    if let Some(tos) = config.tos {
+        util::set_tos(&*socket, tos).map_err(|e| anyhow::anyhow!("IP_TOS: {}", e))?;
+    }
 
-    let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+    if config.dump_sockopts {
+        util::log_sockopts(&*socket);
+    }
+
+    if config.mtu_probe {
+        let mtu = util::probe_path_mtu(&socket, remote_addr, verbose).await?;
+        println!("path MTU to {}: {}", remote_addr, mtu);
+        return Ok(SessionStats::default());
+    }
+
+    // Replies to a broadcast come from the hosts answering it, never from
+    // the broadcast address, and --udp-strict-peer wants to see the others
+    // to report them
+    let connected = !(config.udp_loose || config.broadcast || config.udp_strict_peer);
+    if connected {
+        socket
+            .connect(remote_addr)
+            .await
+            .map_err(|e| anyhow::anyhow!("udp: cannot connect to {}: {}", remote_addr, e))?;
+    }
 
     if verbose {
-        eprintln!(
+        diag!(
             "udp: bound to {}, sending to {}",
             socket.local_addr()?,
             remote_addr
         );
     }
 
-    // Send task: read stdin and send datagrams to remote.
+    // Send task: read the input and send datagrams to remote.
     let send_socket = socket.clone();
+    let (line_datagrams, keep_newline) = (config.line_datagrams, config.keep_newline);
+    let send_log = config.log_file.clone();
+    let stop = Arc::new(Notify::new());
+    let send_stop = stop.clone();
+    // Raised by the send side when reading the input fails
+    let input_failed = Arc::new(Notify::new());
+    let send_failed = input_failed.clone();
+    let (chunks, trace) = (config.verbosity >= 2, config.verbosity >= 3);
     let send_task = tokio::spawn(async move {
-        let mut stdin = io::stdin();
-        let mut buf = vec![0u8; 8192];
-        loop {
-            match stdin.read(&mut buf).await {
-                Ok(0) => break, // EOF
-                Ok(n) => {
-                    // best-effort send; ignore result but break on fatal error would also be acceptable
-                    let _ = send_socket.send_to(&buf[..n], remote_addr).await;
+        let mut sent = 0u64;
+        let send = async {
+            if line_datagrams {
+                let mut input = BufReader::new(input);
+                let mut line = Vec::new();
+                loop {
+                    line.clear();
+                    match input.read_until(b'\n', &mut line).await {
+                        Ok(0) => break,
+                        Err(e) => return Err(e),
+                        Ok(_) => {
+                            if !keep_newline && line.last() == Some(&b'\n') {
+                                line.pop();
+                            }
+                            match send_datagram(&send_socket, &line, remote_addr, connected).await {
+                                Ok(n) => {
+                                    if chunks {
+                                        diag!("udp: sent {} bytes to {}", n, remote_addr);
+                                    }
+                                    sent += n as u64;
+                                    if let Some(log) = &send_log {
+                                        log.record(Direction::Sent, &line);
+                                    }
+                                }
+                                // The next datagram may well get through
+                                Err(e) if verbose => {
+                                    diag!("udp: sending to {} failed: {}", remote_addr, e)
+                                }
+                                Err(_) => {}
+                            }
+                        }
+                    }
                 }
-                Err(_) => break,
+                return Ok(());
             }
+            let mut input = input;
+            let mut buf = vec![0u8; send_len];
+            loop {
+                match input.read(&mut buf).await {
+                    Ok(0) => break, // EOF
+                    Ok(n) => {
+                        match send_datagram(&send_socket, &buf[..n], remote_addr, connected).await {
+                            Ok(n) => {
+                                if chunks {
+                                    diag!("udp: sent {} bytes to {}", n, remote_addr);
+                                }
+                                sent += n as u64;
+                                if let Some(log) = &send_log {
+                                    log.record(Direction::Sent, &buf[..n]);
+                                }
+                            }
+                            // The next datagram may well get through
+                            Err(e) if verbose => {
+                                diag!("udp: sending to {} failed: {}", remote_addr, e)
+                            }
+                            Err(_) => {}
+                        }
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        };
+        let res = tokio::select! {
+            res = send => res,
+            _ = send_stop.notified() => Ok(()),
+        };
+        match &res {
+            Ok(()) if trace => diag!("udp: input done"),
+            // Replies are no use once the input is broken
+            Err(_) => send_failed.notify_one(),
+            Ok(()) => {}
         }
+        res.map(|()| sent)
     });
 
-    // Receive task: print incoming datagrams to stdout.
+    // Receive task: print incoming datagrams to stdout. A broadcast is
+    // answered by any number of hosts, none of them the remote itself.
+    let (strict_peer, broadcast) = (config.udp_strict_peer, config.broadcast);
+    let display = config.display_filters();
+    let recv_log = config.log_file.clone();
     let recv_socket = socket.clone();
+    let cancel = config.cancel.clone();
     let recv_task = tokio::spawn(async move {
-        let mut buf = vec![0u8; 65536];
-        let mut stdout = io::stdout();
-        loop {
-            match recv_socket.recv_from(&mut buf).await {
-                Ok((n, _src)) => {
-                    let _ = stdout.write_all(&buf[..n]).await;
-                    let _ = stdout.flush().await;
+        let mut buf = vec![0u8; recv_len];
+        let mut stdout = FilterWriter::new(output, display);
+        let mut responders = HashSet::new();
+        let mut received_total = 0u64;
+        let receive = async {
+            loop {
+                let recv = async {
+                    if connected {
+                        recv_socket.recv(&mut buf).await.map(|n| (n, remote_addr))
+                    } else {
+                        recv_socket.recv_from(&mut buf).await
+                    }
+                };
+                let received = match timeout {
+                    Some(dur) => match time::timeout(dur, recv).await {
+                        Ok(received) => received,
+                        Err(_) => {
+                            if verbose {
+                                diag!("udp: session idle for {:?}, closing", dur);
+                            }
+                            stop.notify_one();
+                            break Ok(());
+                        }
+                    },
+                    None => recv.await,
+                };
+                let (n, src) = match received {
+                    Ok(received) => received,
+                    // A connected socket hears of the ICMP errors earlier
+                    // sends drew; nothing listening yet is no reason to stop
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                        if verbose {
+                            diag!("udp: {} refused a datagram", remote_addr);
+                        }
+                        continue;
+                    }
+                    Err(_) => break Ok(()),
+                };
+                if strict_peer && src != remote_addr {
+                    if verbose {
+                        diag!("udp: dropped {} bytes from unexpected peer {}", n, src);
+                    }
+                    continue;
                 }
-                Err(_) => break,
-            }
-        }
-    });
-
-    // Wait for both tasks, optionally applying a timeout to the whole session.
-    if let Some(dur) = timeout {
-        match time::timeout(dur, async {
-            let _ = tokio::join!(send_task, recv_task);
-        })
-        .await
-        {
-            Ok(_) => {
-                if verbose {
-                    eprintln!("udp: session finished");
+                if broadcast && verbose && responders.insert(src) {
+                    diag!("udp: reply from {}", src);
                 }
-            }
-            Err(_) => {
-                if verbose {
-                    eprintln!("udp: session timed out after {:?}", dur);
+                if chunks {
+                    diag!("udp: received {} bytes from {}", n, src);
+                }
+                received_total += n as u64;
+                if let Some(log) = &recv_log {
+                    log.record(Direction::Received, &buf[..n]);
+                }
+                if let Err(e) = stdout.write_all(&buf[..n]).await {
+                    // Nowhere to deliver the data, so end the session
+                    stop.notify_one();
+                    break Err(e);
+                }
+                if let Err(e) = stdout.flush().await {
+                    stop.notify_one();
+                    break Err(e);
                 }
             }
-        }
-    } else {
-        let _ = tokio::join!(send_task, recv_task);
-    }
+        };
+        let res = tokio::select! {
+            res = receive => res,
+            // Ending this side ends the send side too
+            _ = cancelled(cancel.as_ref()) => {
+                stop.notify_one();
+                Ok(())
+            }
+            _ = input_failed.notified() => Ok(()),
+        };
+        // A file output only has everything once flushed
+        res.and(stdout.end().await).map(|()| received_total)
+    });
 
-    Ok(())
+    let (bytes_sent, bytes_received) = join_tasks(send_task, recv_task, verbose).await?;
+    let (bytes_sent, bytes_received) = (bytes_sent?, bytes_received?);
+    if verbose {
+        diag!("udp: session finished");
+    }
+    Ok(SessionStats {
+        bytes_sent,
+        bytes_received,
+        duration: started.elapsed(),
+    })
 }
 
-pub async fn listen(port: u16, timeout: Option<Duration>, verbose: bool) -> anyhow::Result<()> {
-    let bind_addr = format!("0.0.0.0:{}", port);
-    if verbose {
-        eprintln!("udp: listening on {}", bind_addr);
+/// Send `data` to `to`, which a connected socket is already bound to.
+async fn send_datagram(
+    socket: &UdpSocket,
+    data: &[u8],
+    to: SocketAddr,
+    connected: bool,
+) -> io::Result<usize> {
+    if connected {
+        socket.send(data).await
+    } else {
+        socket.send_to(data, to).await
     }
+}
 
-    let socket = Arc::new(UdpSocket::bind(&bind_addr).await?);
+/// Print datagrams arriving on `port`; the input goes to the most recent
+/// sender.
+///
+/// Broadcasts are only delivered to sockets bound to the wildcard address (a
+/// socket bound to a unicast address never sees them on Linux), so the
+/// listener always binds one: `0.0.0.0` with `-4`, `[::]` with `-6`, and
+/// otherwise a dual-stack `[::]` that falls back to `0.0.0.0` where the
+/// platform cannot take IPv4 on it (see [`util::bind_udp_listener`]). With
+/// `config.multicast`, the group is joined once bound and left when the
+/// listener is done. `config.ready` announces the bound address.
+pub async fn listen(port: u16, config: &Config) -> anyhow::Result<SessionStats> {
+    let started = Instant::now();
+    let (timeout, verbose) = (config.timeout, config.verbose());
+    let (send_len, recv_len) = (config.send_len(), config.recv_len());
+    let input = config.input.open().await?;
+    let output = config.output.open().await?.writer()?;
+    let wildcard = match config.multicast {
+        Some(Multicast::V4 { .. }) => Ipv4Addr::UNSPECIFIED.into(),
+        Some(Multicast::V6 { .. }) => Ipv6Addr::UNSPECIFIED.into(),
+        None => config.family.wildcard(),
+    };
+    let bind_addr = SocketAddr::new(wildcard, port);
+
+    // Without -4/-6, take both families on one socket where the platform
+    // allows it, and IPv4 alone where it does not
+    let dual_stack = config.multicast.is_none() && config.family == util::Family::Any;
+    let reuseport = config.reuseport;
+    let dual_addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
+    let socket = match dual_stack.then(|| util::bind_udp_listener(dual_addr, reuseport, true)) {
+        Some(Ok(socket)) => {
+            if verbose {
+                diag!("udp: listening on [::]:{} (IPv4 and IPv6)", port);
+            }
+            socket
+        }
+        fallback => {
+            if let (Some(Err(e)), true) = (fallback, verbose) {
+                diag!("udp: no dual-stack socket ({}), IPv4 only", e);
+            }
+            if verbose {
+                diag!("udp: listening on {}", bind_addr);
+            }
+            util::bind_udp_listener(bind_addr, reuseport, false)?
+        }
+    };
+    let socket = Arc::new(socket);
+    if let Some(multicast) = config.multicast {
+        let joined = match multicast {
+            Multicast::V4 { group, iface } => socket.join_multicast_v4(group, iface),
+            Multicast::V6 { group, iface } => socket.join_multicast_v6(&group, iface),
+        };
+        joined.map_err(|e| anyhow::anyhow!("cannot join {}: {}", multicast.group(), e))?;
+        if verbose {
+            diag!("udp: joined multicast group {}", multicast.group());
+        }
+    }
+    socket.set_broadcast(config.broadcast)?;
+    let dscp_passthrough = config.dscp_passthrough;
+    let display = config.display_filters();
+    if dscp_passthrough {
+        util::set_recv_tos(&socket)?;
+    }
+    if config.dump_sockopts {
+        util::log_sockopts(&*socket);
+    }
+    if let Some(signal) = config.ready {
+        announce_ready(signal, socket.local_addr()?)?;
+    }
     if verbose {
-        eprintln!("udp: bound to {}", socket.local_addr()?);
+        diag!("udp: bound to {}", socket.local_addr()?);
     }
 
-    // Track the last peer we heard from so stdin can send to it.
-    let last_peer: Arc<Mutex<Option<SocketAddr>>> = Arc::new(Mutex::new(None));
+    // Track the last peer we heard from so stdin can send to it, along with
+    // the TOS its datagram carried (only read under --dscp-passthrough).
+    let last_peer = Arc::new(Mutex::new(None::<(SocketAddr, Option<u8>)>));
 
     // Receive task: record peer and print incoming payloads to stdout.
     let recv_peer = last_peer.clone();
+    let recv_log = config.log_file.clone();
     let recv_socket = socket.clone();
+    let stop = Arc::new(Notify::new());
+    let recv_stop = stop.clone();
+    // Raised by the send side when reading the input fails
+    let input_failed = Arc::new(Notify::new());
+    let send_failed = input_failed.clone();
+    let cancel = config.cancel.clone();
+    let (chunks, trace) = (config.verbosity >= 2, config.verbosity >= 3);
     let recv_task = tokio::spawn(async move {
-        let mut buf = vec![0u8; 65536];
-        let mut stdout = io::stdout();
-        loop {
-            match recv_socket.recv_from(&mut buf).await {
-                Ok((n, src)) => {
-                    // record peer
-                    {
-                        let mut guard = recv_peer.lock().await;
-                        *guard = Some(src);
+        let mut buf = vec![0u8; recv_len];
+        let mut stdout = FilterWriter::new(output, display);
+        let mut received_total = 0u64;
+        let receive = async {
+            loop {
+                let recv = async {
+                    if dscp_passthrough {
+                        util::recv_from_with_tos(&recv_socket, &mut buf).await
+                    } else {
+                        recv_socket
+                            .recv_from(&mut buf)
+                            .await
+                            .map(|(n, src)| (n, src, None))
                     }
-                    let _ = stdout.write_all(&buf[..n]).await;
-                    let _ = stdout.flush().await;
+                };
+                let received = match timeout {
+                    Some(dur) => match time::timeout(dur, recv).await {
+                        Ok(received) => received,
+                        Err(_) => {
+                            if verbose {
+                                diag!("udp: listener idle for {:?}, closing", dur);
+                            }
+                            recv_stop.notify_one();
+                            break Ok(());
+                        }
+                    },
+                    None => recv.await,
+                };
+                let Ok((n, src, tos)) = received else {
+                    break Ok(());
+                };
+                if chunks {
+                    diag!("udp: received {} bytes from {}", n, src);
+                }
+                // record peer
+                {
+                    let mut guard = recv_peer.lock().await;
+                    *guard = Some((src, tos));
+                }
+                received_total += n as u64;
+                if let Some(log) = &recv_log {
+                    log.record(Direction::Received, &buf[..n]);
+                }
+                if let Err(e) = stdout.write_all(&buf[..n]).await {
+                    // Nowhere to deliver the data, so end the session
+                    recv_stop.notify_one();
+                    break Err(e);
+                }
+                if let Err(e) = stdout.flush().await {
+                    recv_stop.notify_one();
+                    break Err(e);
                 }
-                Err(_) => break,
             }
-        }
+        };
+        let res = tokio::select! {
+            res = receive => res,
+            // Ending this side ends the send side too
+            _ = cancelled(cancel.as_ref()) => {
+                recv_stop.notify_one();
+                Ok(())
+            }
+            _ = input_failed.notified() => Ok(()),
+        };
+        // A file output only has everything once flushed
+        res.and(stdout.end().await).map(|()| received_total)
     });
 
-    // Send task: read stdin and send to last seen peer (if any).
+    // Send task: read the input and send to last seen peer (if any).
+    let send_log = config.log_file.clone();
     let send_peer = last_peer.clone();
     let send_socket = socket.clone();
     let send_task = tokio::spawn(async move {
-        let mut stdin = io::stdin();
-        let mut buf = vec![0u8; 8192];
-        loop {
-            match stdin.read(&mut buf).await {
-                Ok(0) => break,
-                Ok(n) => {
-                    // get current peer snapshot
-                    let opt_peer = { send_peer.lock().await.clone() };
-                    if let Some(peer) = opt_peer {
-                        let _ = send_socket.send_to(&buf[..n], peer).await;
-                    } else {
-                        // no peer yet; drop data
+        let mut input = input;
+        let mut buf = vec![0u8; send_len];
+        let mut applied_tos = None;
+        let mut sent = 0u64;
+        let send = async {
+            loop {
+                match input.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        // get current peer snapshot
+                        let opt_peer = { *send_peer.lock().await };
+                        if let Some((peer, tos)) = opt_peer {
+                            if let Some(value) = tos
+                                && tos != applied_tos
+                            {
+                                match util::set_tos(&send_socket, value) {
+                                    Ok(()) => applied_tos = tos,
+                                    Err(e) => diag!("udp: cannot set TOS {:#04x}: {}", value, e),
+                                }
+                            }
+                            match send_socket.send_to(&buf[..n], peer).await {
+                                Ok(n) => {
+                                    if chunks {
+                                        diag!("udp: sent {} bytes to {}", n, peer);
+                                    }
+                                    sent += n as u64;
+                                    if let Some(log) = &send_log {
+                                        log.record(Direction::Sent, &buf[..n]);
+                                    }
+                                }
+                                Err(e) if verbose => {
+                                    diag!("udp: sending to {} failed: {}", peer, e)
+                                }
+                                Err(_) => {}
+                            }
+                        } else if trace {
+                            diag!("udp: no peer yet, dropped {} bytes of input", n);
+                        }
                     }
+                    Err(e) => return Err(e),
                 }
-                Err(_) => break,
             }
+            Ok(())
+        };
+        let res = tokio::select! {
+            res = send => res,
+            _ = stop.notified() => Ok(()),
+        };
+        if res.is_err() {
+            send_failed.notify_one();
         }
+        res.map(|()| sent)
     });
 
-    if let Some(dur) = timeout {
-        match time::timeout(dur, async {
-            let _ = tokio::join!(recv_task, send_task);
-        })
-        .await
-        {
-            Ok(_) => {
-                if verbose {
-                    eprintln!("udp: listen finished");
-                }
-            }
-            Err(_) => {
-                if verbose {
-                    eprintln!("udp: listen timed out after {:?}", dur);
-                }
-            }
+    let joined = join_tasks(recv_task, send_task, verbose).await;
+    if let Some(multicast) = config.multicast {
+        let left = match multicast {
+            Multicast::V4 { group, iface } => socket.leave_multicast_v4(group, iface),
+            Multicast::V6 { group, iface } => socket.leave_multicast_v6(&group, iface),
+        };
+        if let Err(e) = left {
+            diag!("udp: cannot leave {}: {}", multicast.group(), e);
         }
-    } else {
-        let _ = tokio::join!(recv_task, send_task);
     }
-
-    Ok(())
+    let (bytes_received, bytes_sent) = joined?;
+    let (bytes_sent, bytes_received) = (bytes_sent?, bytes_received?);
+    if verbose {
+        diag!("udp: listen finished");
+    }
+    Ok(SessionStats {
+        bytes_sent,
+        bytes_received,
+        duration: started.elapsed(),
+    })
 }
@@ -0,0 +1,325 @@
+/*!
+r-cat/src/net/unix.rs
+
+Unix-domain stream sockets (`-U`): the destination is a filesystem path and
+there is no port.
+
+Sessions shuttle stdin <-> socket like plain TCP ones and honour the same
+core tunables (`-w`, `-q`, `-k`, `--half-close`, `-I`/`-O` and the
+send/receive filters);
+TCP-only features such as uploads, taps and pacing are not available here.
+*/
+
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+use super::{
+    CancelToken, Config, InputError, Local, OutputError, SessionStats, cancelled, join_tasks,
+    sleep_until,
+};
+use crate::diag;
+use crate::filter::FilterWriter;
+use crate::flush::TimedFlush;
+
+/// Connect to the socket at `path` and shuttle stdin <-> socket.
+///
+/// `config.timeout` bounds the connect and ends a session that receives
+/// nothing for that long.
+pub async fn client(path: &Path, config: &Config) -> anyhow::Result<SessionStats> {
+    let input = config.input.open().await?;
+    let output = config.output.open().await?;
+    if config.verbose() {
+        diag!("Connecting to {}", path.display());
+    }
+    let connect_fut = UnixStream::connect(path);
+    let stream = match config.timeout {
+        Some(dur) => match time::timeout(dur, connect_fut).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("connect timed out after {:?}", dur),
+            )),
+        },
+        None => connect_fut.await,
+    }
+    .map_err(|e| anyhow::Error::new(e).context(format!("cannot connect to {}", path.display())))?;
+
+    let local = Local {
+        input: Some(input),
+        output: output.writer()?,
+    };
+    shuttle(stream, config, local, "Session").await
+}
+
+/// Bind a listener at `path` and shuttle stdin <-> socket for the accepted
+/// connection, or for each one in turn with `config.keep_open`.
+///
+/// A stale socket left at `path` by an earlier run is replaced, but any other
+/// kind of file is refused. The socket file is removed again once the
+/// listener is done.
+pub async fn listen(path: &Path, config: &Config) -> anyhow::Result<SessionStats> {
+    let verbose = config.verbose();
+    let mut input = Some(config.input.open().await?);
+    let output = config.output.open().await?;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => anyhow::bail!("{} exists and is not a socket", path.display()),
+        Err(_) => {}
+    }
+    if verbose {
+        diag!("Listening on {}", path.display());
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|e| anyhow::anyhow!("cannot listen on {}: {}", path.display(), e))?;
+    let _socket_file = SocketFile(path.to_path_buf());
+
+    loop {
+        let accepting = async {
+            let accept_fut = listener.accept();
+            match config.timeout {
+                Some(dur) => match time::timeout(dur, accept_fut).await {
+                    Ok(Ok(accepted)) => Ok(accepted),
+                    Ok(Err(e)) => anyhow::bail!("accept failed: {}", e),
+                    Err(_) => Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("accept timed out after {:?}", dur),
+                    )
+                    .into()),
+                },
+                None => Ok(accept_fut.await?),
+            }
+        };
+        let (stream, _peer) = tokio::select! {
+            accepted = accepting => accepted?,
+            _ = cancelled(config.cancel.as_ref()) => return Ok(SessionStats::default()),
+        };
+        if verbose {
+            diag!("Accepted connection on {}", path.display());
+        }
+        if config.print_peer_cred {
+            report_peer_cred(&stream);
+        }
+
+        // The input only feeds the first connection; later ones just receive
+        let local = Local {
+            input: input.take(),
+            output: output.writer()?,
+        };
+        let res = shuttle(stream, config, local, "Connection").await;
+        let stopped = config
+            .cancel
+            .as_ref()
+            .is_some_and(CancelToken::is_cancelled);
+        if !config.keep_open || stopped {
+            return res;
+        }
+        if let Err(e) = res {
+            diag!("Connection failed: {}", e);
+        }
+    }
+}
+
+/// Print the uid, gid and (where the platform reports it) pid the kernel
+/// recorded for the peer of `stream` when it connected: `SO_PEERCRED` on
+/// Linux, `LOCAL_PEERCRED` or `getpeereid` on the BSDs.
+fn report_peer_cred(stream: &UnixStream) {
+    match stream.peer_cred() {
+        Ok(cred) => diag!(
+            "Peer credentials: uid={} gid={} pid={}",
+            cred.uid(),
+            cred.gid(),
+            cred.pid()
+                .map_or_else(|| "?".to_string(), |pid| pid.to_string())
+        ),
+        Err(e) => diag!("Peer credentials unavailable: {}", e),
+    }
+}
+
+/// Removes the listener's socket file when dropped.
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Shuttle input <-> socket until the peer closes its side (or, with
+/// `config.half_close`, until both directions finish), the peer has sent
+/// nothing for `-w`, or `-q` has passed since our input ended. Our side is
+/// shut down once input ends, unless `config.no_shutdown` is set. Without
+/// `local.input`, nothing is sent and only the peer's data is shown.
+async fn shuttle(
+    stream: UnixStream,
+    config: &Config,
+    local: Local,
+    what: &str,
+) -> anyhow::Result<SessionStats> {
+    let started = Instant::now();
+    let verbose = config.verbose();
+    let (mut reader, writer) = stream.into_split();
+    let mut writer = FilterWriter::new(writer, config.send_filters());
+    let mut stdout = FilterWriter::new(
+        TimedFlush::new(local.output, config.flush_interval),
+        config.recv_filters(),
+    );
+    let (send_len, recv_len) = (config.send_len(), config.recv_len());
+    let (idle_timeout, quit_after) = (config.timeout, config.quit_after);
+    let (half_close, no_shutdown) = (config.half_close, config.no_shutdown);
+    let cancel = config.cancel.clone();
+    let (chunks, trace) = (config.verbosity >= 2, config.verbosity >= 3);
+
+    // Raised by the read side on -w, the peer's EOF or `config.cancel`, ending
+    // the write side too
+    let stop = Arc::new(Notify::new());
+    // Raised by the write side once all input is sent, starting `-q`'s clock
+    let input_done = Arc::new(Notify::new());
+    // Raised by the write side when reading the input fails
+    let input_failed = Arc::new(Notify::new());
+
+    // input -> socket
+    let write_stop = stop.clone();
+    let write_done = input_done.clone();
+    let write_failed = input_failed.clone();
+    let write_task = tokio::spawn(async move {
+        let copy = async {
+            let Some(input) = local.input else {
+                return Ok(0);
+            };
+            let mut input = BufReader::with_capacity(send_len, input);
+            io::copy_buf(&mut input, &mut writer).await
+        };
+        let mut res = tokio::select! {
+            res = copy => res,
+            _ = write_stop.notified() => Ok(0),
+        };
+        if matches!(&res, Err(e) if InputError::caused(e)) {
+            write_failed.notify_one();
+        }
+        if res.is_ok()
+            && let Err(e) = writer.end().await
+        {
+            res = Err(e);
+        }
+        if no_shutdown {
+            // Dropping the half would shut it down all the same
+            writer.into_inner().forget();
+        } else {
+            let _ = writer.get_mut().shutdown().await;
+        }
+        write_done.notify_one();
+        res
+    });
+
+    // socket -> stdout
+    let mut buf = config.buffers.get(recv_len);
+    let read_task = tokio::spawn(async move {
+        let mut total = 0u64;
+        let mut last_received = Instant::now();
+        let mut quit_due = None;
+        let res = loop {
+            let flush_due = stdout.get_mut().deadline();
+            let idle_due = idle_timeout.map(|dur| last_received + dur);
+            let read = tokio::select! {
+                read = reader.read(&mut buf) => read,
+                _ = sleep_until(flush_due) => {
+                    if let Err(e) = stdout.flush().await {
+                        // Nowhere to deliver the data, so end the session
+                        stop.notify_one();
+                        break Err(e);
+                    }
+                    continue;
+                }
+                _ = sleep_until(idle_due) => {
+                    if trace {
+                        diag!("Idle timer expired after {:?}", idle_timeout.unwrap_or_default());
+                    }
+                    stop.notify_one();
+                    break Err(io::ErrorKind::TimedOut.into());
+                }
+                _ = input_done.notified(), if quit_due.is_none() => {
+                    quit_due = quit_after.map(|dur| Instant::now() + dur);
+                    continue;
+                }
+                _ = sleep_until(quit_due) => break Ok(total),
+                _ = input_failed.notified() => break Ok(total),
+                _ = cancelled(cancel.as_ref()) => {
+                    stop.notify_one();
+                    break Ok(total);
+                }
+            };
+            match read {
+                Ok(0) => {
+                    if trace {
+                        diag!("Peer closed its write half");
+                    }
+                    if !half_close {
+                        stop.notify_one();
+                    }
+                    break Ok(total);
+                }
+                Ok(n) => {
+                    last_received = Instant::now();
+                    if chunks {
+                        diag!("Received {} bytes", n);
+                    }
+                    // Unless --flush-interval holds it back, flush straight away: a
+                    // file or stdout only reports a failed write on the next call
+                    let written = match stdout.write_all(&buf[..n]).await {
+                        Ok(()) if stdout.get_mut().deadline().is_none() => stdout.flush().await,
+                        written => written,
+                    };
+                    if let Err(e) = written {
+                        // Nowhere to deliver the data, so end the session
+                        stop.notify_one();
+                        break Err(e);
+                    }
+                    total += n as u64;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        // A file output only has everything once flushed
+        match (stdout.end().await, res) {
+            (Err(e), Ok(_)) => Err(e),
+            (_, res) => res,
+        }
+    });
+
+    let (sent, received) = join_tasks(write_task, read_task, verbose).await?;
+    let sent = match sent {
+        Err(e) if InputError::caused(&e) => return Err(e.into()),
+        sent => sent,
+    };
+    let received = match received {
+        Err(e) if OutputError::caused(&e) => return Err(e.into()),
+        received => received,
+    };
+    match &received {
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+            if verbose {
+                diag!(
+                    "{} idle for {:?}, closing",
+                    what,
+                    idle_timeout.unwrap_or_default()
+                );
+            }
+        }
+        _ => {
+            if verbose {
+                diag!("{} finished", what);
+            }
+        }
+    }
+    Ok(SessionStats {
+        bytes_sent: sent.unwrap_or_default(),
+        bytes_received: received.unwrap_or_default(),
+        duration: started.elapsed(),
+    })
+}
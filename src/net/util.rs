@@ -0,0 +1,1042 @@
+/*!
+r-cat/src/net/util.rs
+
+Socket-level helpers shared by the TCP and UDP paths: raw socket options that
+tokio does not expose, diagnostics built on top of them, destination lookup
+and its `-4`/`-6` restriction, Happy Eyeballs connection racing, the
+backoff policy consulted by retry loops, and the pool of receive buffers
+listeners reuse across connections.
+*/
+
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::task::Poll;
+use std::time::Duration;
+
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{self, Instant};
+
+use super::{Config, idn};
+use crate::diag;
+
+/// Which IP family destinations and listeners are restricted to (`-4`/`-6`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Family {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl Family {
+    /// Keep only the resolved addresses of `host` this family allows.
+    pub fn restrict(self, host: &str, addrs: Vec<SocketAddr>) -> io::Result<Vec<SocketAddr>> {
+        let name = match self {
+            Family::Any => return Ok(addrs),
+            Family::V4 => "IPv4",
+            Family::V6 => "IPv6",
+        };
+        let kept: Vec<_> = addrs
+            .into_iter()
+            .filter(|a| a.is_ipv4() == (self == Family::V4))
+            .collect();
+        if kept.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no {} address", host, name),
+            ));
+        }
+        Ok(kept)
+    }
+
+    /// The wildcard address listeners bind: `[::]` under `-6`, `0.0.0.0`
+    /// otherwise.
+    pub fn wildcard(self) -> IpAddr {
+        match self {
+            Family::V6 => Ipv6Addr::UNSPECIFIED.into(),
+            _ => Ipv4Addr::UNSPECIFIED.into(),
+        }
+    }
+}
+
+/// The local address a client socket should be bound to for `source` (`-s`)
+/// and `port` (`-p`), with the wildcard of `remote`'s IP family standing in
+/// for a missing source address. `None` leaves the choice to the kernel.
+pub fn local_bind(
+    remote: SocketAddr,
+    source: Option<IpAddr>,
+    port: Option<u16>,
+) -> Option<SocketAddr> {
+    if source.is_none() && port.is_none() {
+        return None;
+    }
+    let ip = source.unwrap_or(match remote {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    });
+    Some(SocketAddr::new(ip, port.unwrap_or(0)))
+}
+
+/// A fresh random number for ports, jitter and shuffles. Every
+/// `RandomState` is keyed differently, which is random enough here and
+/// spares an RNG crate.
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// A random port from `lo` to `hi`, both included.
+pub fn random_port(lo: u16, hi: u16) -> u16 {
+    let span = u64::from(hi - lo) + 1;
+    lo + (random_u64() % span) as u16
+}
+
+/// Explain a failure to bind (or connect from) the local address `local`.
+pub fn bind_error(local: SocketAddr, e: io::Error) -> io::Error {
+    let msg = if e.kind() == io::ErrorKind::AddrInUse {
+        format!("source port {} is already in use", local.port())
+    } else {
+        format!("cannot bind to source address {}: {}", local, e)
+    };
+    io::Error::new(e.kind(), msg)
+}
+
+/// How long to wait after a probe for an ICMP "fragmentation needed" reply
+/// to lower the kernel's cached path MTU.
+const PROBE_SETTLE: Duration = Duration::from_millis(100);
+
+/// How retry delays grow (`--backoff`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum BackoffKind {
+    /// base, 2*base, 3*base, ...
+    Linear,
+    /// base, 2*base, 4*base, ...
+    #[default]
+    Exponential,
+}
+
+/// Delay policy shared by every retry loop, so they all time out alike.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub kind: BackoffKind,
+    /// Delay before the first retry.
+    pub base: Duration,
+    /// Upper bound for any single delay.
+    pub max: Duration,
+    /// Randomise each delay to between half and all of its nominal value.
+    pub jitter: bool,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            kind: BackoffKind::Exponential,
+            base: Duration::from_millis(200),
+            max: Duration::from_secs(5),
+            jitter: false,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay to wait before retry number `attempt` (0 for the first retry).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = match self.kind {
+            BackoffKind::Linear => attempt.saturating_add(1),
+            BackoffKind::Exponential => 1u32.checked_shl(attempt).unwrap_or(u32::MAX),
+        };
+        let nominal = self.base.saturating_mul(factor).min(self.max);
+        if self.jitter {
+            // Spread retries of many clients apart
+            let r = random_u64() % 1000;
+            nominal.mul_f64(0.5 + r as f64 / 2000.0)
+        } else {
+            nominal
+        }
+    }
+}
+
+/// Resolve `addr` (`host:port`) with the system resolver, retrying failed or
+/// empty lookups up to `retries` times, `backoff` apart.
+pub async fn resolve(
+    addr: &str,
+    retries: u32,
+    backoff: &Backoff,
+    verbose: bool,
+) -> io::Result<Vec<SocketAddr>> {
+    resolve_with(addr, retries, backoff, verbose, |addr| async move {
+        tokio::net::lookup_host(addr).await
+    })
+    .await
+}
+
+/// [`resolve`] with a caller-supplied lookup function in place of the system
+/// resolver.
+pub async fn resolve_with<F, Fut, I>(
+    addr: &str,
+    retries: u32,
+    backoff: &Backoff,
+    verbose: bool,
+    mut lookup: F,
+) -> io::Result<Vec<SocketAddr>>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = io::Result<I>>,
+    I: IntoIterator<Item = SocketAddr>,
+{
+    let mut attempt = 0;
+    loop {
+        let err = match lookup(addr.to_string()).await {
+            Ok(found) => {
+                let found: Vec<_> = found.into_iter().collect();
+                if !found.is_empty() {
+                    return Ok(found);
+                }
+                io::Error::new(io::ErrorKind::NotFound, "no addresses found")
+            }
+            Err(e) => e,
+        };
+        if attempt >= retries {
+            return Err(io::Error::new(
+                err.kind(),
+                format!("cannot resolve {}: {}", addr, err),
+            ));
+        }
+        let delay = backoff.delay(attempt);
+        attempt += 1;
+        if verbose {
+            diag!(
+                "Resolving {} failed ({}), retry {}/{} in {:?}",
+                addr,
+                err,
+                attempt,
+                retries,
+                delay
+            );
+        }
+        time::sleep(delay).await;
+    }
+}
+
+/// Resolve a session's destination `addr` (`host:port`) as `config` asks:
+/// under `-n` only a literal IP address is accepted, otherwise the name is
+/// looked up per [`resolve`], an internationalized one in its `xn--` form
+/// (see [`idn::to_ascii`]). Either way the result is restricted to
+/// `config.family`.
+pub async fn resolve_destination(addr: &str, config: &Config) -> io::Result<Vec<SocketAddr>> {
+    let found = if config.numeric {
+        vec![parse_numeric(addr)?]
+    } else {
+        let ascii = ascii_destination(addr)?;
+        resolve(
+            &ascii,
+            config.retry_resolve,
+            &config.backoff,
+            config.verbose(),
+        )
+        .await?
+    };
+    config.family.restrict(addr, found)
+}
+
+/// How long a connection attempt gets before the next address is tried in
+/// parallel (RFC 8305's "Connection Attempt Delay").
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Race `attempt` over `targets` Happy Eyeballs style (RFC 8305).
+///
+/// Addresses are tried alternating between IP families, starting with the
+/// family of the first one; each attempt starts `delay` after the previous
+/// one, or right away once every running attempt has failed. The first
+/// success wins and the attempts still running are dropped. If all of them
+/// fail, the last error is returned.
+pub async fn happy_eyeballs<F, Fut, T>(
+    targets: Vec<SocketAddr>,
+    delay: Duration,
+    mut attempt: F,
+) -> io::Result<T>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut queue = interleave_families(targets).into_iter();
+    let mut running = Vec::new();
+    let mut last_err = None;
+    let mut next_due = Instant::now();
+    loop {
+        if running.is_empty() {
+            let Some(target) = queue.next() else {
+                return Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")
+                }));
+            };
+            running.push(Box::pin(attempt(target)));
+            next_due = Instant::now() + delay;
+        }
+        let more = queue.len() > 0;
+        tokio::select! {
+            (i, res) = first_ready(&mut running) => {
+                drop(running.swap_remove(i));
+                match res {
+                    Ok(out) => return Ok(out),
+                    Err(e) => last_err = Some(e),
+                }
+                // A failure frees the slot: no need to wait out the delay
+                next_due = Instant::now();
+            }
+            _ = time::sleep_until(next_due), if more => {
+                if let Some(target) = queue.next() {
+                    running.push(Box::pin(attempt(target)));
+                }
+                next_due = Instant::now() + delay;
+            }
+        }
+    }
+}
+
+/// Reorder `targets` to alternate between IP families, keeping the order
+/// within each family and starting with the family of the first address.
+fn interleave_families(targets: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let lead_v4 = targets.first().is_some_and(|a| a.is_ipv4());
+    let (lead, other): (Vec<_>, Vec<_>) = targets.into_iter().partition(|a| a.is_ipv4() == lead_v4);
+    let mut out = Vec::with_capacity(lead.len() + other.len());
+    let (mut lead, mut other) = (lead.into_iter(), other.into_iter());
+    loop {
+        match (lead.next(), other.next()) {
+            (None, None) => return out,
+            (a, b) => out.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// The index and output of whichever future in `futs` finishes first.
+fn first_ready<F: Future + Unpin>(futs: &mut [F]) -> impl Future<Output = (usize, F::Output)> + '_ {
+    std::future::poll_fn(move |cx| {
+        for (i, fut) in futs.iter_mut().enumerate() {
+            if let Poll::Ready(out) = Pin::new(fut).poll(cx) {
+                return Poll::Ready((i, out));
+            }
+        }
+        Poll::Pending
+    })
+}
+
+/// `addr` (`host:port`) with its host converted by [`idn::to_ascii`].
+pub fn ascii_destination(addr: &str) -> io::Result<String> {
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_ascii() => Ok(format!("{}:{}", idn::to_ascii(host)?, port)),
+        _ => Ok(addr.to_string()),
+    }
+}
+
+/// Parse `host:port` whose host is a literal IP address, bracketed or not
+/// for IPv6 (`-n`).
+pub fn parse_numeric(addr: &str) -> io::Result<SocketAddr> {
+    let (host, port) = addr.rsplit_once(':').unwrap_or((addr, ""));
+    let ip = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host)
+        .parse::<IpAddr>()
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot resolve {}: name resolution is disabled (-n)", host),
+            )
+        })?;
+    let port = port.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' has no numeric port", addr),
+        )
+    })?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Find the path MTU towards `remote` by binary-searching the largest UDP
+/// datagram that can be sent with the don't-fragment bit set.
+///
+/// The socket is connected to `remote` so the kernel tracks the path MTU for
+/// it; sends that exceed it fail with `EMSGSIZE`. The result includes the IP
+/// and UDP headers, i.e. it is comparable to an interface MTU.
+#[cfg(target_os = "linux")]
+pub async fn probe_path_mtu(
+    socket: &UdpSocket,
+    remote: SocketAddr,
+    verbose: bool,
+) -> anyhow::Result<usize> {
+    let v6 = remote.is_ipv6();
+    // IP + UDP header size, and the largest payload a UDP length field allows
+    let (header, max_payload) = if v6 { (48, 65527) } else { (28, 65507) };
+
+    set_dont_fragment(socket, v6)?;
+    socket.connect(remote).await?;
+
+    let mut lo = 0usize;
+    let mut hi = max_payload.min(path_mtu(socket, v6)?.saturating_sub(header));
+    let buf = vec![0u8; hi];
+
+    while lo < hi {
+        let mid = (lo + hi).div_ceil(2);
+        if send_probe(socket, &buf[..mid]).await? {
+            time::sleep(PROBE_SETTLE).await;
+            if verbose {
+                diag!("mtu-probe: {} byte payload sent", mid);
+            }
+            lo = mid;
+        } else {
+            if verbose {
+                diag!("mtu-probe: {} byte payload too big", mid);
+            }
+            hi = mid - 1;
+        }
+        // An ICMP reply may have lowered the path MTU below what we assumed fit
+        hi = hi.min(path_mtu(socket, v6)?.saturating_sub(header));
+        lo = lo.min(hi);
+    }
+
+    Ok(lo + header)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn probe_path_mtu(
+    _socket: &UdpSocket,
+    _remote: SocketAddr,
+    _verbose: bool,
+) -> anyhow::Result<usize> {
+    anyhow::bail!("--mtu-probe is only supported on Linux")
+}
+
+/// Send one probe datagram; `Ok(false)` means it exceeded the path MTU.
+#[cfg(target_os = "linux")]
+async fn send_probe(socket: &UdpSocket, payload: &[u8]) -> io::Result<bool> {
+    for _ in 0..2 {
+        match socket.send(payload).await {
+            Ok(_) => return Ok(true),
+            Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => return Ok(false),
+            // A stale ICMP port-unreachable from an earlier probe; try again
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Set the don't-fragment bit by switching the socket to strict PMTU discovery.
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(socket: &UdpSocket, v6: bool) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let fd = socket.as_raw_fd();
+    if v6 {
+        setsockopt_int(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        )
+    } else {
+        setsockopt_int(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        )
+    }
+}
+
+/// The kernel's current path MTU estimate for a connected socket.
+#[cfg(target_os = "linux")]
+fn path_mtu(socket: &UdpSocket, v6: bool) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    let fd = socket.as_raw_fd();
+    let mtu = if v6 {
+        getsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_MTU)?
+    } else {
+        getsockopt_int(fd, libc::IPPROTO_IP, libc::IP_MTU)?
+    };
+    Ok(mtu as usize)
+}
+
+/// Send `len` bytes of `file`, starting at `offset`, straight to `stream` with
+/// `sendfile(2)`. Stops early if the file turns out to be shorter.
+#[cfg(target_os = "linux")]
+pub async fn sendfile(
+    stream: &TcpStream,
+    file: &impl std::os::fd::AsRawFd,
+    offset: u64,
+    len: u64,
+) -> io::Result<u64> {
+    use std::os::fd::AsRawFd;
+
+    // Linux transfers at most this much per call
+    const MAX_CHUNK: u64 = 0x7fff_f000;
+
+    let (sock, src) = (stream.as_raw_fd(), file.as_raw_fd());
+    let mut sent = 0u64;
+    while sent < len {
+        stream.writable().await?;
+        let res = stream.try_io(tokio::io::Interest::WRITABLE, || {
+            let mut off = (offset + sent) as libc::off_t;
+            let count = (len - sent).min(MAX_CHUNK) as usize;
+            // SAFETY: both fds are open for the duration of the call and
+            // `off` is a valid in/out pointer.
+            let n = unsafe { libc::sendfile(sock, src, &mut off, count) };
+            if n < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(n as u64)
+            }
+        });
+        match res {
+            Ok(0) => break,
+            Ok(n) => sent += n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn sendfile<F>(
+    _stream: &TcpStream,
+    _file: &F,
+    _offset: u64,
+    _len: u64,
+) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "sendfile is only supported on Linux",
+    ))
+}
+
+/// Longest key the kernel accepts for `TCP_MD5SIG`.
+pub const MD5SIG_MAX_KEY_LEN: usize = 80;
+
+/// Sign all segments exchanged with `peer` on `socket` with an RFC 2385
+/// TCP-MD5 `key`. Must be set before connecting (or, on a listener, before
+/// the peer connects).
+#[cfg(target_os = "linux")]
+pub fn set_tcp_md5sig(
+    socket: &impl std::os::fd::AsRawFd,
+    peer: SocketAddr,
+    key: &[u8],
+) -> io::Result<()> {
+    use std::mem;
+
+    /// `struct tcp_md5sig` from <linux/tcp.h>, which libc does not define.
+    #[repr(C)]
+    struct TcpMd5Sig {
+        addr: libc::sockaddr_storage,
+        flags: u8,
+        prefixlen: u8,
+        keylen: u16,
+        ifindex: libc::c_int,
+        key: [u8; MD5SIG_MAX_KEY_LEN],
+    }
+
+    if key.len() > MD5SIG_MAX_KEY_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "TCP-MD5 key is too long",
+        ));
+    }
+    // SAFETY: all-zero is a valid value for this plain C struct.
+    let mut sig: TcpMd5Sig = unsafe { mem::zeroed() };
+    match peer {
+        SocketAddr::V4(v4) => {
+            // SAFETY: sockaddr_storage is large and aligned enough for sockaddr_in.
+            let sin = unsafe { &mut *(&mut sig.addr as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_addr.s_addr = u32::from(*v4.ip()).to_be();
+        }
+        SocketAddr::V6(v6) => {
+            // SAFETY: sockaddr_storage is large and aligned enough for sockaddr_in6.
+            let sin6 = unsafe { &mut *(&mut sig.addr as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_addr.s6_addr = v6.ip().octets();
+        }
+    }
+    sig.keylen = key.len() as u16;
+    sig.key[..key.len()].copy_from_slice(key);
+
+    // SAFETY: `sig` outlives the call and the length matches its type.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_MD5SIG,
+            &sig as *const TcpMd5Sig as *const libc::c_void,
+            mem::size_of::<TcpMd5Sig>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_tcp_md5sig<S>(_socket: &S, _peer: SocketAddr, _key: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP-MD5 signatures (-S) are only supported on Linux",
+    ))
+}
+
+/// TCP keepalive settings (`--keepalive`, `--keepalive-idle`,
+/// `--keepalive-interval`); times left unset keep the system's defaults.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Seconds a connection is idle before the first probe.
+    pub idle: Option<u32>,
+    /// Seconds between unanswered probes.
+    pub interval: Option<u32>,
+}
+
+/// Longest keepalive idle time or interval, in seconds: Linux's
+/// `MAX_TCP_KEEPIDLE`/`MAX_TCP_KEEPINTVL`.
+pub const MAX_KEEPALIVE_SECS: u32 = 32767;
+
+/// Turn on `SO_KEEPALIVE` and apply the probe timing of `keepalive`.
+///
+/// The timing maps to `TCP_KEEPIDLE`/`TCP_KEEPINTVL` (`TCP_KEEPALIVE` on
+/// Apple systems), whose defaults on Linux come from the
+/// `net.ipv4.tcp_keepalive_*` sysctls. A bare `--keepalive` works
+/// everywhere; an interval fails with `Unsupported` where the system has no
+/// way to set one.
+pub fn set_keepalive(stream: &TcpStream, keepalive: &Keepalive) -> io::Result<()> {
+    let mut params = socket2::TcpKeepalive::new();
+    if let Some(idle) = keepalive.idle {
+        params = params.with_time(Duration::from_secs(idle.into()));
+    }
+    if let Some(interval) = keepalive.interval {
+        params = keepalive_interval(params, Duration::from_secs(interval.into()))?;
+    }
+    socket2::SockRef::from(stream).set_tcp_keepalive(&params)
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "illumos",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "tvos",
+    target_os = "watchos",
+    target_os = "windows",
+))]
+fn keepalive_interval(
+    params: socket2::TcpKeepalive,
+    interval: Duration,
+) -> io::Result<socket2::TcpKeepalive> {
+    Ok(params.with_interval(interval))
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "illumos",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "tvos",
+    target_os = "watchos",
+    target_os = "windows",
+)))]
+fn keepalive_interval(
+    _params: socket2::TcpKeepalive,
+    _interval: Duration,
+) -> io::Result<socket2::TcpKeepalive> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--keepalive-interval is not supported on this platform",
+    ))
+}
+
+/// Read back the effective values of the socket options r-cat's flags (or a
+/// `--socket-hook`) may have touched, as `NAME=value` pairs for
+/// `--dump-sockopts`. TCP options are only listed for stream sockets; an
+/// option the kernel refuses to report shows as `NAME=?`.
+#[cfg(target_os = "linux")]
+pub fn dump_sockopts(socket: &impl std::os::fd::AsRawFd) -> io::Result<String> {
+    use libc::{IPPROTO_IP, IPPROTO_IPV6, IPPROTO_TCP, SOL_SOCKET};
+
+    let fd = socket.as_raw_fd();
+    let stream = getsockopt_int(fd, SOL_SOCKET, libc::SO_TYPE)? == libc::SOCK_STREAM;
+    let v6 = getsockopt_int(fd, SOL_SOCKET, libc::SO_DOMAIN)? == libc::AF_INET6;
+
+    // (name, level, option, shown in hex): TOS and traffic class are bit
+    // fields, easier to read that way
+    let mut opts = Vec::new();
+    if stream {
+        opts.push(("TCP_NODELAY", IPPROTO_TCP, libc::TCP_NODELAY, false));
+        opts.push(("TCP_MAXSEG", IPPROTO_TCP, libc::TCP_MAXSEG, false));
+        opts.push(("TCP_KEEPIDLE", IPPROTO_TCP, libc::TCP_KEEPIDLE, false));
+        opts.push(("TCP_KEEPINTVL", IPPROTO_TCP, libc::TCP_KEEPINTVL, false));
+    }
+    opts.extend([
+        ("SO_SNDBUF", SOL_SOCKET, libc::SO_SNDBUF, false),
+        ("SO_RCVBUF", SOL_SOCKET, libc::SO_RCVBUF, false),
+        ("SO_KEEPALIVE", SOL_SOCKET, libc::SO_KEEPALIVE, false),
+        ("SO_REUSEADDR", SOL_SOCKET, libc::SO_REUSEADDR, false),
+        ("SO_REUSEPORT", SOL_SOCKET, libc::SO_REUSEPORT, false),
+        ("SO_BROADCAST", SOL_SOCKET, libc::SO_BROADCAST, false),
+        ("SO_PRIORITY", SOL_SOCKET, libc::SO_PRIORITY, false),
+        ("SO_MARK", SOL_SOCKET, libc::SO_MARK, false),
+    ]);
+    if v6 {
+        opts.push((
+            "IPV6_UNICAST_HOPS",
+            IPPROTO_IPV6,
+            libc::IPV6_UNICAST_HOPS,
+            false,
+        ));
+        opts.push(("IPV6_TCLASS", IPPROTO_IPV6, libc::IPV6_TCLASS, true));
+    } else {
+        opts.push(("IP_TTL", IPPROTO_IP, libc::IP_TTL, false));
+        opts.push(("IP_TOS", IPPROTO_IP, libc::IP_TOS, true));
+    }
+
+    let pairs: Vec<String> = opts
+        .into_iter()
+        .map(
+            |(label, level, name, hex)| match getsockopt_int(fd, level, name) {
+                Ok(v) if hex => format!("{}={:#04x}", label, v),
+                Ok(v) => format!("{}={}", label, v),
+                Err(_) => format!("{}=?", label),
+            },
+        )
+        .collect();
+    Ok(pairs.join(" "))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn dump_sockopts<S>(_socket: &S) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--dump-sockopts is only supported on Linux",
+    ))
+}
+
+/// Print [`dump_sockopts`] for `socket` on stderr, or why it failed.
+#[cfg(target_os = "linux")]
+pub fn log_sockopts(socket: &impl std::os::fd::AsRawFd) {
+    match dump_sockopts(socket) {
+        Ok(opts) => diag!("sockopts: {}", opts),
+        Err(e) => diag!("sockopts: {}", e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn log_sockopts<S>(socket: &S) {
+    if let Err(e) = dump_sockopts(socket) {
+        diag!("sockopts: {}", e);
+    }
+}
+
+/// Bind a listening UDP socket to `addr` with `SO_REUSEADDR`, as the TCP
+/// listeners have it, and `SO_REUSEPORT` when `reuseport` is set. With
+/// `dual_stack`, `addr` must be an IPv6 one and `IPV6_V6ONLY` is cleared so
+/// the socket also receives IPv4 datagrams, from v4-mapped peers.
+pub fn bind_udp_listener(
+    addr: SocketAddr,
+    reuseport: bool,
+    dual_stack: bool,
+) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    socket.set_reuse_address(true)?;
+    if reuseport {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--reuseport is only supported on Unix",
+        ));
+    }
+    if dual_stack {
+        socket.set_only_v6(false)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Ask the kernel to report each received datagram's TOS byte.
+#[cfg(target_os = "linux")]
+pub fn set_recv_tos(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    setsockopt_int(socket.as_raw_fd(), libc::IPPROTO_IP, libc::IP_RECVTOS, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_recv_tos(_socket: &UdpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reading the received TOS is only supported on Linux",
+    ))
+}
+
+/// Set the TOS byte of packets sent from now on, as the IPv6 traffic class
+/// on an IPv6 socket.
+#[cfg(target_os = "linux")]
+pub fn set_tos(socket: &impl std::os::fd::AsRawFd, tos: u8) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+    if getsockopt_int(fd, libc::SOL_SOCKET, libc::SO_DOMAIN)? == libc::AF_INET6 {
+        setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos.into())?;
+        // IPv4 peers of a dual-stack socket still go by IP_TOS; a v6-only
+        // socket refuses it, which is fine
+        let _ = setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_TOS, tos.into());
+        return Ok(());
+    }
+    setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_TOS, tos.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_tos<S>(_socket: &S, _tos: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "setting the TOS is only supported on Linux",
+    ))
+}
+
+/// Like `recv_from`, also returning the datagram's TOS byte when the kernel
+/// reported one (see [`set_recv_tos`]).
+#[cfg(target_os = "linux")]
+pub async fn recv_from_with_tos(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<u8>)> {
+    use std::os::fd::AsRawFd;
+
+    let fd = socket.as_raw_fd();
+    socket
+        .async_io(tokio::io::Interest::READABLE, || recvmsg_tos(fd, buf))
+        .await
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn recv_from_with_tos(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<u8>)> {
+    let (n, src) = socket.recv_from(buf).await?;
+    Ok((n, src, None))
+}
+
+#[cfg(target_os = "linux")]
+fn recvmsg_tos(
+    fd: std::os::fd::RawFd,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<u8>)> {
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    // SAFETY: all-zero is a valid value for these plain C structs.
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // u64 elements keep the control buffer aligned for cmsghdr
+    let mut control = [0u64; 8];
+    msg.msg_name = (&mut addr as *mut libc::sockaddr_storage).cast();
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control);
+
+    // SAFETY: every pointer in `msg` refers to a live local buffer of the
+    // advertised length.
+    let n = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut tos = None;
+    // SAFETY: the CMSG_* macros only walk the control buffer filled above.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::IPPROTO_IP && (*cmsg).cmsg_type == libc::IP_TOS {
+                tos = Some(*libc::CMSG_DATA(cmsg));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    // SAFETY: the kernel filled `addr` with a sockaddr of the family it names.
+    let src = match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(&addr as *const _ as *const libc::sockaddr_in) };
+            SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+                u16::from_be(sin.sin_port),
+            ))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(&addr as *const _ as *const libc::sockaddr_in6) };
+            SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            ))
+        }
+        family => {
+            return Err(io::Error::other(format!(
+                "unexpected address family {}",
+                family
+            )));
+        }
+    };
+    Ok((n as usize, src, tos))
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt_int(
+    fd: std::os::fd::RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: `value` outlives the call and the length matches its type.
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn getsockopt_int(
+    fd: std::os::fd::RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: both out-pointers are valid for the duration of the call.
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == 0 {
+        Ok(value)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Most idle buffers a [`BufferPool`] keeps; any returned beyond that are
+/// freed.
+const MAX_POOLED_BUFFERS: usize = 16;
+
+/// Receive buffers reused from one connection to the next, so a keep-open
+/// listener serving many short connections does not allocate a fresh
+/// `-O`-sized buffer for each.
+#[derive(Default)]
+pub struct BufferPool {
+    idle: std::sync::Mutex<Vec<Vec<u8>>>,
+    allocations: std::sync::atomic::AtomicU64,
+}
+
+impl BufferPool {
+    /// A buffer of `len` bytes, idle or freshly allocated, that goes back to
+    /// the pool when dropped. Its contents are whatever the last user left.
+    pub fn get(self: &Arc<Self>, len: usize) -> PooledBuffer {
+        let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let buf = match idle {
+            Some(mut buf) if buf.capacity() >= len => {
+                buf.resize(len, 0);
+                buf
+            }
+            _ => {
+                self.allocations.fetch_add(1, Ordering::Relaxed);
+                vec![0u8; len]
+            }
+        };
+        PooledBuffer {
+            buf,
+            pool: self.clone(),
+        }
+    }
+
+    /// How many buffers the pool has had to allocate so far.
+    pub fn allocations(&self) -> u64 {
+        self.allocations.load(Ordering::Relaxed)
+    }
+}
+
+impl std::fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let idle = self.idle.lock().map_or(0, |idle| idle.len());
+        f.debug_struct("BufferPool")
+            .field("idle", &idle)
+            .field("allocations", &self.allocations())
+            .finish()
+    }
+}
+
+/// A buffer on loan from a [`BufferPool`].
+#[derive(Debug)]
+pub struct PooledBuffer {
+    buf: Vec<u8>,
+    pool: Arc<BufferPool>,
+}
+
+impl std::ops::Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl std::ops::DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut idle = self.pool.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < MAX_POOLED_BUFFERS {
+            idle.push(std::mem::take(&mut self.buf));
+        }
+    }
+}
@@ -0,0 +1,231 @@
+/*!
+r-cat/src/pcap.rs
+
+A minimal pcap writer for `--pcap`: every chunk seen on the tee path becomes
+one packet record with synthesized IPv4/IPv6 + TCP headers, so Wireshark can
+open the capture and even follow the stream. Only payload is real; the TCP
+headers carry made-up but consistent sequence numbers and no handshake.
+Every connection of a `-k` listener goes into the same file, told apart by
+its addresses.
+*/
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::diag;
+use crate::tee::{Direction, Tap};
+
+/// Classic pcap magic, microsecond timestamps.
+pub const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// LINKTYPE_RAW: packets start directly with an IPv4 or IPv6 header.
+pub const LINKTYPE_RAW: u32 = 101;
+
+const SNAPLEN: u32 = 262_144;
+const TCP_HEADER_LEN: usize = 20;
+/// Largest payload per record, keeping the IPv4 total length within 16 bits.
+const MAX_SEGMENT: usize = 65_000;
+
+/// Write the pcap global header that starts every capture file.
+fn write_header<W: Write>(out: &mut W) -> io::Result<()> {
+    out.write_all(&PCAP_MAGIC.to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?; // version major
+    out.write_all(&4u16.to_le_bytes())?; // version minor
+    out.write_all(&0i32.to_le_bytes())?; // thiszone
+    out.write_all(&0u32.to_le_bytes())?; // sigfigs
+    out.write_all(&SNAPLEN.to_le_bytes())?;
+    out.write_all(&LINKTYPE_RAW.to_le_bytes())
+}
+
+/// Writes pcap records for one connection between `local` and `peer`.
+pub struct PcapWriter<W: Write> {
+    out: W,
+    stream: Stream,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Start a capture, writing the global header immediately.
+    pub fn new(mut out: W, local: SocketAddr, peer: SocketAddr) -> io::Result<Self> {
+        write_header(&mut out)?;
+        Ok(PcapWriter {
+            out,
+            stream: Stream::new(local, peer),
+        })
+    }
+
+    /// Append `data` as one or more packets travelling in `dir`.
+    pub fn record(&mut self, dir: Direction, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(&self.stream.records(dir, data))?;
+        self.out.flush()
+    }
+}
+
+/// The addresses and sequence numbers of one connection's packets.
+struct Stream {
+    local: SocketAddr,
+    peer: SocketAddr,
+    /// Next sequence number for data we send / data we receive.
+    seq_sent: u32,
+    seq_received: u32,
+}
+
+impl Stream {
+    fn new(local: SocketAddr, peer: SocketAddr) -> Self {
+        Stream {
+            local,
+            peer,
+            seq_sent: 1,
+            seq_received: 1,
+        }
+    }
+
+    /// The records carrying `data` in `dir`, headers included.
+    fn records(&mut self, dir: Direction, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 128);
+        for segment in data.chunks(MAX_SEGMENT) {
+            let packet = self.packet(dir, segment);
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            out.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+            out.extend_from_slice(&now.subsec_micros().to_le_bytes());
+            out.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            out.extend_from_slice(&packet);
+        }
+        out
+    }
+
+    fn packet(&mut self, dir: Direction, payload: &[u8]) -> Vec<u8> {
+        let (src, dst, seq, ack) = match dir {
+            Direction::Sent => (self.local, self.peer, &mut self.seq_sent, self.seq_received),
+            Direction::Received => (self.peer, self.local, &mut self.seq_received, self.seq_sent),
+        };
+
+        let mut tcp = Vec::with_capacity(TCP_HEADER_LEN + payload.len());
+        tcp.extend_from_slice(&src.port().to_be_bytes());
+        tcp.extend_from_slice(&dst.port().to_be_bytes());
+        tcp.extend_from_slice(&seq.to_be_bytes());
+        tcp.extend_from_slice(&ack.to_be_bytes());
+        tcp.push((TCP_HEADER_LEN as u8 / 4) << 4); // data offset
+        tcp.push(0x18); // PSH | ACK
+        tcp.extend_from_slice(&65535u16.to_be_bytes()); // window
+        tcp.extend_from_slice(&[0, 0, 0, 0]); // checksum (unchecked), urgent pointer
+        tcp.extend_from_slice(payload);
+        *seq = seq.wrapping_add(payload.len() as u32);
+
+        let mut packet = ip_header(src.ip(), dst.ip(), tcp.len());
+        packet.extend_from_slice(&tcp);
+        packet
+    }
+}
+
+fn ip_header(src: IpAddr, dst: IpAddr, l4_len: usize) -> Vec<u8> {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut h = Vec::with_capacity(20);
+            h.push(0x45); // version 4, IHL 5
+            h.push(0);
+            h.extend_from_slice(&((20 + l4_len) as u16).to_be_bytes());
+            h.extend_from_slice(&[0, 0, 0x40, 0]); // id, DF
+            h.push(64); // TTL
+            h.push(6); // TCP
+            h.extend_from_slice(&[0, 0]); // checksum, filled below
+            h.extend_from_slice(&src.octets());
+            h.extend_from_slice(&dst.octets());
+            let sum = ipv4_checksum(&h);
+            h[10..12].copy_from_slice(&sum.to_be_bytes());
+            h
+        }
+        (src, dst) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            let mut h = Vec::with_capacity(40);
+            h.extend_from_slice(&[0x60, 0, 0, 0]); // version 6
+            h.extend_from_slice(&(l4_len as u16).to_be_bytes());
+            h.push(6); // next header: TCP
+            h.push(64); // hop limit
+            h.extend_from_slice(&to_v6(src).octets());
+            h.extend_from_slice(&to_v6(dst).octets());
+            h
+        }
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|w| u32::from(u16::from_be_bytes([w[0], w[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// A capture file opened up front and shared by every connection of the
+/// process, so a `-k` listener keeps them all in one capture. Write errors
+/// are reported once, then ignored.
+#[derive(Debug)]
+pub struct PcapFile {
+    file: Mutex<File>,
+    failed: AtomicBool,
+}
+
+impl PcapFile {
+    /// Create (truncating) the capture file at `path` and write its header.
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let mut file = File::create(path)
+            .map_err(|e| anyhow::anyhow!("cannot create pcap file '{}': {}", path.display(), e))?;
+        write_header(&mut file)?;
+        Ok(PcapFile {
+            file: Mutex::new(file),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// Append whole records, so concurrent connections never interleave
+    /// inside one.
+    fn append(&self, records: &[u8]) {
+        let res = match self.file.lock() {
+            Ok(mut file) => file.write_all(records),
+            Err(_) => return,
+        };
+        if let Err(e) = res
+            && !self.failed.swap(true, Ordering::Relaxed)
+        {
+            diag!("pcap: write failed, capture is incomplete: {}", e);
+        }
+    }
+}
+
+/// A tee tap adding one connection to a [`PcapFile`].
+pub struct PcapTap {
+    file: Arc<PcapFile>,
+    stream: Mutex<Stream>,
+}
+
+impl PcapTap {
+    pub fn new(file: Arc<PcapFile>, local: SocketAddr, peer: SocketAddr) -> Self {
+        PcapTap {
+            file,
+            stream: Mutex::new(Stream::new(local, peer)),
+        }
+    }
+}
+
+impl Tap for PcapTap {
+    fn record(&self, dir: Direction, data: &[u8]) {
+        let records = match self.stream.lock() {
+            Ok(mut stream) => stream.records(dir, data),
+            Err(_) => return,
+        };
+        self.file.append(&records);
+    }
+}
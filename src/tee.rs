@@ -0,0 +1,243 @@
+/*!
+r-cat/src/tee.rs
+
+The tee path: observers ("taps") that see a copy of every byte a session puts
+on the wire or takes off it, without being able to change it.
+
+Outbound data is observed by wrapping the socket writer in [`TeeWriter`]
+(below any send filters, so taps see what was actually sent); inbound data is
+reported by the receive loop via [`Tee::record`].
+
+Besides the session counters, taps write `--pcap` captures, `--annotate`
+traces, `-vv` chunk sizes, `--log-file` logs and the
+`--tee-sent-fd`/`--tee-recv-fd` copies.
+*/
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+
+use tokio::io::AsyncWrite;
+
+use crate::diag;
+
+/// Which way a chunk of data travelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From us to the peer.
+    Sent,
+    /// From the peer to us.
+    Received,
+}
+
+/// An observer of session traffic.
+pub trait Tap: Send + Sync {
+    fn record(&self, dir: Direction, data: &[u8]);
+
+    /// Whether the tap looks at the bytes themselves rather than just their
+    /// count; zero-copy paths that never see the data are only taken if no
+    /// tap does.
+    fn needs_data(&self) -> bool {
+        true
+    }
+}
+
+/// A cheaply cloneable set of taps shared by both directions of a session.
+#[derive(Clone, Default)]
+pub struct Tee {
+    taps: Vec<Arc<dyn Tap>>,
+}
+
+impl Tee {
+    pub fn add(&mut self, tap: Arc<dyn Tap>) {
+        self.taps.push(tap);
+    }
+
+    pub fn record(&self, dir: Direction, data: &[u8]) {
+        for tap in &self.taps {
+            tap.record(dir, data);
+        }
+    }
+
+    pub fn needs_data(&self) -> bool {
+        self.taps.iter().any(|tap| tap.needs_data())
+    }
+}
+
+/// Echo each chunk on stderr as `>> N bytes: ...` (sent) or `<< N bytes: ...`
+/// (received), with non-printable bytes escaped (`--annotate`).
+#[derive(Debug, Default)]
+pub struct Annotate;
+
+impl Tap for Annotate {
+    fn record(&self, dir: Direction, data: &[u8]) {
+        let marker = match dir {
+            Direction::Sent => ">>",
+            Direction::Received => "<<",
+        };
+        diag!("{} {} bytes: {}", marker, data.len(), data.escape_ascii());
+    }
+}
+
+/// Report the size of each chunk on stderr, `Sent N bytes` or `Received N
+/// bytes` (`-vv`). Unlike [`Annotate`], the bytes themselves are not shown.
+#[derive(Debug, Default)]
+pub struct ChunkSizes;
+
+impl Tap for ChunkSizes {
+    fn record(&self, dir: Direction, data: &[u8]) {
+        let verb = match dir {
+            Direction::Sent => "Sent",
+            Direction::Received => "Received",
+        };
+        diag!("{} {} bytes", verb, data.len());
+    }
+
+    fn needs_data(&self) -> bool {
+        false
+    }
+}
+
+/// Append both directions of every session to a file (`--log-file`), each
+/// line marked `> ` (sent) or `< ` (received). A chunk ending mid-line is
+/// given a newline in the log so the next marker starts a line of its own.
+/// Write errors are reported once, then ignored.
+#[derive(Debug)]
+pub struct LogTap {
+    file: Mutex<File>,
+    failed: AtomicBool,
+}
+
+impl LogTap {
+    /// Open `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("cannot open log file '{}': {}", path.display(), e))?;
+        Ok(LogTap {
+            file: Mutex::new(file),
+            failed: AtomicBool::new(false),
+        })
+    }
+}
+
+impl Tap for LogTap {
+    fn record(&self, dir: Direction, data: &[u8]) {
+        let marker: &[u8] = match dir {
+            Direction::Sent => b"> ",
+            Direction::Received => b"< ",
+        };
+        let mut entry = Vec::with_capacity(data.len() + 4);
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            entry.extend_from_slice(marker);
+            entry.extend_from_slice(line);
+        }
+        if !entry.ends_with(b"\n") {
+            entry.push(b'\n');
+        }
+        // One write per chunk, flushed, so `tail -f` sees whole entries
+        let res = match self.file.lock() {
+            Ok(mut file) => file.write_all(&entry).and_then(|_| file.flush()),
+            Err(_) => return,
+        };
+        if let Err(e) = res
+            && !self.failed.swap(true, Ordering::Relaxed)
+        {
+            diag!("log: write failed, log is incomplete: {}", e);
+        }
+    }
+}
+
+/// Copy one direction of the session to an inherited file descriptor
+/// (`--tee-sent-fd`, `--tee-recv-fd`); write errors are reported once, then
+/// ignored.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct FdTap {
+    dir: Direction,
+    fd: i32,
+    failed: AtomicBool,
+}
+
+#[cfg(unix)]
+impl FdTap {
+    /// Copy chunks travelling in `dir` to `fd`, which stays owned by the caller.
+    pub fn new(dir: Direction, fd: i32) -> Self {
+        FdTap {
+            dir,
+            fd,
+            failed: AtomicBool::new(false),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Tap for FdTap {
+    fn record(&self, dir: Direction, data: &[u8]) {
+        use std::os::fd::FromRawFd;
+
+        if dir != self.dir {
+            return;
+        }
+        // SAFETY: the fd was inherited from our parent; ManuallyDrop leaves it
+        // open for the next chunk and for whoever else holds it.
+        let mut file = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(self.fd) });
+        if let Err(e) = file.write_all(data)
+            && !self.failed.swap(true, Ordering::Relaxed)
+        {
+            diag!(
+                "tee: write to fd {} failed, copy is incomplete: {}",
+                self.fd,
+                e
+            );
+        }
+    }
+}
+
+/// An `AsyncWrite` that reports every accepted byte as [`Direction::Sent`].
+pub struct TeeWriter<W> {
+    inner: W,
+    tee: Tee,
+}
+
+impl<W> TeeWriter<W> {
+    pub fn new(inner: W, tee: Tee) -> Self {
+        TeeWriter { inner, tee }
+    }
+
+    /// The wrapped writer; bytes written around it are not reported.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for TeeWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.tee.record(Direction::Sent, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -1,7 +1,38 @@
+use std::process::Stdio;
+use std::time::Duration;
+
 use clap::Parser;
 use r_cat::cli;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, UdpSocket};
+use tokio::process::Command;
+use tokio::time;
+
+/// Command for the compiled `r-cat` binary, killed if the test drops it.
+fn rcat() -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_r-cat"));
+    cmd.kill_on_drop(true);
+    cmd
+}
+
+/// Grab a currently free local TCP port for a listener spawned by the test.
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .map(|a| a.port())
+        .expect("free port")
+}
+
+/// Connect to a freshly spawned `r-cat -l`, retrying until it is bound.
+async fn connect_retry(port: u16) -> anyhow::Result<tokio::net::TcpStream> {
+    for _ in 0..50 {
+        if let Ok(s) = tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+            return Ok(s);
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    anyhow::bail!("listener on port {} never came up", port)
+}
 
 #[tokio::test]
 async fn tcp_echo_integration() -> anyhow::Result<()> {
@@ -53,12 +84,4331 @@ async fn udp_echo_integration() -> anyhow::Result<()> {
 
 #[test]
 fn cli_parsing_integration() {
-    let args = cli::Args::parse_from(&["r-cat", "-u", "-l", "-p", "1234"]);
+    let args = cli::Args::parse_from(["r-cat", "-u", "-l", "-p", "1234"]);
     assert!(args.udp);
     assert!(args.listen);
     assert_eq!(args.source_port, Some(1234));
+    assert_eq!(args.verbose, 0);
 
-    let args2 = cli::Args::parse_from(&["r-cat", "example.com", "80"]);
+    let args2 = cli::Args::parse_from(["r-cat", "-vv", "example.com", "80"]);
     assert_eq!(args2.destination.as_deref(), Some("example.com"));
-    assert_eq!(args2.port, Some(80));
+    assert_eq!(args2.port.as_deref(), Some("80"));
+    assert_eq!(args2.verbose, 2);
+
+    let args3 = cli::Args::parse_from(["r-cat", "-v", "-v", "-v", "-Q", "example.com", "80"]);
+    assert_eq!(args3.verbose, 3);
+    let config = r_cat::net::Config::try_from(&args3).expect("config");
+    assert_eq!(config.verbosity, 0);
+    assert!(!config.verbose());
+}
+
+#[tokio::test]
+async fn eof_marker_is_sent_last() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.expect("read");
+        received
+    });
+
+    let mut child = rcat()
+        .args(["--eof-marker", ".\\r\\n", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"hello\n").await?;
+    drop(stdin);
+
+    let received = time::timeout(Duration::from_secs(5), server).await??;
+    assert_eq!(received, b"hello\n.\r\n");
+    time::timeout(Duration::from_secs(5), child.wait()).await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn eof_marker_skipped_when_peer_closes_first() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        let mut received = vec![0u8; 6];
+        socket.read_exact(&mut received).await?;
+        // Hang up while the client's input is still open
+        socket.shutdown().await?;
+        socket.read_to_end(&mut received).await?;
+        anyhow::Ok(received)
+    });
+
+    let mut child = rcat()
+        .args(["--eof-marker", ".\\r\\n", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"hello\n").await?;
+
+    let received = time::timeout(Duration::from_secs(5), server).await???;
+    assert_eq!(received, b"hello\n");
+    time::timeout(Duration::from_secs(5), child.wait()).await??;
+    drop(stdin);
+    Ok(())
+}
+
+#[test]
+fn unescape_handles_common_escapes() {
+    assert_eq!(cli::unescape(".\\r\\n").unwrap(), b".\r\n");
+    assert_eq!(cli::unescape("\\x00\\xff\\\\").unwrap(), b"\x00\xff\\");
+    assert!(cli::unescape("bad\\").is_err());
+    assert!(cli::unescape("\\xZZ").is_err());
+}
+
+#[tokio::test]
+async fn idle_banner_sent_after_client_silence() -> anyhow::Result<()> {
+    let port = free_port();
+    let _child = rcat()
+        .args(["-l", "-p", &port.to_string()])
+        .args(["--idle-banner", "> ", "--idle-banner-after", "0.3"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+
+    let mut client = connect_retry(port).await?;
+    let started = time::Instant::now();
+    let mut banner = [0u8; 2];
+    time::timeout(Duration::from_secs(5), client.read_exact(&mut banner)).await??;
+    assert_eq!(&banner, b"> ");
+    assert!(started.elapsed() >= Duration::from_millis(250));
+
+    // Activity resets the timer, so the next banner comes a full period later
+    client.write_all(b"ping\n").await?;
+    let sent = time::Instant::now();
+    time::timeout(Duration::from_secs(5), client.read_exact(&mut banner)).await??;
+    assert_eq!(&banner, b"> ");
+    assert!(sent.elapsed() >= Duration::from_millis(250));
+    Ok(())
+}
+
+#[test]
+fn idle_banner_requires_listen() {
+    assert!(cli::Args::try_parse_from(["r-cat", "--idle-banner", "x", "host", "1"]).is_err());
+    assert!(cli::Args::try_parse_from(["r-cat", "-l", "--idle-banner", "x", "-p", "1"]).is_ok());
+}
+
+#[tokio::test]
+async fn panicking_io_task_fails_session() {
+    let panicking = tokio::spawn(async {
+        time::sleep(Duration::from_millis(20)).await;
+        panic!("filter bug");
+    });
+    // The peer would run forever if the panic went unnoticed
+    let stuck = tokio::spawn(async {
+        time::sleep(Duration::from_secs(3600)).await;
+    });
+
+    let res = time::timeout(
+        Duration::from_secs(5),
+        r_cat::net::join_tasks(panicking, stuck, false),
+    )
+    .await
+    .expect("join_tasks must not hang");
+    let err = res.expect_err("panic must surface as an error");
+    assert!(err.to_string().contains("panicked"), "{}", err);
+}
+
+#[tokio::test]
+async fn quiet_silences_failed_connect() -> anyhow::Result<()> {
+    // Nothing listens on this port, so the connect is refused
+    let port = free_port().to_string();
+
+    let out = rcat()
+        .args(["-v", "--quiet", "127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+    assert!(
+        out.stderr.is_empty(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    // Without --quiet the same failure is explained on stderr
+    let out = rcat()
+        .args(["127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+    assert!(!out.stderr.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn require_tls_refuses_plaintext() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let out = rcat()
+        .args(["--require-tls", "127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--require-tls"));
+    // It must fail before connecting at all
+    let accepted = time::timeout(Duration::from_millis(200), listener.accept()).await;
+    assert!(accepted.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn annotate_marks_both_directions() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        let mut buf = [0u8; 64];
+        let n = socket.read(&mut buf).await?;
+        socket.write_all(b"pong\r\n").await?;
+        anyhow::Ok(buf[..n].to_vec())
+    });
+
+    let mut child = rcat()
+        .args(["--annotate", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"ping\n").await?;
+
+    assert_eq!(
+        time::timeout(Duration::from_secs(5), server).await???,
+        b"ping\n"
+    );
+    drop(stdin);
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    // stdout carries only the data; the annotations go to stderr
+    assert_eq!(out.stdout, b"pong\r\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains(">> 5 bytes: ping\\n"), "{}", stderr);
+    assert!(stderr.contains("<< 6 bytes: pong\\r\\n"), "{}", stderr);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn tee_sent_fd_copies_outbound_data() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let copy = dir.path().join("sent");
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        let mut received = Vec::new();
+        socket.write_all(b"reply\n").await?;
+        socket.read_to_end(&mut received).await?;
+        anyhow::Ok(received)
+    });
+
+    // The shell opens fd 3 on the copy file, then becomes r-cat
+    let mut child = Command::new("sh")
+        .args([
+            "-c",
+            r#"exec "$0" --tee-sent-fd 3 127.0.0.1 "$1" 3>"$2""#,
+            env!("CARGO_BIN_EXE_r-cat"),
+            &port,
+        ])
+        .arg(&copy)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"hello over fd 3\n").await?;
+    drop(stdin);
+
+    assert_eq!(
+        time::timeout(Duration::from_secs(5), server).await???,
+        b"hello over fd 3\n"
+    );
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success());
+    // Received data still goes to stdout; only the sent side is copied
+    assert_eq!(out.stdout, b"reply\n");
+    assert_eq!(std::fs::read(&copy)?, b"hello over fd 3\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn tee_fd_must_be_open() -> anyhow::Result<()> {
+    let out = rcat()
+        .args(["--tee-recv-fd", "97", "127.0.0.1", "9"])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("file descriptor 97 is not open"));
+    Ok(())
+}
+
+/// An `AsyncWrite` sink counting how many writes reach it.
+#[derive(Default)]
+struct CountingSink {
+    data: Vec<u8>,
+    writes: usize,
+}
+
+impl tokio::io::AsyncWrite for CountingSink {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        this.writes += 1;
+        this.data.extend_from_slice(buf);
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn flush_interval_batches_small_writes() -> anyhow::Result<()> {
+    use r_cat::flush::TimedFlush;
+
+    let burst: Vec<Vec<u8>> = (0..100)
+        .map(|i| format!("line {}\n", i).into_bytes())
+        .collect();
+
+    let mut direct = TimedFlush::new(CountingSink::default(), None);
+    for chunk in &burst {
+        direct.write_all(chunk).await?;
+    }
+    direct.flush().await?;
+
+    let mut batched = TimedFlush::new(CountingSink::default(), Some(Duration::from_secs(60)));
+    for chunk in &burst {
+        batched.write_all(chunk).await?;
+    }
+    assert!(batched.deadline().is_some());
+    assert_eq!(batched.get_ref().writes, 0);
+    batched.flush().await?;
+    assert!(batched.deadline().is_none());
+
+    assert_eq!(direct.get_ref().writes, 100);
+    assert_eq!(batched.get_ref().writes, 1);
+    assert_eq!(direct.get_ref().data, batched.get_ref().data);
+    Ok(())
+}
+
+#[tokio::test]
+async fn flush_interval_delivers_quiet_tail() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let mut child = rcat()
+        .args(["--flush-interval", "50", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let (mut socket, _peer) = listener.accept().await?;
+    for i in 0..20 {
+        socket.write_all(format!("{}\n", i).as_bytes()).await?;
+    }
+
+    // The connection stays open, so only the timer can release the output
+    let mut stdout = BufReader::new(child.stdout.take().expect("stdout"));
+    let mut line = String::new();
+    for i in 0..20 {
+        line.clear();
+        time::timeout(Duration::from_secs(5), stdout.read_line(&mut line)).await??;
+        assert_eq!(line, format!("{}\n", i));
+    }
+    drop(socket);
+    drop(child.stdin.take());
+    time::timeout(Duration::from_secs(5), child.wait()).await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn repeat_until_match_polls_until_healthy() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    // Report "starting" twice, then healthy
+    let server = tokio::spawn(async move {
+        let mut requests = Vec::new();
+        for attempt in 1..=3 {
+            let (mut socket, _peer) = listener.accept().await?;
+            let mut request = Vec::new();
+            socket.read_to_end(&mut request).await?;
+            requests.push(request);
+            let reply: &[u8] = if attempt < 3 {
+                b"STARTING\n"
+            } else {
+                b"status: OK\n"
+            };
+            socket.write_all(reply).await?;
+        }
+        anyhow::Ok(requests)
+    });
+
+    let mut child = rcat()
+        .args(["--repeat-until-match", "OK", "--repeat-delay", "0.05"])
+        .args(["127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"GET /health\n").await?;
+    drop(stdin);
+
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"status: OK\n");
+    let requests = time::timeout(Duration::from_secs(5), server).await???;
+    assert_eq!(requests, vec![b"GET /health\n".to_vec(); 3]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn input_template_numbers_each_attempt() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let template = dir.path().join("req.tmpl");
+    std::fs::write(&template, "GET /{{i}} id={{i}}\n")?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let mut requests = Vec::new();
+        for attempt in 1..=3 {
+            let (mut socket, _peer) = listener.accept().await?;
+            let mut request = Vec::new();
+            socket.read_to_end(&mut request).await?;
+            requests.push(String::from_utf8(request)?);
+            let reply: &[u8] = if attempt < 3 { b"WAIT\n" } else { b"OK\n" };
+            socket.write_all(reply).await?;
+        }
+        anyhow::Ok(requests)
+    });
+
+    let out = time::timeout(
+        Duration::from_secs(5),
+        rcat()
+            .args(["--repeat-until-match", "OK", "--repeat-delay", "0.05", "-C"])
+            .arg("--input-template")
+            .arg(&template)
+            .args(["127.0.0.1", &port])
+            .stdin(Stdio::null())
+            .output(),
+    )
+    .await??;
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(out.stdout, b"OK\n");
+    // Filled in afresh for each attempt, then filtered like any input
+    let requests = time::timeout(Duration::from_secs(5), server).await???;
+    assert_eq!(
+        requests,
+        ["GET /1 id=1\r\n", "GET /2 id=2\r\n", "GET /3 id=3\r\n"]
+    );
+
+    let args = cli::Args::try_parse_from(["r-cat", "--input-template", "t", "h", "1"]);
+    assert!(args.is_err(), "needs --repeat-until-match");
+    Ok(())
+}
+
+#[tokio::test]
+async fn repeat_max_gives_up() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _peer)) = listener.accept().await {
+            let _ = socket.write_all(b"STARTING\n").await;
+        }
+    });
+
+    let out = time::timeout(
+        Duration::from_secs(5),
+        rcat()
+            .args(["--repeat-until-match", "OK", "--repeat-delay", "0.05"])
+            .args(["--repeat-max", "2", "127.0.0.1", &port])
+            .stdin(Stdio::null())
+            .output(),
+    )
+    .await??;
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("after 2 attempts"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn connect_source_cycle_moves_through_the_range() -> anyhow::Result<()> {
+    // Ports seen by a server that never says OK, over `attempts` attempts
+    async fn source_ports(extra: &[&str], range: &str, attempts: u32) -> anyhow::Result<Vec<u16>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port().to_string();
+        let server = tokio::spawn(async move {
+            let mut ports = Vec::new();
+            for _ in 0..attempts {
+                let (mut socket, peer) = listener.accept().await?;
+                ports.push(peer.port());
+                let mut request = Vec::new();
+                socket.read_to_end(&mut request).await?;
+            }
+            anyhow::Ok(ports)
+        });
+        let out = time::timeout(
+            Duration::from_secs(5),
+            rcat()
+                .args(["--repeat-until-match", "OK", "--repeat-delay", "0.05"])
+                .args(["--repeat-max", &attempts.to_string()])
+                .args(["--source-port-range", range, "--connect-source-cycle"])
+                .args(extra)
+                .args(["127.0.0.1", &port])
+                .stdin(Stdio::null())
+                .output(),
+        )
+        .await??;
+        assert!(
+            String::from_utf8_lossy(&out.stderr).contains("attempts"),
+            "{:?}",
+            out
+        );
+        time::timeout(Duration::from_secs(5), server).await??
+    }
+
+    assert_eq!(
+        source_ports(&[], "47400-47402", 3).await?,
+        [47400, 47401, 47402]
+    );
+    // -r shuffles the order, still using each port once per round
+    let mut ports = source_ports(&["-r"], "47410-47414", 5).await?;
+    ports.sort_unstable();
+    assert_eq!(ports, [47410, 47411, 47412, 47413, 47414]);
+
+    let args = cli::Args::try_parse_from([
+        "r-cat",
+        "--repeat-until-match",
+        "OK",
+        "--connect-source-cycle",
+        "h",
+        "1",
+    ]);
+    assert!(args.is_err(), "needs --source-port-range");
+    Ok(())
+}
+
+#[tokio::test]
+async fn connect_hooks_see_peer_address() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let connected = dir.path().join("connected");
+    let disconnected = dir.path().join("disconnected");
+    let port = free_port();
+
+    let mut child = rcat()
+        .args(["-l", "-p", &port.to_string()])
+        .arg("--on-connect")
+        .arg(format!("echo \"$RCAT_PEER\" > '{}'", connected.display()))
+        .arg("--on-disconnect")
+        .arg(format!(
+            "echo \"$RCAT_PEER\" > '{}'",
+            disconnected.display()
+        ))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let client = connect_retry(port).await?;
+    let me = client.local_addr()?.to_string();
+    drop(client);
+    time::timeout(Duration::from_secs(5), child.wait()).await??;
+
+    // The hooks run detached, so give them a moment to land
+    for path in [&connected, &disconnected] {
+        let mut contents = String::new();
+        for _ in 0..50 {
+            contents = std::fs::read_to_string(path).unwrap_or_default();
+            if contents.ends_with('\n') {
+                break;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(contents.trim_end(), me, "{}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn socket_hook_option_survives_connect() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+
+    let hook = "python3 -c 'import os, socket; \
+        s = socket.socket(fileno=int(os.environ[\"RCAT_SOCKET_FD\"])); \
+        s.setsockopt(socket.SOL_SOCKET, socket.SO_KEEPALIVE, 1); s.detach()'";
+    let mut child = rcat()
+        .args(["--socket-hook", hook, "127.0.0.1", &port.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let (_socket, peer) = time::timeout(Duration::from_secs(5), listener.accept()).await??;
+
+    // An armed keepalive timer ("tr" column 2) shows SO_KEEPALIVE is set on
+    // r-cat's end of the connection
+    let local = format!("0100007F:{:04X}", peer.port());
+    let remote = format!("0100007F:{:04X}", port);
+    let table = std::fs::read_to_string("/proc/net/tcp")?;
+    let entry = table
+        .lines()
+        .map(|l| l.split_whitespace().collect::<Vec<_>>())
+        .find(|f| f.len() > 5 && f[1] == local && f[2] == remote)
+        .expect("client socket in /proc/net/tcp");
+    assert!(entry[5].starts_with("02:"), "{:?}", entry);
+
+    drop(child.stdin.take());
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn dump_sockopts_shows_applied_options() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    tokio::spawn(async move { drop(listener.accept().await) });
+
+    // Have the hook set options r-cat itself leaves alone
+    let hook = "python3 -c 'import os, socket; \
+        s = socket.socket(fileno=int(os.environ[\"RCAT_SOCKET_FD\"])); \
+        s.setsockopt(socket.SOL_SOCKET, socket.SO_KEEPALIVE, 1); \
+        s.setsockopt(socket.IPPROTO_IP, socket.IP_TOS, 0x10); s.detach()'";
+    let out = rcat()
+        .args(["--dump-sockopts", "--socket-hook", hook, "127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let dump = stderr
+        .lines()
+        .find_map(|l| l.strip_prefix("sockopts: "))
+        .unwrap_or_else(|| panic!("no sockopts line in {:?}", stderr));
+    let opts: Vec<&str> = dump.split(' ').collect();
+    assert!(opts.contains(&"SO_KEEPALIVE=1"), "{}", dump);
+    assert!(opts.contains(&"IP_TOS=0x10"), "{}", dump);
+    assert!(opts.contains(&"TCP_NODELAY=0"), "{}", dump);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn no_delay_sets_tcp_nodelay_both_ways() -> anyhow::Result<()> {
+    fn sockopts(stderr: &[u8]) -> String {
+        let stderr = String::from_utf8_lossy(stderr);
+        stderr
+            .lines()
+            .find_map(|l| l.strip_prefix("sockopts: "))
+            .unwrap_or_else(|| panic!("no sockopts line in {:?}", stderr))
+            .to_string()
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    tokio::spawn(async move { drop(listener.accept().await) });
+    let out = rcat()
+        .args(["-D", "--dump-sockopts", "127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(out.status.success());
+    let dump = sockopts(&out.stderr);
+    assert!(dump.split(' ').any(|o| o == "TCP_NODELAY=1"), "{}", dump);
+
+    let port = free_port();
+    let child = rcat()
+        .args(["-l", "-D", "--dump-sockopts", "-p", &port.to_string()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    drop(connect_retry(port).await?);
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    let dump = sockopts(&out.stderr);
+    assert!(dump.split(' ').any(|o| o == "TCP_NODELAY=1"), "{}", dump);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_client_echoes_through_socket() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("echo.sock");
+    let listener = tokio::net::UnixListener::bind(&path)?;
+
+    tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        let (mut reader, mut writer) = socket.split();
+        tokio::io::copy(&mut reader, &mut writer).await?;
+        anyhow::Ok(())
+    });
+
+    let mut child = rcat()
+        .arg("-U")
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"hello over a unix socket\n").await?;
+    drop(stdin);
+
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"hello over a unix socket\n");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_listen_replaces_stale_socket_and_cleans_up() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("listen.sock");
+    // A socket file left behind by a listener that went away
+    drop(std::os::unix::net::UnixListener::bind(&path)?);
+
+    let child = rcat()
+        .args(["-l", "-U"])
+        .arg(&path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut client = None;
+    for _ in 0..50 {
+        if let Ok(s) = tokio::net::UnixStream::connect(&path).await {
+            client = Some(s);
+            break;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    let mut client = client.expect("listener never came up");
+    client.write_all(b"ping\n").await?;
+    client.shutdown().await?;
+
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"ping\n");
+    assert!(!path.exists(), "socket file left behind");
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn unix_listen_prints_peer_credentials() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("cred.sock");
+
+    let child = rcat()
+        .args(["-l", "-U", "--print-peer-cred"])
+        .arg(&path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut client = None;
+    for _ in 0..50 {
+        if let Ok(s) = tokio::net::UnixStream::connect(&path).await {
+            client = Some(s);
+            break;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    let mut client = client.expect("listener never came up");
+    client.shutdown().await?;
+
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    // SAFETY: getuid, getgid and getpid cannot fail
+    let (uid, gid, pid) = unsafe { (libc::getuid(), libc::getgid(), libc::getpid()) };
+    assert!(
+        stderr.contains(&format!(
+            "Peer credentials: uid={} gid={} pid={}",
+            uid, gid, pid
+        )),
+        "{}",
+        stderr
+    );
+
+    let args = cli::Args::try_parse_from(["r-cat", "-l", "--print-peer-cred", "-p", "1"]);
+    assert!(args.is_err(), "needs -U");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_listen_refuses_to_replace_other_files() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("notes.txt");
+    std::fs::write(&path, "keep me")?;
+
+    let out = rcat()
+        .args(["-l", "-U"])
+        .arg(&path)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("is not a socket"));
+    assert_eq!(std::fs::read_to_string(&path)?, "keep me");
+    Ok(())
+}
+
+#[tokio::test]
+async fn keep_open_accepts_successive_connections() -> anyhow::Result<()> {
+    let port = free_port();
+    let mut child = rcat()
+        .args(["-l", "-k", "-p", &port.to_string()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdout = BufReader::new(child.stdout.take().expect("stdout"));
+
+    for payload in ["first\n", "second\n"] {
+        let mut client = connect_retry(port).await?;
+        client.write_all(payload.as_bytes()).await?;
+        client.shutdown().await?;
+        // Our end finishes once the listener is done with the connection
+        let mut rest = Vec::new();
+        time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest)).await??;
+
+        let mut line = String::new();
+        time::timeout(Duration::from_secs(5), stdout.read_line(&mut line)).await??;
+        assert_eq!(line, payload);
+    }
+
+    // Still listening after the second connection
+    assert!(child.try_wait()?.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn keep_open_listener_reuses_receive_buffers() -> anyhow::Result<()> {
+    use r_cat::net::{Config, tcp};
+
+    let port = free_port();
+    let config = Config {
+        timeout: Some(Duration::from_secs(5)),
+        keep_open: true,
+        max_conns: Some(20),
+        echo_server: true,
+        ..Config::default()
+    };
+    let buffers = config.buffers.clone();
+    let server = tokio::spawn(async move { tcp::listen(port, &config).await });
+    for i in 0..20 {
+        let mut client = connect_retry(port).await?;
+        let msg = format!("connection {}\n", i);
+        client.write_all(msg.as_bytes()).await?;
+        client.shutdown().await?;
+        let mut echoed = Vec::new();
+        time::timeout(Duration::from_secs(5), client.read_to_end(&mut echoed)).await??;
+        assert_eq!(echoed, msg.as_bytes());
+    }
+    time::timeout(Duration::from_secs(5), server).await???;
+    // One buffer served every connection in turn
+    assert_eq!(buffers.allocations(), 1);
+
+    // Buffers in use at the same time are separate allocations
+    let (a, b) = (buffers.get(16), buffers.get(16));
+    assert_eq!(buffers.allocations(), 2);
+    drop((a, b));
+    let _c = buffers.get(8);
+    assert_eq!(buffers.allocations(), 2);
+    Ok(())
+}
+
+#[cfg(feature = "exec")]
+#[tokio::test]
+async fn exec_runs_a_fresh_command_per_connection() -> anyhow::Result<()> {
+    let port = free_port();
+    let mut child = rcat()
+        .args([
+            "-l",
+            "-k",
+            "-p",
+            &port.to_string(),
+            "--exec",
+            "echo pid $$; tr a-z A-Z",
+        ])
+        .stdin(Stdio::null())
+        .spawn()?;
+
+    let mut pids = Vec::new();
+    for word in ["first", "second"] {
+        let mut client = connect_retry(port).await?;
+        client.write_all(format!("{}\n", word).as_bytes()).await?;
+        client.shutdown().await?;
+        // The connection ends once the command exits
+        let mut reply = String::new();
+        time::timeout(Duration::from_secs(5), client.read_to_string(&mut reply)).await??;
+        let (pid, rest) = reply.split_once('\n').expect("pid line");
+        assert_eq!(rest, format!("{}\n", word.to_uppercase()));
+        pids.push(pid.to_string());
+    }
+    assert_ne!(pids[0], pids[1]);
+    child.kill().await?;
+
+    // The client side hands the connection to the command the same way
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        socket.write_all(b"ping\n").await?;
+        socket.shutdown().await?;
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await?;
+        anyhow::Ok(received)
+    });
+    let status = rcat()
+        .args(["--exec", "sed s/ping/pong/", "127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .status()
+        .await?;
+    assert!(status.success());
+    assert_eq!(
+        time::timeout(Duration::from_secs(5), server).await???,
+        b"pong\n"
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "exec"))]
+#[tokio::test]
+async fn exec_needs_the_build_feature() -> anyhow::Result<()> {
+    let out = rcat()
+        .args(["--exec", "cat", "127.0.0.1", "9"])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("without the `exec` feature"));
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn source_address_is_seen_by_peer() -> anyhow::Result<()> {
+    // All of 127/8 is local on Linux, so 127.0.0.2 can be bound without setup
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let mut child = rcat()
+        .args(["-s", "127.0.0.2", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let (_socket, peer) = time::timeout(Duration::from_secs(5), listener.accept()).await??;
+    assert_eq!(peer.ip().to_string(), "127.0.0.2");
+
+    let udp = UdpSocket::bind("127.0.0.1:0").await?;
+    let udp_port = udp.local_addr()?.port().to_string();
+    let mut udp_child = rcat()
+        .args(["-u", "-s", "127.0.0.2", "127.0.0.1", &udp_port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    udp_child
+        .stdin
+        .as_mut()
+        .expect("stdin")
+        .write_all(b"hi")
+        .await?;
+    let mut buf = [0u8; 16];
+    let (_, from) = time::timeout(Duration::from_secs(5), udp.recv_from(&mut buf)).await??;
+    assert_eq!(from.ip().to_string(), "127.0.0.2");
+
+    drop(child.stdin.take());
+    Ok(())
+}
+
+#[tokio::test]
+async fn source_address_family_must_match() -> anyhow::Result<()> {
+    let out = rcat()
+        .args(["-s", "::1", "127.0.0.1", "9"])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+    assert!(
+        String::from_utf8_lossy(&out.stderr).contains("different IP families"),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn ipv4_flag_picks_the_a_record() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    let server = tokio::spawn(async move {
+        let (_socket, peer) = listener.accept().await?;
+        anyhow::Ok(peer)
+    });
+
+    let out = rcat()
+        .args(["-4", "localhost", &port])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(out.status.success());
+    let peer = time::timeout(Duration::from_secs(5), server).await???;
+    assert_eq!(peer.ip(), std::net::Ipv4Addr::LOCALHOST);
+    Ok(())
+}
+
+#[tokio::test]
+async fn ipv6_flag_rejects_ipv4_destination() -> anyhow::Result<()> {
+    for proto in [None, Some("-u")] {
+        let out = rcat()
+            .args(proto)
+            .args(["-6", "127.0.0.1", "9"])
+            .stdin(Stdio::null())
+            .output()
+            .await?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("has no IPv6 address"), "{}", stderr);
+    }
+    assert!(cli::Args::try_parse_from(["r-cat", "-4", "-6", "localhost", "80"]).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn numeric_refuses_to_resolve_names() -> anyhow::Result<()> {
+    for proto in [None, Some("-u")] {
+        let out = rcat()
+            .args(proto)
+            .args(["-n", "localhost", "9"])
+            .stdin(Stdio::null())
+            .output()
+            .await?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("name resolution is disabled"), "{}", stderr);
+    }
+
+    use r_cat::net::util::parse_numeric;
+    assert_eq!(parse_numeric("127.0.0.1:80")?, "127.0.0.1:80".parse()?);
+    assert_eq!(parse_numeric("::1:80")?, "[::1]:80".parse()?);
+    assert_eq!(parse_numeric("[::1]:80")?, "[::1]:80".parse()?);
+    assert!(parse_numeric("example.com:80").is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn source_port_is_seen_by_peer() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    let source_port = free_port();
+
+    let mut child = rcat()
+        .args(["-p", &source_port.to_string(), "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let (_socket, peer) = time::timeout(Duration::from_secs(5), listener.accept()).await??;
+    assert_eq!(peer.port(), source_port);
+    drop(child.stdin.take());
+
+    // A port some other socket is listening on cannot be taken over
+    let out = rcat()
+        .args(["-p", &port, "127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains(&format!("source port {} is already in use", port)),
+        "{}",
+        stderr
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn zero_io_scan_reports_open_and_refused() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let open = listener.local_addr()?.port().to_string();
+
+    let out = rcat()
+        .args(["-z", "-v", "127.0.0.1", &open])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert_eq!(out.status.code(), Some(0));
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains(&format!(
+            "Connection to 127.0.0.1 {} port [tcp/*] succeeded!",
+            open
+        )),
+        "{}",
+        stderr
+    );
+    // The probe connected and hung up without sending anything
+    let (mut socket, _peer) = time::timeout(Duration::from_secs(5), listener.accept()).await??;
+    let mut rest = Vec::new();
+    socket.read_to_end(&mut rest).await?;
+    assert!(rest.is_empty());
+
+    let closed = free_port().to_string();
+    let out = rcat()
+        .args(["-z", "127.0.0.1", &closed])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert_eq!(out.status.code(), Some(2));
+    Ok(())
+}
+
+#[cfg(not(all(feature = "syn-scan", target_os = "linux")))]
+#[tokio::test]
+async fn syn_scan_needs_the_build_feature() -> anyhow::Result<()> {
+    let out = rcat()
+        .args(["-z", "--syn", "127.0.0.1", "9"])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("without the `syn-scan` feature"));
+    Ok(())
+}
+
+#[cfg(all(feature = "syn-scan", target_os = "linux"))]
+#[tokio::test]
+async fn syn_scan_reports_open_and_closed_without_connecting() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let open = listener.local_addr()?.port();
+    let closed = free_port();
+    let spec = format!("{},{}", open, closed);
+    let out = rcat()
+        .args(["-z", "--syn", "-w", "2", "127.0.0.1", &spec])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    let stderr = String::from_utf8_lossy(&out.stderr);
+
+    // SAFETY: plain socket(2) and close(2) calls on a descriptor we own
+    let privileged = unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_RAW, libc::IPPROTO_TCP);
+        fd >= 0 && libc::close(fd) == 0
+    };
+    if !privileged {
+        assert_eq!(out.status.code(), Some(1));
+        assert!(stderr.contains("CAP_NET_RAW"), "{}", stderr);
+        return Ok(());
+    }
+
+    assert_eq!(out.status.code(), Some(2), "{}", stderr);
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", stderr);
+    assert!(lines[0].contains(&format!("{} port [tcp/*] succeeded!", open)));
+    assert!(lines[1].contains(&format!("port {} (tcp) failed: Connection refused", closed)));
+    // The handshake was never completed, so there is nothing to accept
+    let accepted = time::timeout(Duration::from_millis(200), listener.accept()).await;
+    assert!(accepted.is_err(), "the scan completed a connection");
+    Ok(())
+}
+
+#[test]
+fn parse_ports_accepts_ranges_and_lists() {
+    assert_eq!(cli::parse_ports("80").unwrap(), vec![80]);
+    assert_eq!(cli::parse_ports("20-23").unwrap(), vec![20, 21, 22, 23]);
+    assert_eq!(cli::parse_ports("25-23").unwrap(), vec![23, 24, 25]);
+    assert_eq!(cli::parse_ports("22,80,443").unwrap(), vec![22, 80, 443]);
+    assert_eq!(cli::parse_ports("1,5-6").unwrap(), vec![1, 5, 6]);
+    assert!(cli::parse_ports("0").is_err());
+    assert!(cli::parse_ports("0-5").is_err());
+    assert!(cli::parse_ports("65536").is_err());
+    assert!(cli::parse_ports("80,").is_err());
+    assert!(cli::parse_ports("http").is_err());
+}
+
+#[test]
+fn format_ports_collapses_runs() {
+    assert_eq!(cli::format_ports(&[80]), "80");
+    assert_eq!(cli::format_ports(&[82, 22, 80, 81]), "22,80-82");
+    assert_eq!(cli::format_ports(&[3, 1, 2, 2]), "1-3");
+    assert_eq!(cli::format_ports(&[65534, 65535]), "65534-65535");
+    assert_eq!(cli::format_ports(&[]), "");
+}
+
+#[tokio::test]
+async fn scan_deadline_lists_unscanned_ports() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let open = listener.local_addr()?.port();
+
+    // The open port goes first; the rest of the range cannot all be probed
+    // in the time allowed
+    let spec = format!("{},1-65535", open);
+    let started = std::time::Instant::now();
+    let out = rcat()
+        .args(["-z", "--scan-deadline", "0.2", "127.0.0.1", &spec])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert_eq!(out.status.code(), Some(3), "{:?}", out);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert!(
+        lines[0].contains(&format!("{} port [tcp/*] succeeded!", open)),
+        "{}",
+        stderr
+    );
+    let notice = lines.last().unwrap();
+    assert!(notice.starts_with("scan deadline reached: "), "{}", notice);
+    assert!(notice.ends_with("-65535"), "{}", notice);
+
+    let args = cli::Args::try_parse_from(["r-cat", "--scan-deadline", "1", "h", "1"]);
+    assert!(args.is_err(), "needs -z");
+    Ok(())
+}
+
+#[tokio::test]
+async fn zero_io_scan_covers_port_list() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let open = listener.local_addr()?.port();
+    let closed = free_port();
+
+    let spec = format!("{},{}", open, closed);
+    let out = rcat()
+        .args(["-z", "127.0.0.1", &spec])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    // One closed port is enough to fail the scan
+    assert_eq!(out.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", stderr);
+    assert!(lines[0].contains(&format!("{} port [tcp/*] succeeded!", open)));
+    assert!(lines[1].contains(&format!("port {} (tcp) failed: Connection refused", closed)));
+
+    // Outside -z a port list is refused
+    let out = rcat()
+        .args(["127.0.0.1", "20-25"])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+    Ok(())
+}
+
+#[tokio::test]
+async fn timeout_only_fires_on_silence() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    // A slow but steady sender, well past the 1s timeout in total
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        for _ in 0..25 {
+            socket.write_all(b".").await?;
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        // Then go quiet while keeping the connection open
+        time::sleep(Duration::from_secs(10)).await;
+        anyhow::Ok(())
+    });
+
+    let started = time::Instant::now();
+    let child = rcat()
+        .args(["-w", "1", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let out = time::timeout(Duration::from_secs(8), child.wait_with_output()).await??;
+    assert_eq!(out.stdout, vec![b'.'; 25]);
+    // Cut off about a second after the last byte, not at the first second
+    let elapsed = started.elapsed();
+    assert!(elapsed > Duration::from_millis(3000), "{:?}", elapsed);
+    server.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn udp_timeout_resets_on_traffic() -> anyhow::Result<()> {
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let port = server.local_addr()?.port().to_string();
+
+    let started = time::Instant::now();
+    let mut child = rcat()
+        .args(["-u", "-w", "1", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"hi").await?;
+    let mut buf = [0u8; 16];
+    let (_, client) = time::timeout(Duration::from_secs(5), server.recv_from(&mut buf)).await??;
+    for _ in 0..5 {
+        server.send_to(b"+", client).await?;
+        time::sleep(Duration::from_millis(400)).await;
+    }
+
+    // stdin stays open; only the idle timeout ends the session
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert_eq!(out.stdout, b"+++++");
+    assert!(started.elapsed() > Duration::from_millis(2000));
+    drop(stdin);
+    Ok(())
+}
+
+#[tokio::test]
+async fn quit_after_drains_late_reply_then_exits() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        let mut request = Vec::new();
+        socket.read_to_end(&mut request).await?;
+        // Answer a little after the client's EOF, then never hang up
+        time::sleep(Duration::from_millis(300)).await;
+        socket.write_all(b"late reply\n").await?;
+        time::sleep(Duration::from_secs(30)).await;
+        anyhow::Ok(request)
+    });
+
+    let mut child = rcat()
+        .args(["-q", "1", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .expect("stdin")
+        .write_all(b"ask\n")
+        .await?;
+
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"late reply\n");
+    server.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn echo_server_reflects_client_data() -> anyhow::Result<()> {
+    let port = free_port();
+    let child = rcat()
+        .args(["--echo-server", "-l", "-p", &port.to_string()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut client = connect_retry(port).await?;
+    client.write_all(b"marco").await?;
+    let mut buf = [0u8; 5];
+    time::timeout(Duration::from_secs(5), client.read_exact(&mut buf)).await??;
+    assert_eq!(&buf, b"marco");
+    client.write_all(b" polo").await?;
+    client.shutdown().await?;
+    let mut rest = Vec::new();
+    time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest)).await??;
+    assert_eq!(rest, b" polo");
+
+    // Nothing is copied to stdout in this mode
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_message_size_disconnects_over_limit_client() -> anyhow::Result<()> {
+    assert_eq!(cli::parse_size("1M")?, 1 << 20);
+    assert_eq!(cli::parse_size("64k")?, 64 << 10);
+    assert!(cli::parse_size("12Q").is_err());
+
+    let port = free_port();
+    let child = rcat()
+        .args(["-l", "-p", &port.to_string(), "--max-message-size", "16"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut client = connect_retry(port).await?;
+    client.write_all(b"fits in limit\n").await?;
+    client.write_all(&[b'x'; 40]).await?;
+    // The server hangs up on us (EOF or reset, depending on unread data)
+    let mut buf = [0u8; 16];
+    let closed = time::timeout(Duration::from_secs(5), client.read(&mut buf)).await?;
+    assert!(matches!(closed, Ok(0) | Err(_)), "{:?}", closed);
+
+    // Everything up to the limit is still delivered
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert_eq!(out.stdout, [&b"fits in limit\n"[..], &[b'x'; 16]].concat());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("more than 16 bytes"), "{}", stderr);
+    // The overrun fails the session
+    assert_eq!(out.status.code(), Some(1), "{}", stderr);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn md5sig_client_matches_signed_listener() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    let client_ip = "127.0.0.1:0".parse()?;
+    if let Err(e) = r_cat::net::util::set_tcp_md5sig(&listener, client_ip, b"secret") {
+        // Kernels built without CONFIG_TCP_MD5SIG (or sandboxes denying it)
+        eprintln!("skipping: TCP_MD5SIG unavailable: {}", e);
+        return Ok(());
+    }
+
+    // The listening kernel drops SYNs with a missing or wrong signature
+    let out = rcat()
+        .args(["-S", "--md5-key", "wrong", "-w", "1", "127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+
+    let mut child = rcat()
+        .args(["-S", "--md5-key", "secret", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"signed").await?;
+    drop(stdin);
+    let (mut socket, _peer) = time::timeout(Duration::from_secs(5), listener.accept()).await??;
+    let mut received = Vec::new();
+    time::timeout(Duration::from_secs(5), socket.read_to_end(&mut received)).await??;
+    assert_eq!(received, b"signed");
+    Ok(())
+}
+
+#[tokio::test]
+async fn reply_delay_holds_back_listener_output() -> anyhow::Result<()> {
+    for echo in [false, true] {
+        let port = free_port();
+        let mut child = rcat()
+            .args(["-l", "-p", &port.to_string(), "--reply-delay", "0.5"])
+            .args(echo.then_some("--echo-server"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        // In plain listen mode the reply is whatever stdin already holds
+        let mut stdin = child.stdin.take().expect("stdin");
+        stdin.write_all(b"reply").await?;
+
+        let mut client = connect_retry(port).await?;
+        let started = time::Instant::now();
+        client.write_all(b"reply").await?;
+        let mut buf = [0u8; 5];
+        time::timeout(Duration::from_secs(5), client.read_exact(&mut buf)).await??;
+        assert_eq!(&buf, b"reply");
+        let waited = started.elapsed();
+        assert!(
+            waited >= Duration::from_millis(450),
+            "echo={} {:?}",
+            echo,
+            waited
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn report_rtt_prints_connect_latency() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    // Hang up right away so the client's session ends
+    tokio::spawn(async move { drop(listener.accept().await) });
+
+    let out = rcat()
+        .args(["--report-rtt", "127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let ms: f64 = stderr
+        .lines()
+        .find_map(|l| l.strip_prefix("connected in ")?.strip_suffix("ms"))
+        .unwrap_or_else(|| panic!("no RTT line in {:?}", stderr))
+        .parse()?;
+    // Loopback handshakes are fast, but never instantaneous at 0.1ms resolution
+    assert!(ms > 0.0 && ms < 1000.0, "{}", ms);
+    Ok(())
+}
+
+#[tokio::test]
+async fn upload_resumes_from_offset() -> anyhow::Result<()> {
+    let payload: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+    let file = tempfile::NamedTempFile::new()?;
+    std::fs::write(file.path(), &payload)?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.expect("read");
+        received
+    });
+
+    let out = time::timeout(
+        Duration::from_secs(5),
+        rcat()
+            .arg("--upload")
+            .arg(file.path())
+            .args(["--resume-from", "12345", "127.0.0.1", &port])
+            .stdin(Stdio::null())
+            .output(),
+    )
+    .await??;
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("20000/20000 bytes (100%)"));
+
+    let received = server.await?;
+    assert_eq!(received, &payload[12345..]);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn sendfile_transfers_file_intact() -> anyhow::Result<()> {
+    let payload: Vec<u8> = (0..3_000_000u32).map(|i| (i % 253) as u8).collect();
+    let file = tempfile::NamedTempFile::new()?;
+    std::fs::write(file.path(), &payload)?;
+
+    for extra in [None, Some("--strip-cr")] {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port().to_string();
+        let server = tokio::spawn(async move {
+            let (mut socket, _peer) = listener.accept().await.expect("accept");
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await.expect("read");
+            received
+        });
+
+        let out = time::timeout(
+            Duration::from_secs(10),
+            rcat()
+                .arg("-v")
+                .arg("--sendfile")
+                .arg(file.path())
+                .args(extra)
+                .args(["127.0.0.1", &port])
+                .stdin(Stdio::null())
+                .output(),
+        )
+        .await??;
+        assert!(out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        match extra {
+            // A send filter needs the bytes, so the copy path takes over
+            Some(_) => assert!(stderr.contains("copying through userspace"), "{}", stderr),
+            None => assert!(stderr.contains("sendfile: 3000000 bytes"), "{}", stderr),
+        }
+
+        let received = server.await?;
+        assert!(received == payload, "payload corrupted");
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn mtu_probe_finds_loopback_mtu() -> anyhow::Result<()> {
+    // A bound receiver keeps ICMP port-unreachable out of the picture
+    let receiver = UdpSocket::bind("127.0.0.1:0").await?;
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let mtu = r_cat::net::util::probe_path_mtu(&socket, receiver.local_addr()?, false).await?;
+
+    // The IPv4 total-length field caps a datagram at 65535 bytes
+    let lo_mtu: usize = std::fs::read_to_string("/sys/class/net/lo/mtu")?
+        .trim()
+        .parse()?;
+    assert_eq!(mtu, lo_mtu.min(65535));
+    Ok(())
+}
+
+#[tokio::test]
+async fn print_ready_announces_bound_listener() -> anyhow::Result<()> {
+    // Port 0: the READY line is the only way to learn where we are bound
+    let mut child = rcat()
+        .args(["-l", "-p", "0", "--print-ready"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stderr = BufReader::new(child.stderr.take().expect("stderr")).lines();
+    let line = time::timeout(Duration::from_secs(5), stderr.next_line())
+        .await??
+        .expect("ready line");
+    let addr: std::net::SocketAddr = line.strip_prefix("READY ").expect("READY prefix").parse()?;
+
+    // No retry loop needed: the listener is bound by the time READY appears
+    let mut client = tokio::net::TcpStream::connect(("127.0.0.1", addr.port())).await?;
+    client.write_all(b"ready!").await?;
+    drop(client);
+
+    let mut out = Vec::new();
+    let mut stdout = child.stdout.take().expect("stdout");
+    time::timeout(Duration::from_secs(5), stdout.read_to_end(&mut out)).await??;
+    assert_eq!(out, b"ready!");
+    Ok(())
+}
+
+#[tokio::test]
+async fn udp_broadcast_reaches_listener() -> anyhow::Result<()> {
+    let mut child = rcat()
+        .args(["-u", "-l", "-b", "-p", "0", "-w", "1", "--print-ready"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stderr = BufReader::new(child.stderr.take().expect("stderr")).lines();
+    let line = time::timeout(Duration::from_secs(5), stderr.next_line())
+        .await??
+        .expect("ready line");
+    let addr: std::net::SocketAddr = line.strip_prefix("READY ").expect("READY prefix").parse()?;
+
+    // The loopback subnet broadcast is the portable stand-in for a LAN one
+    let sender = UdpSocket::bind("127.0.0.1:0").await?;
+    sender.set_broadcast(true)?;
+    sender
+        .send_to(b"discover", ("127.255.255.255", addr.port()))
+        .await?;
+
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert_eq!(out.stdout, b"discover");
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn udp_broadcast_client_hears_every_responder() -> anyhow::Result<()> {
+    // Only a wildcard-bound socket sees the broadcast
+    let responder = UdpSocket::bind("0.0.0.0:0").await?;
+    let port = responder.local_addr()?.port().to_string();
+    let second = UdpSocket::bind("127.0.0.1:0").await?;
+    let second_addr = second.local_addr()?;
+
+    let mut child = rcat()
+        .args(["-u", "-b", "-v", "--dump-sockopts", "-w", "1"])
+        .args(["127.255.255.255", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"who is there?").await?;
+
+    let mut buf = [0u8; 64];
+    let (n, client) =
+        time::timeout(Duration::from_secs(5), responder.recv_from(&mut buf)).await??;
+    assert_eq!(&buf[..n], b"who is there?");
+    responder.send_to(b"one\n", client).await?;
+    time::sleep(Duration::from_millis(100)).await;
+    second.send_to(b"two\n", client).await?;
+    drop(stdin);
+
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert_eq!(out.stdout, b"one\ntwo\n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("SO_BROADCAST=1"), "{}", stderr);
+    let first = format!("udp: reply from 127.0.0.1:{}", port);
+    assert!(stderr.contains(&first), "{}", stderr);
+    assert!(
+        stderr.contains(&format!("udp: reply from {}", second_addr)),
+        "{}",
+        stderr
+    );
+    assert!(
+        cli::Args::try_parse_from(["r-cat", "-u", "-b", "--udp-strict-peer", "h", "1"]).is_err()
+    );
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn udp_listener_joins_multicast_group() -> anyhow::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mut child = rcat()
+        .args(["-u", "-l", "-v", "-p", "0", "-w", "1", "--print-ready"])
+        .args(["--multicast-iface", "127.0.0.1", "239.255.77.77"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stderr = BufReader::new(child.stderr.take().expect("stderr")).lines();
+    let mut port = None;
+    let mut log = Vec::new();
+    while port.is_none() {
+        let line = time::timeout(Duration::from_secs(5), stderr.next_line())
+            .await??
+            .expect("ready line");
+        if let Some(addr) = line.strip_prefix("READY ") {
+            port = Some(addr.parse::<std::net::SocketAddr>()?.port());
+        }
+        log.push(line);
+    }
+
+    // Send out of loopback, where the listener joined
+    let sender = UdpSocket::bind("127.0.0.1:0").await?;
+    let iface = libc::in_addr {
+        s_addr: u32::from(std::net::Ipv4Addr::LOCALHOST).to_be(),
+    };
+    // SAFETY: a valid in_addr for the lifetime of the call
+    let rc = unsafe {
+        libc::setsockopt(
+            sender.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MULTICAST_IF,
+            (&iface as *const libc::in_addr).cast(),
+            std::mem::size_of::<libc::in_addr>() as libc::socklen_t,
+        )
+    };
+    assert_eq!(
+        rc,
+        0,
+        "IP_MULTICAST_IF: {}",
+        std::io::Error::last_os_error()
+    );
+    // The join happens before READY, so the first datagram already counts
+    let port = port.expect("port");
+    sender
+        .send_to(b"to the group\n", ("239.255.77.77", port))
+        .await?;
+
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert_eq!(out.stdout, b"to the group\n");
+    assert!(
+        log.iter()
+            .any(|l| l.contains("joined multicast group 239.255.77.77")),
+        "{:?}",
+        log
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn line_datagrams_sends_one_datagram_per_line() -> anyhow::Result<()> {
+    for (extra, expected) in [
+        (None, [&b"<13>first"[..], b"second", b"last"]),
+        (
+            Some("--keep-newline"),
+            [&b"<13>first\n"[..], b"second\n", b"last"],
+        ),
+    ] {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let port = server.local_addr()?.port().to_string();
+        let mut child = rcat()
+            .args(["-u", "--line-datagrams", "-w", "1", "127.0.0.1", &port])
+            .args(extra)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        // One write, so a plain read would have produced a single datagram
+        let mut stdin = child.stdin.take().expect("stdin");
+        stdin.write_all(b"<13>first\nsecond\nlast").await?;
+        drop(stdin);
+
+        let mut buf = [0u8; 64];
+        for want in expected {
+            let (n, _) =
+                time::timeout(Duration::from_secs(5), server.recv_from(&mut buf)).await??;
+            assert_eq!(&buf[..n], want);
+        }
+        time::timeout(Duration::from_secs(5), child.wait()).await??;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn udp_send_failure_is_reported_and_skipped() -> anyhow::Result<()> {
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let port = server.local_addr()?.port().to_string();
+    let mut child = rcat()
+        .args([
+            "-u",
+            "-v",
+            "--line-datagrams",
+            "-w",
+            "1",
+            "127.0.0.1",
+            &port,
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Too long for any datagram, then one that fits
+    let mut stdin = child.stdin.take().expect("stdin");
+    let mut input = vec![b'x'; 70_000];
+    input.extend_from_slice(b"\nfits\n");
+    stdin.write_all(&input).await?;
+    drop(stdin);
+
+    let mut buf = [0u8; 64];
+    let (n, _) = time::timeout(Duration::from_secs(5), server.recv_from(&mut buf)).await??;
+    assert_eq!(&buf[..n], b"fits");
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let failed = format!("sending to 127.0.0.1:{} failed", port);
+    assert!(stderr.contains(&failed), "{}", stderr);
+    assert!(out.status.success(), "{}", stderr);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn dscp_passthrough_reflects_received_tos() -> anyhow::Result<()> {
+    use r_cat::net::util::{recv_from_with_tos, set_recv_tos, set_tos};
+
+    let mut child = rcat()
+        .args(["-u", "-l", "-p", "0", "--dscp-passthrough", "--print-ready"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stderr = BufReader::new(child.stderr.take().expect("stderr")).lines();
+    let line = time::timeout(Duration::from_secs(5), stderr.next_line())
+        .await??
+        .expect("ready line");
+    let addr: std::net::SocketAddr = line.strip_prefix("READY ").expect("READY prefix").parse()?;
+
+    // CS5 (DSCP 40) marking, as set by a voice-signalling client
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    set_tos(&peer, 0xa0)?;
+    set_recv_tos(&peer)?;
+    peer.send_to(b"ping", ("127.0.0.1", addr.port())).await?;
+    let mut stdout = child.stdout.take().expect("stdout");
+    let mut seen = [0u8; 4];
+    time::timeout(Duration::from_secs(5), stdout.read_exact(&mut seen)).await??;
+    assert_eq!(&seen, b"ping");
+
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"pong").await?;
+    let mut buf = [0u8; 16];
+    let (n, _, tos) =
+        time::timeout(Duration::from_secs(5), recv_from_with_tos(&peer, &mut buf)).await??;
+    assert_eq!(&buf[..n], b"pong");
+    assert_eq!(tos, Some(0xa0));
+    Ok(())
+}
+
+#[tokio::test]
+async fn retry_resolve_waits_for_record_to_appear() -> anyhow::Result<()> {
+    use r_cat::net::util::{Backoff, resolve_with};
+
+    let backoff = Backoff {
+        base: Duration::from_millis(10),
+        ..Backoff::default()
+    };
+    // A resolver whose record only shows up on the third query
+    let lookup = |calls: &std::cell::Cell<u32>| {
+        calls.set(calls.get() + 1);
+        let n = calls.get();
+        async move {
+            if n < 3 {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "NXDOMAIN",
+                ))
+            } else {
+                Ok(vec!["192.0.2.7:53".parse().unwrap()])
+            }
+        }
+    };
+
+    let calls = std::cell::Cell::new(0);
+    let found = resolve_with("late.example:53", 5, &backoff, false, |_| lookup(&calls)).await?;
+    assert_eq!(found, vec!["192.0.2.7:53".parse()?]);
+    assert_eq!(calls.get(), 3);
+
+    // Too few retries still fails, naming the host
+    let calls = std::cell::Cell::new(0);
+    let err = resolve_with("late.example:53", 1, &backoff, false, |_| lookup(&calls))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("late.example"), "{}", err);
+    assert_eq!(calls.get(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn happy_eyeballs_skips_a_dead_address() -> anyhow::Result<()> {
+    use r_cat::net::util::{ATTEMPT_DELAY, happy_eyeballs};
+    use std::io::{Error, ErrorKind};
+    use std::net::SocketAddr;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let live = listener.local_addr()?;
+    tokio::spawn(async move { drop(listener.accept().await) });
+    // Listed first, and never answers
+    let dead: SocketAddr = "[2001:db8::1]:9".parse()?;
+
+    let started = time::Instant::now();
+    let stream = happy_eyeballs(vec![dead, live], ATTEMPT_DELAY, |addr| async move {
+        if addr == dead {
+            time::sleep(Duration::from_secs(30)).await;
+            return Err(Error::from(ErrorKind::TimedOut));
+        }
+        tokio::net::TcpStream::connect(addr).await
+    })
+    .await?;
+    assert_eq!(stream.peer_addr()?, live);
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // Families alternate, and with everything failing the last error wins
+    let tried = std::sync::Mutex::new(Vec::new());
+    let targets: Vec<SocketAddr> = ["[::1]:1", "[::1]:2", "127.0.0.1:3", "127.0.0.1:4"]
+        .iter()
+        .map(|a| a.parse())
+        .collect::<Result<_, _>>()?;
+    let err = happy_eyeballs(targets, Duration::from_millis(10), |addr| {
+        tried.lock().unwrap().push(addr.port());
+        async move { Err::<(), _>(Error::other(format!("refused by {}", addr.port()))) }
+    })
+    .await
+    .unwrap_err();
+    assert_eq!(*tried.lock().unwrap(), [1, 3, 2, 4]);
+    assert_eq!(err.to_string(), "refused by 4");
+    Ok(())
+}
+
+/// What a test SOCKS5 proxy saw: the login, the CONNECT request bytes
+/// (address type onwards) and the tunnelled data.
+type SocksLog = (Option<(String, String)>, Vec<u8>, Vec<u8>);
+
+/// A one-shot SOCKS5 proxy that answers CONNECT with `reply` and, on
+/// success, acts as the destination itself: it greets the client and
+/// collects everything sent through the tunnel.
+async fn socks5_responder(
+    reply: u8,
+) -> anyhow::Result<(u16, tokio::task::JoinHandle<anyhow::Result<SocksLog>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let task = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        let mut head = [0u8; 2];
+        socket.read_exact(&mut head).await?;
+        let mut methods = vec![0u8; head[1] as usize];
+        socket.read_exact(&mut methods).await?;
+
+        let mut login = None;
+        if methods.contains(&2) {
+            socket.write_all(&[5, 2]).await?;
+            let mut ver_len = [0u8; 2];
+            socket.read_exact(&mut ver_len).await?;
+            let mut user = vec![0u8; ver_len[1] as usize];
+            socket.read_exact(&mut user).await?;
+            let mut pass = vec![0u8; socket.read_u8().await? as usize];
+            socket.read_exact(&mut pass).await?;
+            socket.write_all(&[1, 0]).await?;
+            login = Some((String::from_utf8(user)?, String::from_utf8(pass)?));
+        } else {
+            socket.write_all(&[5, 0]).await?;
+        }
+
+        let mut request = [0u8; 4];
+        socket.read_exact(&mut request).await?;
+        let mut target = vec![request[3]];
+        match request[3] {
+            3 => {
+                let len = socket.read_u8().await?;
+                target.push(len);
+                target.resize(2 + len as usize + 2, 0);
+                socket.read_exact(&mut target[2..]).await?;
+            }
+            _ => {
+                target.resize(1 + 4 + 2, 0);
+                socket.read_exact(&mut target[1..]).await?;
+            }
+        }
+        socket
+            .write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await?;
+        let mut data = Vec::new();
+        if reply == 0 {
+            socket.write_all(b"hello via socks\n").await?;
+            socket.read_to_end(&mut data).await?;
+        }
+        anyhow::Ok((login, target, data))
+    });
+    Ok((port, task))
+}
+
+#[tokio::test]
+async fn socks5_proxy_tunnels_session() -> anyhow::Result<()> {
+    let (proxy_port, proxy) = socks5_responder(0).await?;
+    let mut child = rcat()
+        .args(["-X", "socks5", "-x", &format!("127.0.0.1:{}", proxy_port)])
+        .args(["-P", "alice", "db.internal", "5432"])
+        .env("RCAT_PROXY_PASSWORD", "s3cret")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"through the tunnel\n").await?;
+    drop(stdin);
+
+    let (login, target, data) = time::timeout(Duration::from_secs(5), proxy).await???;
+    assert_eq!(login, Some(("alice".to_string(), "s3cret".to_string())));
+    // The name goes to the proxy unresolved
+    let mut expected = vec![3, 11];
+    expected.extend_from_slice(b"db.internal");
+    expected.extend_from_slice(&5432u16.to_be_bytes());
+    assert_eq!(target, expected);
+    assert_eq!(data, b"through the tunnel\n");
+
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"hello via socks\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn repeat_until_match_goes_through_the_proxy() -> anyhow::Result<()> {
+    let (proxy_port, proxy) = socks5_responder(0).await?;
+    let mut child = rcat()
+        .args(["-x", &format!("127.0.0.1:{}", proxy_port), "-C"])
+        .args(["--repeat-until-match", "socks", "db.internal", "5432"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"PING\n").await?;
+    drop(stdin);
+
+    let (_, target, data) = time::timeout(Duration::from_secs(5), proxy).await???;
+    assert_eq!(&target[2..13], b"db.internal");
+    // The resent request is filtered like any other input
+    assert_eq!(data, b"PING\r\n");
+
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"hello via socks\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn socks5_reply_code_is_reported() -> anyhow::Result<()> {
+    let (proxy_port, proxy) = socks5_responder(5).await?;
+    let out = rcat()
+        .args(["-x", &format!("127.0.0.1:{}", proxy_port), "10.1.2.3", "80"])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("could not connect to 10.1.2.3:80: connection refused"),
+        "{}",
+        stderr
+    );
+
+    let (login, target, _) = time::timeout(Duration::from_secs(5), proxy).await???;
+    assert_eq!(login, None);
+    assert_eq!(target, [1, 10, 1, 2, 3, 0, 80]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_connect_needs_a_200() -> anyhow::Result<()> {
+    use r_cat::net::proxy::http_connect;
+
+    // 407: the status code and reason make it into the error
+    let (mut ours, mut proxy) = tokio::io::duplex(4096);
+    proxy
+        .write_all(
+            b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic\r\n\r\n",
+        )
+        .await?;
+    let err = http_connect(&mut ours, None, "db.internal", 5432)
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("407 Proxy Authentication Required"), "{}", err);
+    let mut request = vec![0u8; 4096];
+    let n = proxy.read(&mut request).await?;
+    assert_eq!(
+        &request[..n],
+        b"CONNECT db.internal:5432 HTTP/1.1\r\nHost: db.internal:5432\r\n\r\n"
+    );
+
+    // 200: credentials are sent, and bytes after the header stay in the tunnel
+    let (mut ours, mut proxy) = tokio::io::duplex(4096);
+    proxy
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nSSH-2.0-banner\r\n")
+        .await?;
+    let auth = ("alice".to_string(), "s3cret".to_string());
+    http_connect(&mut ours, Some(&auth), "::1", 22).await?;
+    let n = proxy.read(&mut request).await?;
+    let sent = String::from_utf8_lossy(&request[..n]);
+    assert!(
+        sent.starts_with("CONNECT [::1]:22 HTTP/1.1\r\n"),
+        "{}",
+        sent
+    );
+    assert!(
+        sent.contains("Proxy-Authorization: Basic YWxpY2U6czNjcmV0\r\n"),
+        "{}",
+        sent
+    );
+    let mut banner = [0u8; 16];
+    ours.read_exact(&mut banner).await?;
+    assert_eq!(&banner, b"SSH-2.0-banner\r\n");
+    Ok(())
+}
+
+#[test]
+fn replace_matches_across_chunk_boundary() {
+    use r_cat::filter::{Filter, Replace};
+
+    let mut replace = Replace::new(b"foo".to_vec(), b"bar".to_vec());
+    let mut out = Vec::new();
+    for chunk in [&b"xxf"[..], b"o", b"oyyf", b"ox"] {
+        replace.feed(chunk, &mut out);
+    }
+    // A trailing partial match is released unchanged at end of stream
+    replace.feed(b"yyfo", &mut out);
+    replace.finish(&mut out);
+    assert_eq!(out, b"xxbaryyfoxyyfo");
+}
+
+#[tokio::test]
+async fn filter_writer_replaces_hex_escaped_bytes() -> anyhow::Result<()> {
+    use r_cat::filter::{FilterWriter, Replace};
+
+    let (from, to) = cli::parse_replacement("\\x00\\x01=<nul>")?;
+    let mut writer = FilterWriter::new(Vec::new(), vec![Box::new(Replace::new(from, to))]);
+    writer.write_all(b"a\x00").await?;
+    writer.write_all(b"\x01b\x00").await?;
+    writer.end().await?;
+    assert_eq!(writer.get_mut().as_slice(), b"a<nul>b\x00");
+
+    for flag in ["--replace-send", "--replace-recv"] {
+        let args = cli::Args::parse_from(["r-cat", "-u", flag, "a=b", "h", "1"]);
+        assert!(r_cat::net::Config::try_from(&args).is_err(), "{}", flag);
+    }
+    Ok(())
+}
+
+#[test]
+fn crlf_adds_missing_carriage_returns() {
+    use r_cat::filter::{Crlf, Filter};
+
+    let mut crlf = Crlf::default();
+    let mut out = Vec::new();
+    crlf.feed(b"a\nb\r\nc\n", &mut out);
+    assert_eq!(out, b"a\r\nb\r\nc\r\n");
+
+    // A CR ending one chunk still pairs with the LF starting the next
+    out.clear();
+    for chunk in [&b"x\r"[..], b"\ny\n"] {
+        crlf.feed(chunk, &mut out);
+    }
+    assert_eq!(out, b"x\r\ny\r\n");
+}
+
+#[tokio::test]
+async fn crlf_flag_translates_outbound_newlines() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await?;
+        anyhow::Ok(received)
+    });
+
+    let mut child = rcat()
+        .args(["-C", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .expect("stdin")
+        .write_all(b"EHLO a\nQUIT\r\n")
+        .await?;
+
+    let received = time::timeout(Duration::from_secs(5), server).await???;
+    assert_eq!(received, b"EHLO a\r\nQUIT\r\n");
+    time::timeout(Duration::from_secs(5), child.wait()).await??;
+
+    // Datagrams are sent as read, so -C is refused rather than ignored
+    let args = cli::Args::parse_from(["r-cat", "-u", "-C", "h", "1"]);
+    assert!(r_cat::net::Config::try_from(&args).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn buffer_lengths_cap_each_read() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    let payload: Vec<u8> = (0..2000u32).map(|i| b'a' + (i % 26) as u8).collect();
+
+    let reply = payload.clone();
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        socket.write_all(&reply).await?;
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await?;
+        anyhow::Ok(received)
+    });
+
+    let mut child = rcat()
+        .args(["--annotate", "-I", "3", "-O", "5", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(&payload).await?;
+    drop(stdin);
+
+    assert_eq!(
+        time::timeout(Duration::from_secs(5), server).await???,
+        payload
+    );
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert_eq!(out.stdout, payload);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains(">> 3 bytes"), "{}", stderr);
+    for line in stderr.lines() {
+        let (limit, rest) = match line.split_once(' ') {
+            Some((">>", rest)) => (3, rest),
+            Some(("<<", rest)) => (5, rest),
+            _ => continue,
+        };
+        let n: usize = rest.split(' ').next().expect("count").parse()?;
+        assert!(n <= limit, "{}", line);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn buffer_length_must_be_positive() -> anyhow::Result<()> {
+    let out = rcat()
+        .args(["-O", "0", "127.0.0.1", "9"])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("-O must be at least 1 byte"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn strip_cr_sends_lf_only() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.expect("read");
+        received
+    });
+
+    let mut child = rcat()
+        .args(["--strip-cr", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    // Split a CRLF across two writes; a lone CR must survive
+    stdin.write_all(b"one\r\ntwo\r").await?;
+    stdin.flush().await?;
+    time::sleep(Duration::from_millis(100)).await;
+    stdin.write_all(b"\nbare\rcr\r\n").await?;
+    drop(stdin);
+
+    let received = time::timeout(Duration::from_secs(5), server).await??;
+    assert_eq!(received, b"one\ntwo\nbare\rcr\n");
+    time::timeout(Duration::from_secs(5), child.wait()).await??;
+
+    let args = cli::Args::parse_from(["r-cat", "-u", "--strip-cr", "h", "1"]);
+    assert!(r_cat::net::Config::try_from(&args).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn turn_based_waits_for_full_reply() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        let mut buf = [0u8; 64];
+        let n = socket.read(&mut buf).await?;
+        assert_eq!(&buf[..n], b"one\n");
+        // Reply in two parts with a pause shorter than --turn-idle in between;
+        // the next line must not arrive until after the second part
+        socket.write_all(b"A").await?;
+        let early = time::timeout(Duration::from_millis(200), socket.read(&mut buf)).await;
+        assert!(early.is_err(), "second line sent mid-reply");
+        socket.write_all(b"B\n").await?;
+        let n = time::timeout(Duration::from_secs(5), socket.read(&mut buf)).await??;
+        assert_eq!(&buf[..n], b"two\n");
+        socket.write_all(b"C\n").await?;
+        anyhow::Ok(())
+    });
+
+    let mut child = rcat()
+        .args(["--turn-based", "--turn-idle", "0.4", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"one\ntwo\n").await?;
+    drop(stdin);
+
+    time::timeout(Duration::from_secs(5), server).await???;
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert_eq!(out.stdout, b"AB\nC\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn headers_only_stops_before_body() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        let mut buf = [0u8; 256];
+        let _ = socket.read(&mut buf).await?;
+        // Split the terminator across writes, then hold the body back
+        socket
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r")
+            .await?;
+        time::sleep(Duration::from_millis(100)).await;
+        socket.write_all(b"\n").await?;
+        // The client hangs up instead of waiting for the body
+        let n = time::timeout(Duration::from_secs(5), socket.read(&mut buf)).await??;
+        assert_eq!(n, 0);
+        anyhow::Ok(())
+    });
+
+    let mut child = rcat()
+        .args(["--headers-only", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // stdin stays open: the header boundary alone must end the session
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin
+        .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+        .await?;
+
+    time::timeout(Duration::from_secs(5), server).await???;
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert_eq!(out.stdout, b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n");
+    drop(stdin);
+    Ok(())
+}
+
+#[test]
+fn detect_classifies_common_banners() {
+    use r_cat::detect::{Protocol, classify};
+
+    assert_eq!(classify(b"SSH-2.0-OpenSSH_9.6\r\n"), Some(Protocol::Ssh));
+    assert_eq!(classify(b"HTTP/1.1 200 OK\r\n"), Some(Protocol::Http));
+    assert_eq!(
+        classify(b"220 mx.example.com ESMTP Postfix\r\n"),
+        Some(Protocol::Smtp)
+    );
+    assert_eq!(classify(b"220 (vsFTPd 3.0.5)\r\n"), Some(Protocol::Ftp));
+    assert_eq!(classify(b"+OK POP3 ready\r\n"), Some(Protocol::Pop3));
+    assert_eq!(
+        classify(b"* OK [CAPABILITY IMAP4rev1] ready\r\n"),
+        Some(Protocol::Imap)
+    );
+    assert_eq!(
+        classify(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01]),
+        Some(Protocol::Tls)
+    );
+    assert_eq!(classify(&[0xff, 0xfd, 0x18]), Some(Protocol::Telnet));
+    assert_eq!(classify(b"RFB 003.008\n"), Some(Protocol::Vnc));
+    assert_eq!(
+        classify(b"\x4a\x00\x00\x00\x0a8.0.36\x00"),
+        Some(Protocol::Mysql)
+    );
+    assert_eq!(classify(b"hello there"), None);
+}
+
+#[tokio::test]
+async fn detect_protocol_reports_without_consuming() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        socket.write_all(b"SSH-2.0-test\r\n").await.expect("write");
+    });
+
+    let out = time::timeout(
+        Duration::from_secs(5),
+        rcat()
+            .args(["-v", "--detect-protocol", "127.0.0.1", &port])
+            .stdin(Stdio::null())
+            .output(),
+    )
+    .await??;
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Detected protocol: SSH"));
+    assert_eq!(out.stdout, b"SSH-2.0-test\r\n");
+    Ok(())
+}
+
+#[test]
+fn backoff_sequences() {
+    use r_cat::net::util::{Backoff, BackoffKind};
+
+    let ms = |v: u64| Duration::from_millis(v);
+    let exp = Backoff {
+        kind: BackoffKind::Exponential,
+        base: ms(100),
+        max: ms(1000),
+        jitter: false,
+    };
+    let delays: Vec<_> = (0..6).map(|a| exp.delay(a)).collect();
+    assert_eq!(
+        delays,
+        [ms(100), ms(200), ms(400), ms(800), ms(1000), ms(1000)]
+    );
+    // Huge attempt numbers must saturate at the cap rather than overflow
+    assert_eq!(exp.delay(200), ms(1000));
+
+    let linear = Backoff {
+        kind: BackoffKind::Linear,
+        ..exp
+    };
+    let delays: Vec<_> = (0..4).map(|a| linear.delay(a)).collect();
+    assert_eq!(delays, [ms(100), ms(200), ms(300), ms(400)]);
+
+    let jittered = Backoff {
+        jitter: true,
+        ..exp
+    };
+    for attempt in 0..6 {
+        let d = jittered.delay(attempt);
+        let nominal = exp.delay(attempt);
+        assert!(d >= nominal / 2 && d <= nominal, "{:?} vs {:?}", d, nominal);
+    }
+}
+
+#[tokio::test]
+async fn stats_file_appends_session_record() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let stats = dir.path().join("run.jsonl");
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _peer) = listener.accept().await.expect("accept");
+            let mut buf = Vec::new();
+            socket.read_to_end(&mut buf).await.expect("read");
+            socket.write_all(b"world!!").await.expect("write");
+        }
+    });
+
+    for _ in 0..2 {
+        let mut child = rcat()
+            .arg("--stats-file")
+            .arg(&stats)
+            .args(["127.0.0.1", &port.to_string()])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin");
+        stdin.write_all(b"hello").await?;
+        drop(stdin);
+        assert!(
+            time::timeout(Duration::from_secs(5), child.wait())
+                .await??
+                .success()
+        );
+    }
+
+    let contents = std::fs::read_to_string(&stats)?;
+    let lines: Vec<_> = contents.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", contents);
+    for line in lines {
+        assert!(
+            line.contains(&format!("\"peer\":\"127.0.0.1:{}\"", port)),
+            "{}",
+            line
+        );
+        assert!(line.contains("\"bytes_sent\":5"), "{}", line);
+        assert!(line.contains("\"bytes_received\":7"), "{}", line);
+        assert!(line.contains("\"exit\":\"completed\""), "{}", line);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn udp_strict_peer_drops_third_party_datagrams() -> anyhow::Result<()> {
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let port = server.local_addr()?.port().to_string();
+    let intruder = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let mut child = rcat()
+        .args([
+            "-u",
+            "-v",
+            "--udp-strict-peer",
+            "-w",
+            "1",
+            "127.0.0.1",
+            &port,
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"hi").await?;
+
+    // Learn the client's address from its first datagram
+    let mut buf = [0u8; 64];
+    let (_, client) = time::timeout(Duration::from_secs(5), server.recv_from(&mut buf)).await??;
+    intruder.send_to(b"spoofed", client).await?;
+    time::sleep(Duration::from_millis(100)).await;
+    server.send_to(b"legit", client).await?;
+    drop(stdin);
+
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert_eq!(out.stdout, b"legit");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("unexpected peer"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn udp_client_connects_unless_loose() -> anyhow::Result<()> {
+    for loose in [false, true] {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let port = server.local_addr()?.port().to_string();
+        let intruder = UdpSocket::bind("127.0.0.1:0").await?;
+
+        let mut cmd = rcat();
+        cmd.arg("-u");
+        if loose {
+            cmd.arg("--udp-loose");
+        }
+        let mut child = cmd
+            .args(["-w", "1", "127.0.0.1", &port])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin");
+        stdin.write_all(b"hi").await?;
+
+        let mut buf = [0u8; 64];
+        let (_, client) =
+            time::timeout(Duration::from_secs(5), server.recv_from(&mut buf)).await??;
+        intruder.send_to(b"spoofed ", client).await?;
+        time::sleep(Duration::from_millis(100)).await;
+        server.send_to(b"legit", client).await?;
+        drop(stdin);
+
+        let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+        if loose {
+            assert_eq!(out.stdout, b"spoofed legit");
+        } else {
+            assert_eq!(out.stdout, b"legit");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn pcap_writer_emits_parseable_records() -> anyhow::Result<()> {
+    use r_cat::pcap::{LINKTYPE_RAW, PCAP_MAGIC, PcapWriter};
+    use r_cat::tee::Direction;
+
+    let local = "127.0.0.1:40000".parse()?;
+    let peer = "127.0.0.1:80".parse()?;
+    let mut out = Vec::new();
+    let mut pcap = PcapWriter::new(&mut out, local, peer)?;
+    pcap.record(Direction::Sent, b"GET / HTTP/1.0\r\n\r\n")?;
+    pcap.record(Direction::Received, b"HTTP/1.0 200 OK\r\n")?;
+
+    let u32_at = |i: usize| u32::from_le_bytes(out[i..i + 4].try_into().unwrap());
+    assert_eq!(u32_at(0), PCAP_MAGIC);
+    assert_eq!(u32_at(20), LINKTYPE_RAW);
+
+    // Walk the records: 16-byte record header, then IPv4 + TCP + payload
+    let mut at = 24;
+    let mut payloads = Vec::new();
+    while at < out.len() {
+        let len = u32_at(at + 8) as usize;
+        let packet = &out[at + 16..at + 16 + len];
+        assert_eq!(packet[0], 0x45);
+        let src_port = u16::from_be_bytes([packet[20], packet[21]]);
+        payloads.push((src_port, packet[40..].to_vec()));
+        at += 16 + len;
+    }
+    assert_eq!(at, out.len());
+    assert_eq!(
+        payloads,
+        vec![
+            (40000, b"GET / HTTP/1.0\r\n\r\n".to_vec()),
+            (80, b"HTTP/1.0 200 OK\r\n".to_vec()),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn pcap_keeps_every_connection_of_a_keep_open_listener() -> anyhow::Result<()> {
+    use r_cat::pcap::PCAP_MAGIC;
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("capture.pcap");
+    let port = free_port();
+    let _child = rcat()
+        .args(["-l", "-k", "-p", &port.to_string(), "--pcap"])
+        .arg(&path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+
+    let mut clients = Vec::new();
+    for payload in [&b"first\n"[..], b"second\n"] {
+        let mut client = connect_retry(port).await?;
+        clients.push(client.local_addr()?.port());
+        client.write_all(payload).await?;
+        client.shutdown().await?;
+        let mut rest = Vec::new();
+        time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest)).await??;
+    }
+
+    // One header, then the records of both connections. The listener's own
+    // FIN can beat its reading of ours, so give it a moment
+    let mut payloads = Vec::new();
+    for _ in 0..50 {
+        let out = std::fs::read(&path)?;
+        let u32_at = |i: usize| u32::from_le_bytes(out[i..i + 4].try_into().unwrap());
+        assert_eq!(u32_at(0), PCAP_MAGIC);
+        let mut at = 24;
+        payloads.clear();
+        while at < out.len() {
+            let len = u32_at(at + 8) as usize;
+            let packet = &out[at + 16..at + 16 + len];
+            let src_port = u16::from_be_bytes([packet[20], packet[21]]);
+            payloads.push((src_port, packet[40..].to_vec()));
+            at += 16 + len;
+        }
+        assert_eq!(at, out.len());
+        if payloads.len() >= 2 {
+            break;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(
+        payloads,
+        vec![
+            (clients[0], b"first\n".to_vec()),
+            (clients[1], b"second\n".to_vec()),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn verbose_client_reports_byte_counts() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.expect("read");
+        socket.write_all(&[b'r'; 1000]).await.expect("write");
+        received.len()
+    });
+
+    let mut child = rcat()
+        .args(["-v", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(&[b's'; 2000]).await?;
+    drop(stdin);
+
+    assert_eq!(time::timeout(Duration::from_secs(5), server).await??, 2000);
+    let output = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("sent 2000, rcvd 1000"),
+        "stderr: {}",
+        stderr
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn rate_limits_outbound_throughput() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.expect("read");
+        received.len()
+    });
+
+    let started = time::Instant::now();
+    let mut child = rcat()
+        .args(["--rate", "50K", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(&[b'x'; 100 * 1024]).await?;
+    drop(stdin);
+
+    let received = time::timeout(Duration::from_secs(10), server).await??;
+    let elapsed = started.elapsed();
+    assert_eq!(received, 100 * 1024);
+    // 100K at 50K/s, less the initial burst allowance
+    assert!(
+        elapsed >= Duration::from_millis(1600) && elapsed < Duration::from_secs(4),
+        "took {:?}",
+        elapsed
+    );
+    time::timeout(Duration::from_secs(5), child.wait()).await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn interval_spaces_out_lines() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.expect("read");
+        received
+    });
+
+    let mut child = rcat()
+        .args(["-i", "0.5", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"one\ntwo\nthree").await?;
+    drop(stdin);
+
+    let started = time::Instant::now();
+    let received = time::timeout(Duration::from_secs(5), server).await??;
+    let elapsed = started.elapsed();
+    // The unterminated last line still goes out, after two pauses
+    assert_eq!(received, b"one\ntwo\nthree");
+    assert!(
+        elapsed >= Duration::from_millis(900) && elapsed < Duration::from_millis(2500),
+        "took {:?}",
+        elapsed
+    );
+    time::timeout(Duration::from_secs(5), child.wait()).await??;
+    Ok(())
+}
+
+#[test]
+fn hexdump_matches_canonical_layout() -> anyhow::Result<()> {
+    use r_cat::format::write_hexdump;
+
+    let mut out = Vec::new();
+    write_hexdump(&mut out, 0, b"ABC\x00\xff")?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "00000000  41 42 43 00 ff                                    |ABC..|\n"
+    );
+
+    // A full line splits its bytes in two groups; offsets start where asked
+    let mut out = Vec::new();
+    write_hexdump(&mut out, 0x20, b"0123456789abcdef~")?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "00000020  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+         00000030  7e                                                |~|\n"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn hexdump_lines_continue_across_reads() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        socket.write_all(b"hi").await.expect("write");
+        time::sleep(Duration::from_millis(200)).await;
+        socket.write_all(b"there").await.expect("write");
+    });
+
+    let child = rcat()
+        .args(["--hexdump", "127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let output = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    server.await?;
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "00000000  68 69 74 68 65 72 65                              |hithere|\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn hexdump_lines_span_uneven_chunks() -> anyhow::Result<()> {
+    use r_cat::filter::Filter;
+    use r_cat::format::{Hexdump, write_hexdump};
+
+    let data: Vec<u8> = (0u8..40).collect();
+    let mut expected = Vec::new();
+    write_hexdump(&mut expected, 0, &data)?;
+
+    let mut dump = Hexdump::default();
+    let mut out = Vec::new();
+    for chunk in [&data[..3], &data[3..20], &data[20..21], &data[21..]] {
+        dump.feed(chunk, &mut out);
+    }
+    // The last eight bytes wait for a line of their own
+    let two_lines = expected
+        .iter()
+        .enumerate()
+        .filter(|&(_, &b)| b == b'\n')
+        .nth(1);
+    assert_eq!(out, expected[..=two_lines.expect("two lines").0]);
+    dump.finish(&mut out);
+    assert_eq!(String::from_utf8(out)?, String::from_utf8(expected)?);
+    Ok(())
+}
+
+#[test]
+fn colored_hexdump_only_adds_escapes() -> anyhow::Result<()> {
+    use r_cat::format::{write_hexdump, write_hexdump_colored};
+
+    let data = b"Hi\x01\xff and a second line";
+    let (mut plain, mut colored) = (Vec::new(), Vec::new());
+    write_hexdump(&mut plain, 0, data)?;
+    write_hexdump_colored(&mut colored, 0, data)?;
+    let colored = String::from_utf8(colored)?;
+    // Printable, control and high bytes each get their own colour
+    assert!(
+        colored.contains("\x1b[32m48 69 \x1b[33m01 \x1b[31mff "),
+        "{:?}",
+        colored
+    );
+    assert!(
+        colored.starts_with("\x1b[2m00000000\x1b[0m"),
+        "{:?}",
+        colored
+    );
+
+    // Dropping the escapes leaves the plain dump
+    let mut stripped = String::new();
+    let mut rest = colored.as_str();
+    while let Some(at) = rest.find('\x1b') {
+        stripped.push_str(&rest[..at]);
+        let end = rest[at..].find('m').expect("escape ends in m");
+        rest = &rest[at + end + 1..];
+    }
+    stripped.push_str(rest);
+    assert_eq!(stripped, String::from_utf8(plain)?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn color_setting_decides_hexdump_colors() -> anyhow::Result<()> {
+    // The piped stdout is no terminal, so auto leaves the dump plain
+    for (color, escaped) in [("always", true), ("never", false), ("auto", false)] {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port().to_string();
+        let server = tokio::spawn(async move {
+            let (mut socket, _peer) = listener.accept().await?;
+            socket.write_all(b"Hi\x01\xff").await
+        });
+        let out = time::timeout(
+            Duration::from_secs(5),
+            rcat()
+                .args(["--hexdump", "--color-hexdump", "--color", color])
+                .args(["127.0.0.1", &port])
+                .stdin(Stdio::null())
+                .output(),
+        )
+        .await??;
+        server.await??;
+        assert!(out.status.success());
+        assert_eq!(out.stdout.contains(&0x1b), escaped, "--color {}", color);
+        if !escaped {
+            assert!(String::from_utf8_lossy(&out.stdout).contains("|Hi..|"));
+        }
+    }
+
+    let args = cli::Args::try_parse_from(["r-cat", "--color-hexdump", "h", "1"]);
+    assert!(args.is_err(), "needs --hexdump");
+    Ok(())
+}
+
+#[test]
+fn timestamps_only_start_lines() {
+    use r_cat::filter::Filter;
+    use r_cat::format::Timestamp;
+
+    let mut stamp = Timestamp::default();
+    let mut out = Vec::new();
+    stamp.feed(b"one\ntw", &mut out);
+    stamp.feed(b"o\n", &mut out);
+    stamp.feed(b"three", &mut out);
+    let out = String::from_utf8(out).unwrap();
+
+    // "[HH:MM:SS.mmm] " is 15 bytes; the chunk continuing "two" gets none
+    let lines: Vec<&str> = out.split_inclusive('\n').collect();
+    assert_eq!(lines.len(), 3);
+    for (line, text) in lines.iter().zip(["one\n", "two\n", "three"]) {
+        let (stamp, rest) = line.split_at(15);
+        assert!(
+            stamp.starts_with('[') && stamp.ends_with("] "),
+            "{:?}",
+            line
+        );
+        assert_eq!(&stamp[3..4], ":");
+        assert_eq!(&stamp[9..10], ".");
+        assert_eq!(rest, text);
+    }
+}
+
+#[tokio::test]
+async fn timestamp_prefixes_received_output() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        socket.write_all(b"hello\n").await.expect("write");
+    });
+
+    let child = rcat()
+        .args(["--timestamp", "127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let output = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    server.await?;
+    assert_eq!(output.stdout.first(), Some(&b'['));
+    assert!(output.stdout.ends_with(b"] hello\n"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn log_file_records_both_directions() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let log = dir.path().join("session.log");
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        let mut buf = [0u8; 64];
+        let n = socket.read(&mut buf).await.expect("read");
+        socket.write_all(&buf[..n]).await.expect("write");
+    });
+
+    let mut child = rcat()
+        .args([
+            "--log-file",
+            log.to_str().unwrap(),
+            "-q",
+            "1",
+            "127.0.0.1",
+            &port,
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"ping\n").await?;
+    drop(stdin);
+
+    time::timeout(Duration::from_secs(5), server).await??;
+    time::timeout(Duration::from_secs(5), child.wait()).await??;
+    assert_eq!(std::fs::read_to_string(&log)?, "> ping\n< ping\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn unwritable_log_file_fails_at_startup() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let log = dir.path().join("missing").join("session.log");
+    let output = rcat()
+        .args(["--log-file", log.to_str().unwrap(), "127.0.0.1", "9"])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("cannot open log file"),
+        "stderr: {}",
+        stderr
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn udp_listener_hears_both_families() -> anyhow::Result<()> {
+    let mut child = rcat()
+        .args(["-u", "-l", "-p", "0", "-w", "1", "--print-ready"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stderr = BufReader::new(child.stderr.take().expect("stderr")).lines();
+    let ready = time::timeout(Duration::from_secs(5), stderr.next_line())
+        .await??
+        .expect("ready line");
+    let addr: std::net::SocketAddr = ready.strip_prefix("READY ").expect("READY").parse()?;
+    assert!(addr.is_ipv6(), "bound {}", addr);
+
+    let v6 = UdpSocket::bind("[::1]:0").await?;
+    v6.send_to(b"over v6\n", ("::1", addr.port())).await?;
+    time::sleep(Duration::from_millis(100)).await;
+    let v4 = UdpSocket::bind("127.0.0.1:0").await?;
+    v4.send_to(b"over v4\n", ("127.0.0.1", addr.port())).await?;
+
+    let output = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert_eq!(output.stdout, b"over v6\nover v4\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_exits_when_peer_closes_despite_open_stdin() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        socket.write_all(b"bye\n").await.expect("write");
+    });
+
+    let mut child = rcat()
+        .args(["127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // Hold stdin open for the whole test
+    let _stdin = child.stdin.take().expect("stdin");
+    server.await?;
+
+    let output = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"bye\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn half_close_keeps_sending_after_peer_eof() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        socket.write_all(b"go ahead\n").await.expect("write");
+        socket.shutdown().await.expect("shutdown");
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.expect("read");
+        received
+    });
+
+    let mut child = rcat()
+        .args(["--half-close", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    let mut stdout = child.stdout.take().expect("stdout");
+    let mut greeting = [0u8; 9];
+    time::timeout(Duration::from_secs(5), stdout.read_exact(&mut greeting)).await??;
+    assert_eq!(&greeting, b"go ahead\n");
+    // Well after the peer's EOF, input still goes out
+    time::sleep(Duration::from_millis(200)).await;
+    stdin.write_all(b"late\n").await?;
+    drop(stdin);
+
+    let received = time::timeout(Duration::from_secs(5), server).await??;
+    assert_eq!(received, b"late\n");
+    time::timeout(Duration::from_secs(5), child.wait()).await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn retry_waits_for_a_late_listener() -> anyhow::Result<()> {
+    let port = free_port();
+    let mut child = rcat()
+        .args(["-v", "--retry", "5", "--backoff-base", "0.1"])
+        .args(["127.0.0.1", &port.to_string()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Come up after the first attempts have been refused
+    time::sleep(Duration::from_millis(400)).await;
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    let (mut socket, _peer) = time::timeout(Duration::from_secs(5), listener.accept()).await??;
+    socket.write_all(b"finally\n").await?;
+    drop(socket);
+
+    let mut stdout = child.stdout.take().expect("stdout");
+    let mut received = Vec::new();
+    time::timeout(Duration::from_secs(5), stdout.read_to_end(&mut received)).await??;
+    assert_eq!(received, b"finally\n");
+    let output = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("retry 1/5"), "stderr: {}", stderr);
+    Ok(())
+}
+
+#[tokio::test]
+async fn retry_gives_up_after_its_budget() -> anyhow::Result<()> {
+    let port = free_port().to_string();
+    let output = rcat()
+        .args([
+            "-v",
+            "--retry",
+            "2",
+            "--backoff-base",
+            "0.05",
+            "127.0.0.1",
+            &port,
+        ])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("retry 2/2"), "stderr: {}", stderr);
+    assert!(!stderr.contains("retry 3/"), "stderr: {}", stderr);
+    Ok(())
+}
+
+#[test]
+fn shuffled_ports_keep_the_same_set() {
+    let original = cli::parse_ports("1-200").unwrap();
+    let mut first = original.clone();
+    cli::shuffle_ports_seeded(&mut first, 42);
+    assert_ne!(first, original);
+
+    // Same seed, same order
+    let mut again = original.clone();
+    cli::shuffle_ports_seeded(&mut again, 42);
+    assert_eq!(again, first);
+
+    let mut random = original.clone();
+    cli::shuffle_ports(&mut random);
+    for shuffled in [&mut first, &mut random] {
+        shuffled.sort_unstable();
+        assert_eq!(*shuffled, original);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn reuseport_lets_listeners_share_a_port() -> anyhow::Result<()> {
+    let port = free_port().to_string();
+    let spawn = |extra: &[&str]| {
+        rcat()
+            .args(["-l", "-p", &port, "-w", "2", "--print-ready"])
+            .args(extra)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+    };
+    let ready = |child: &mut tokio::process::Child| {
+        let stderr = child.stderr.take().expect("stderr");
+        async move {
+            let line = BufReader::new(stderr).lines().next_line().await;
+            line.ok().flatten().unwrap_or_default()
+        }
+    };
+
+    for proto in [&[][..], &["-u"][..]] {
+        let mut first = spawn(&[proto, &["--reuseport"]].concat())?;
+        let mut second = spawn(&[proto, &["--reuseport"]].concat())?;
+        for child in [&mut first, &mut second] {
+            let line = time::timeout(Duration::from_secs(5), ready(child)).await?;
+            assert!(line.starts_with("READY "), "{:?}: {}", proto, line);
+        }
+    }
+
+    // Without it, a second TCP listener cannot bind. UDP listeners set
+    // SO_REUSEADDR, which on Linux already lets them share the port
+    let _first = spawn(&[])?;
+    time::sleep(Duration::from_millis(200)).await;
+    let second = spawn(&[])?;
+    let output = time::timeout(Duration::from_secs(5), second.wait_with_output()).await??;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("in use"), "{}", stderr);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn keepalive_flags_tune_the_socket() -> anyhow::Result<()> {
+    fn sockopts(stderr: &[u8]) -> Vec<String> {
+        let stderr = String::from_utf8_lossy(stderr);
+        stderr
+            .lines()
+            .find_map(|l| l.strip_prefix("sockopts: "))
+            .unwrap_or_else(|| panic!("no sockopts line in {:?}", stderr))
+            .split(' ')
+            .map(str::to_string)
+            .collect()
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        let mut buf = [0u8; 5];
+        socket.read_exact(&mut buf).await.expect("read");
+        socket.write_all(&buf).await.expect("write");
+    });
+    let mut child = rcat()
+        .args(["--keepalive", "--keepalive-idle", "30"])
+        .args([
+            "--keepalive-interval",
+            "5",
+            "--dump-sockopts",
+            "127.0.0.1",
+            &port,
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"alive").await?;
+    server.await?;
+    drop(stdin);
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert_eq!(out.stdout, b"alive");
+    let dump = sockopts(&out.stderr);
+    for opt in ["SO_KEEPALIVE=1", "TCP_KEEPIDLE=30", "TCP_KEEPINTVL=5"] {
+        assert!(
+            dump.iter().any(|o| o == opt),
+            "{} missing from {:?}",
+            opt,
+            dump
+        );
+    }
+
+    // Listen mode, with the system's probe timing
+    let port = free_port();
+    let child = rcat()
+        .args([
+            "-l",
+            "--keepalive",
+            "--dump-sockopts",
+            "-p",
+            &port.to_string(),
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    drop(connect_retry(port).await?);
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(sockopts(&out.stderr).iter().any(|o| o == "SO_KEEPALIVE=1"));
+
+    assert!(cli::Args::try_parse_from(["r-cat", "--keepalive-idle", "30", "h", "1"]).is_err());
+    let args = cli::Args::parse_from([
+        "r-cat",
+        "--keepalive",
+        "--keepalive-idle",
+        "4294967295",
+        "h",
+        "1",
+    ]);
+    assert!(r_cat::net::Config::try_from(&args).is_err());
+    Ok(())
+}
+
+#[test]
+fn tos_accepts_keywords_and_numbers() {
+    assert_eq!(cli::parse_tos("lowdelay").unwrap(), 0x10);
+    assert_eq!(cli::parse_tos("mincost").unwrap(), 0x02);
+    assert_eq!(cli::parse_tos("0x10").unwrap(), 0x10);
+    assert_eq!(cli::parse_tos("184").unwrap(), 0xb8);
+    assert!(cli::parse_tos("256").is_err());
+    let err = cli::parse_tos("fast").unwrap_err().to_string();
+    assert!(
+        err.contains("lowdelay, throughput, reliability, mincost"),
+        "{}",
+        err
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn tos_flag_sets_ip_tos() -> anyhow::Result<()> {
+    fn sockopts(stderr: &[u8]) -> String {
+        let stderr = String::from_utf8_lossy(stderr);
+        stderr
+            .lines()
+            .find_map(|l| l.strip_prefix("sockopts: "))
+            .unwrap_or_else(|| panic!("no sockopts line in {:?}", stderr))
+            .to_string()
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    tokio::spawn(async move { drop(listener.accept().await) });
+    let out = rcat()
+        .args(["-T", "0x10", "--dump-sockopts", "127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(out.status.success());
+    let dump = sockopts(&out.stderr);
+    assert!(dump.split(' ').any(|o| o == "IP_TOS=0x10"), "{}", dump);
+
+    let port = free_port();
+    let out = rcat()
+        .args(["-u", "-T", "throughput", "--dump-sockopts", "-w", "1"])
+        .args(["127.0.0.1", &port.to_string()])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    let dump = sockopts(&out.stderr);
+    assert!(dump.split(' ').any(|o| o == "IP_TOS=0x08"), "{}", dump);
+    Ok(())
+}
+
+#[tokio::test]
+async fn recv_only_prints_without_forwarding_input() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    // An echo server with a greeting: anything forwarded would come back
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        socket.write_all(b"welcome\n").await.expect("write");
+        let mut buf = [0u8; 64];
+        // Neither data nor an EOF should arrive while the client is up
+        let idle = time::timeout(Duration::from_millis(500), socket.read(&mut buf)).await;
+        assert!(idle.is_err(), "client sent {:?}", idle);
+    });
+
+    let mut child = rcat()
+        .args(["--recv-only", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"should stay local\n").await?;
+    drop(stdin);
+
+    time::timeout(Duration::from_secs(5), server).await??;
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"welcome\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn send_only_ignores_what_the_peer_sends() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        socket.write_all(b"noise\n").await.expect("write");
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.expect("read");
+        received
+    });
+
+    let mut child = rcat()
+        .args(["--send-only", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"payload\n").await?;
+    drop(stdin);
+
+    let received = time::timeout(Duration::from_secs(5), server).await??;
+    assert_eq!(received, b"payload\n");
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty(), "{:?}", out.stdout);
+
+    assert!(cli::Args::try_parse_from(["r-cat", "--send-only", "--recv-only", "h", "1"]).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn cancel_token_stops_sessions_promptly() -> anyhow::Result<()> {
+    use r_cat::net::{CancelToken, Config, tcp};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        // Stay connected and silent, so only the cancel can end the session
+        let (_socket, _peer) = listener.accept().await?;
+        time::sleep(Duration::from_secs(30)).await;
+        anyhow::Ok(())
+    });
+
+    // --recv-only keeps the session off the test's stdin
+    let cancel = CancelToken::new();
+    let config = Config {
+        recv_only: true,
+        cancel: Some(cancel.clone()),
+        ..Config::default()
+    };
+    let session = {
+        let config = config.clone();
+        tokio::spawn(async move { tcp::client("127.0.0.1", port, &config).await })
+    };
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(!session.is_finished());
+    cancel.cancel();
+    let stats = time::timeout(Duration::from_secs(2), session).await???;
+    assert_eq!(stats.bytes_sent, 0);
+    assert!(stats.duration >= Duration::from_millis(100));
+
+    // An already cancelled token ends a listener still waiting to accept
+    let stats = time::timeout(Duration::from_secs(2), tcp::listen(0, &config)).await??;
+    assert_eq!(stats, Default::default());
+    Ok(())
+}
+
+#[test]
+fn telnet_refuses_options_and_strips_commands() {
+    use r_cat::net::telnet::{DO, DONT, IAC, SB, SE, Telnet, WILL, WONT};
+    const ECHO: u8 = 1;
+
+    let mut telnet = Telnet::new();
+    let (mut out, mut reply) = (Vec::new(), Vec::new());
+    telnet.filter(&[IAC, DO, ECHO], &mut out, &mut reply);
+    assert!(out.is_empty());
+    assert_eq!(reply, [IAC, WONT, ECHO]);
+
+    // Split across reads, with data, an escaped 0xFF and a subnegotiation
+    let (mut out, mut reply) = (Vec::new(), Vec::new());
+    telnet.filter(b"ab\xff", &mut out, &mut reply);
+    telnet.filter(&[WILL], &mut out, &mut reply);
+    telnet.filter(
+        &[3, b'c', IAC, IAC, IAC, SB, 24, 1, IAC],
+        &mut out,
+        &mut reply,
+    );
+    telnet.filter(
+        &[SE, b'd', IAC, WONT, ECHO, IAC, DONT, ECHO],
+        &mut out,
+        &mut reply,
+    );
+    assert_eq!(out, b"abc\xffd");
+    // The peer's own refusals need no answer
+    assert_eq!(reply, [IAC, DONT, 3]);
+}
+
+#[tokio::test]
+async fn telnet_flag_answers_negotiation() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await.expect("accept");
+        socket
+            .write_all(b"\xff\xfd\x18login: ")
+            .await
+            .expect("write");
+        let mut reply = [0u8; 3];
+        socket.read_exact(&mut reply).await.expect("read");
+        reply
+    });
+
+    // Keep stdin open: once our side is shut down there is no answering
+    let mut child = rcat()
+        .args(["-t", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let stdin = child.stdin.take().expect("stdin");
+    let reply = time::timeout(Duration::from_secs(5), server).await??;
+    assert_eq!(reply, [0xff, 0xfc, 0x18]);
+    drop(stdin);
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert_eq!(out.stdout, b"login: ");
+    Ok(())
+}
+
+#[tokio::test]
+async fn telnet_replies_go_through_the_write_side() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let log = dir.path().join("session.log");
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    let server = tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        socket.write_all(b"\xff\xfb\x01ok\n").await?;
+        let mut reply = [0u8; 3];
+        socket.read_exact(&mut reply).await?;
+        anyhow::Ok(reply)
+    });
+
+    // Even with nothing of its own to send, and through the tee
+    let child = rcat()
+        .args(["-t", "--recv-only", "--log-file"])
+        .arg(&log)
+        .args(["127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let reply = time::timeout(Duration::from_secs(5), server).await???;
+    assert_eq!(reply, [0xff, 0xfe, 0x01]);
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert_eq!(out.stdout, b"ok\n");
+    let logged = std::fs::read(&log)?;
+    assert!(
+        logged.windows(5).any(|w| w == b"> \xff\xfe\x01"),
+        "{:?}",
+        String::from_utf8_lossy(&logged)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn exit_status_reports_the_connection_outcome() -> anyhow::Result<()> {
+    let closed = free_port().to_string();
+    let out = rcat()
+        .args(["127.0.0.1", &closed])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert_eq!(out.status.code(), Some(2), "{:?}", out);
+
+    // Nobody connects within -w
+    let out = rcat()
+        .args(["-l", "-w", "1", "-p", &free_port().to_string()])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert_eq!(out.status.code(), Some(3), "{:?}", out);
+
+    let out = rcat()
+        .args(["127.0.0.1"])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert_eq!(out.status.code(), Some(1), "{:?}", out);
+    Ok(())
+}
+
+#[tokio::test]
+async fn stdin_eof_half_closes_unless_no_shutdown() -> anyhow::Result<()> {
+    for no_shutdown in [false, true] {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port().to_string();
+        let server = tokio::spawn(async move {
+            let (mut socket, _peer) = listener.accept().await.expect("accept");
+            let mut received = Vec::new();
+            let eof = time::timeout(
+                Duration::from_millis(500),
+                socket.read_to_end(&mut received),
+            )
+            .await
+            .is_ok();
+            // Our reply still gets through after the client's FIN
+            socket.write_all(b"bye\n").await.expect("write");
+            (eof, received)
+        });
+
+        let mut child = rcat();
+        if no_shutdown {
+            child.arg("--no-shutdown");
+        }
+        let mut child = child
+            .args(["127.0.0.1", &port])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin");
+        stdin.write_all(b"done\n").await?;
+        drop(stdin);
+
+        let (eof, received) = time::timeout(Duration::from_secs(5), server).await??;
+        assert_eq!(eof, !no_shutdown);
+        assert_eq!(received, b"done\n");
+        let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+        assert_eq!(out.stdout, b"bye\n");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn input_file_replaces_stdin() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("request.txt");
+    std::fs::write(&path, b"first line\nsecond line\n")?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        let (mut reader, mut writer) = socket.split();
+        tokio::io::copy(&mut reader, &mut writer).await?;
+        anyhow::Ok(())
+    });
+
+    let out = rcat()
+        .arg("--input-file")
+        .arg(&path)
+        .args(["127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"first line\nsecond line\n");
+
+    // Checked before anything connects: this is not reported as refused
+    let out = rcat()
+        .arg("--input-file")
+        .arg(dir.path().join("missing.txt"))
+        .args(["127.0.0.1", &free_port().to_string()])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("cannot open input file"), "{}", stderr);
+    Ok(())
+}
+
+#[tokio::test]
+async fn output_file_receives_a_download() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("download.bin");
+    let payload: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+
+    for (body, append) in [(payload.clone(), false), (b"tail\n".to_vec(), true)] {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _peer) = listener.accept().await?;
+            socket.write_all(&body).await?;
+            anyhow::Ok(())
+        });
+
+        let mut cmd = rcat();
+        cmd.args(["--recv-only", "--output-file"]).arg(&path);
+        if append {
+            cmd.arg("--append");
+        }
+        let out = cmd
+            .args(["127.0.0.1", &port])
+            .stdin(Stdio::null())
+            .output()
+            .await?;
+        assert!(out.status.success(), "{:?}", out);
+        assert!(out.stdout.is_empty());
+    }
+
+    let mut expected = payload;
+    expected.extend_from_slice(b"tail\n");
+    assert!(std::fs::read(&path)? == expected, "file contents differ");
+
+    // Without --append, the file starts over
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    tokio::spawn(async move {
+        let (mut socket, _peer) = listener.accept().await?;
+        socket.write_all(b"fresh\n").await?;
+        anyhow::Ok(())
+    });
+    let out = rcat()
+        .arg("--output-file")
+        .arg(&path)
+        .args(["127.0.0.1", &port])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(out.status.success());
+    assert_eq!(std::fs::read(&path)?, b"fresh\n");
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn unwritable_output_fails_the_session() -> anyhow::Result<()> {
+    for to_file in [true, false] {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _peer) = listener.accept().await?;
+            socket.write_all(b"nowhere to go\n").await?;
+            // Stay open so only the failed write can end the session
+            time::sleep(Duration::from_secs(10)).await;
+            anyhow::Ok(())
+        });
+
+        let mut cmd = rcat();
+        if to_file {
+            cmd.args(["--output-file", "/dev/full"]);
+        } else {
+            cmd.stdout(std::fs::File::create("/dev/full")?);
+        }
+        let child = cmd
+            .args(["127.0.0.1", &port])
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(
+            out.status.code(),
+            Some(1),
+            "to_file={}: {}",
+            to_file,
+            stderr
+        );
+    }
+
+    // The UDP receive side fails the same way
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let port = server.local_addr()?.port().to_string();
+    let mut child = rcat()
+        .args(["-u", "--output-file", "/dev/full", "127.0.0.1", &port])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"ping\n").await?;
+    let mut buf = [0u8; 64];
+    let (_, peer) = time::timeout(Duration::from_secs(5), server.recv_from(&mut buf)).await??;
+    server.send_to(b"pong\n", peer).await?;
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert_eq!(out.status.code(), Some(1), "udp: {}", stderr);
+    drop(stdin);
+    Ok(())
+}
+
+#[tokio::test]
+async fn repeated_verbose_adds_detail() -> anyhow::Result<()> {
+    async fn session(verbose: &str) -> anyhow::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _peer) = listener.accept().await?;
+            socket.write_all(b"hello\n").await?;
+            anyhow::Ok(())
+        });
+        let out = rcat()
+            .args([verbose, "--recv-only", "127.0.0.1", &port])
+            .stdin(Stdio::null())
+            .output()
+            .await?;
+        assert!(out.status.success(), "{:?}", out);
+        assert_eq!(out.stdout, b"hello\n");
+        Ok(String::from_utf8(out.stderr)?)
+    }
+
+    let one = session("-v").await?;
+    assert!(one.contains("Connecting to"), "{}", one);
+    assert!(!one.contains("Received 6 bytes"), "{}", one);
+
+    let two = session("-vv").await?;
+    assert!(two.contains("Received 6 bytes"), "{}", two);
+    assert!(two.contains("Session between 127.0.0.1:"), "{}", two);
+    assert!(!two.contains("Peer closed its write half"), "{}", two);
+
+    let three = session("-vvv").await?;
+    assert!(three.contains("Peer closed its write half"), "{}", three);
+    Ok(())
+}
+
+#[tokio::test]
+async fn input_read_errors_are_not_taken_for_eof() -> anyhow::Result<()> {
+    use r_cat::net::{InputError, InputReader};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    struct Broken;
+    impl tokio::io::AsyncRead for Broken {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+    }
+
+    let mut buf = [0u8; 16];
+    let err = InputReader::new(Broken)
+        .read(&mut buf)
+        .await
+        .expect_err("read should fail");
+    assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    assert!(InputError::caused(&err));
+    assert!(err.to_string().starts_with("reading input: "), "{}", err);
+    assert_eq!(
+        InputReader::new(tokio::io::empty()).read(&mut buf).await?,
+        0
+    );
+    // Socket errors stay unmarked
+    assert!(!InputError::caused(&std::io::ErrorKind::BrokenPipe.into()));
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn unreadable_input_fails_the_session() -> anyhow::Result<()> {
+    for udp in [false, true] {
+        // Reading the unmapped start of /proc/self/mem fails with EIO
+        let mut cmd = rcat();
+        let _peer = if udp {
+            let server = UdpSocket::bind("127.0.0.1:0").await?;
+            cmd.args(["-u", "127.0.0.1", &server.local_addr()?.port().to_string()]);
+            Box::new(server) as Box<dyn Send>
+        } else {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            cmd.args(["127.0.0.1", &listener.local_addr()?.port().to_string()]);
+            // Hold the connection open: only the input failing ends it
+            let server = tokio::spawn(async move {
+                let (socket, _peer) = listener.accept().await?;
+                time::sleep(Duration::from_secs(10)).await;
+                drop(socket);
+                anyhow::Ok(())
+            });
+            Box::new(server) as Box<dyn Send>
+        };
+        let out = cmd
+            .args(["--input-file", "/proc/self/mem"])
+            .stdin(Stdio::null())
+            .output();
+        let out = time::timeout(Duration::from_secs(5), out).await??;
+        assert_eq!(out.status.code(), Some(1), "{:?}", out);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("reading input: "), "{}", stderr);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn chat_relays_lines_between_clients() -> anyhow::Result<()> {
+    async fn next_line(client: &mut BufReader<tokio::net::TcpStream>) -> anyhow::Result<String> {
+        let mut line = String::new();
+        time::timeout(Duration::from_secs(5), client.read_line(&mut line)).await??;
+        Ok(line)
+    }
+
+    let mut child = rcat()
+        .args(["-l", "-k", "--chat", "-p", "0", "--print-ready"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stderr = BufReader::new(child.stderr.take().expect("stderr")).lines();
+    let line = time::timeout(Duration::from_secs(5), stderr.next_line())
+        .await??
+        .expect("ready line");
+    let addr: std::net::SocketAddr = line.strip_prefix("READY ").expect("READY prefix").parse()?;
+    let join = || async move {
+        let stream = tokio::net::TcpStream::connect(("127.0.0.1", addr.port())).await?;
+        anyhow::Ok(BufReader::new(stream))
+    };
+    let mut a = join().await?;
+    let mut b = join().await?;
+    // Hearing b proves both are registered
+    b.get_mut().write_all(b"hi from b\n").await?;
+    assert_eq!(next_line(&mut a).await?, "user2: hi from b\n");
+    a.get_mut().write_all(b"hello").await?;
+    drop(a);
+    assert_eq!(next_line(&mut b).await?, "user1: hello\n");
+
+    // The relay carries on without the client that left
+    let mut c = join().await?;
+    c.get_mut().write_all(b"c here\n").await?;
+    assert_eq!(next_line(&mut b).await?, "user3: c here\n");
+
+    child.kill().await?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn chat_keeps_accepting_after_a_failed_accept() -> anyhow::Result<()> {
+    let mut child = rcat()
+        .args(["-l", "-k", "--chat", "-p", "0", "--print-ready"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stderr = BufReader::new(child.stderr.take().expect("stderr")).lines();
+    let line = time::timeout(Duration::from_secs(5), stderr.next_line())
+        .await??
+        .expect("ready line");
+    let addr: std::net::SocketAddr = line.strip_prefix("READY ").expect("READY prefix").parse()?;
+
+    // Leave the relay no descriptor to accept into
+    let pid = child.id().expect("pid") as libc::pid_t;
+    let highest = std::fs::read_dir(format!("/proc/{}/fd", pid))?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u64>().ok())
+        .max()
+        .expect("open descriptors");
+    let mut old = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let rc = unsafe { libc::prlimit(pid, libc::RLIMIT_NOFILE, std::ptr::null(), &mut old) };
+    assert_eq!(rc, 0, "prlimit: {}", std::io::Error::last_os_error());
+    let tight = libc::rlimit {
+        rlim_cur: highest + 1,
+        ..old
+    };
+    let rc = unsafe { libc::prlimit(pid, libc::RLIMIT_NOFILE, &tight, std::ptr::null_mut()) };
+    assert_eq!(rc, 0, "prlimit: {}", std::io::Error::last_os_error());
+
+    // Any gaps below the limit still take a client or two
+    let mut waiting = Vec::new();
+    let failed = loop {
+        waiting.push(tokio::net::TcpStream::connect(addr).await?);
+        let next = time::timeout(Duration::from_millis(500), stderr.next_line()).await;
+        if let Ok(line) = next {
+            break line?.expect("stderr line");
+        }
+        assert!(waiting.len() < 20, "accept never failed");
+    };
+    assert!(failed.starts_with("Chat: accept failed"), "{}", failed);
+
+    // With descriptors to spare again, the relay is still there
+    let rc = unsafe { libc::prlimit(pid, libc::RLIMIT_NOFILE, &old, std::ptr::null_mut()) };
+    assert_eq!(rc, 0);
+    let mut a = BufReader::new(tokio::net::TcpStream::connect(addr).await?);
+    let mut b = tokio::net::TcpStream::connect(addr).await?;
+    let mut heard = String::new();
+    // b may join after its first line went out, so keep saying it
+    let said = async {
+        loop {
+            if let Err(e) = b.write_all(b"still here\n").await {
+                return e;
+            }
+            time::sleep(Duration::from_millis(200)).await;
+        }
+    };
+    tokio::select! {
+        read = time::timeout(Duration::from_secs(5), a.read_line(&mut heard)) => {
+            read??;
+        }
+        e = said => return Err(e.into()),
+    }
+    assert!(heard.ends_with(": still here\n"), "{:?}", heard);
+    assert!(child.try_wait()?.is_none());
+    Ok(())
+}
+
+#[test]
+fn unicode_hostnames_become_punycode() {
+    use r_cat::net::{idn, util};
+
+    assert_eq!(
+        idn::to_ascii("müller.example").unwrap(),
+        "xn--mller-kva.example"
+    );
+    assert_eq!(idn::to_ascii("MÜNCHEN.de.").unwrap(), "xn--mnchen-3ya.de.");
+    assert_eq!(idn::to_ascii("例子.test").unwrap(), "xn--fsqu00a.test");
+    // ASCII names and IP literals pass through untouched
+    assert_eq!(idn::to_ascii("Example.COM").unwrap(), "Example.COM");
+    assert_eq!(
+        util::ascii_destination("bücher.example:443").unwrap(),
+        "xn--bcher-kva.example:443"
+    );
+    assert_eq!(util::ascii_destination("[::1]:80").unwrap(), "[::1]:80");
+
+    for bad in ["mü..example", "bad näme.example", &"ü".repeat(70)] {
+        let err = idn::to_ascii(bad).expect_err(bad);
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(
+            err.to_string()
+                .contains("invalid internationalized hostname")
+        );
+    }
+}
+
+#[tokio::test]
+async fn source_port_range_picks_random_ports_within_it() -> anyhow::Result<()> {
+    assert_eq!(
+        cli::parse_port_range("40010-40000").unwrap(),
+        (40000, 40010)
+    );
+    assert!(cli::parse_port_range("40000").is_err());
+    assert!(cli::parse_port_range("0-10").is_err());
+    let args = cli::Args::parse_from(["r-cat", "--source-port-range", "1-2", "h", "1"]);
+    assert!(
+        r_cat::net::Config::try_from(&args).is_err(),
+        "needs -z or -r"
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port().to_string();
+    let (lo, hi) = (47100, 47199);
+    for _ in 0..5 {
+        let scan = rcat()
+            .args([
+                "-z",
+                "--source-port-range",
+                "47100-47199",
+                "127.0.0.1",
+                &port,
+            ])
+            .stdin(Stdio::null())
+            .output();
+        let (out, accepted) = tokio::join!(scan, listener.accept());
+        let out = out?;
+        assert!(out.status.success(), "{:?}", out);
+        let source = accepted?.1.port();
+        assert!((lo..=hi).contains(&source), "source port {}", source);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn signal_handler_cancels_the_token() -> anyhow::Result<()> {
+    use r_cat::net::{CancelToken, install_signal_handler};
+
+    let token = CancelToken::new();
+    install_signal_handler(token.clone())?;
+    assert!(!token.is_cancelled());
+    // SAFETY: the handler installed above catches the signal
+    unsafe { libc::raise(libc::SIGTERM) };
+    time::timeout(Duration::from_secs(5), token.cancelled()).await?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn sigint_ends_the_session_with_stats() -> anyhow::Result<()> {
+    let mut child = rcat()
+        .args(["-v", "-l", "-p", "0", "--print-ready"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stderr = BufReader::new(child.stderr.take().expect("stderr")).lines();
+    let addr = loop {
+        let line = time::timeout(Duration::from_secs(5), stderr.next_line())
+            .await??
+            .expect("ready line");
+        if let Some(addr) = line.strip_prefix("READY ") {
+            break addr.parse::<std::net::SocketAddr>()?;
+        }
+    };
+    let mut client = tokio::net::TcpStream::connect(("127.0.0.1", addr.port())).await?;
+    client.write_all(b"hello\n").await?;
+    let mut stdout = BufReader::new(child.stdout.take().expect("stdout"));
+    let mut line = String::new();
+    time::timeout(Duration::from_secs(5), stdout.read_line(&mut line)).await??;
+    assert_eq!(line, "hello\n");
+
+    let pid = child.id().expect("pid") as libc::pid_t;
+    // SAFETY: plain kill(2) on our own child
+    assert_eq!(unsafe { libc::kill(pid, libc::SIGINT) }, 0);
+    let status = time::timeout(Duration::from_secs(5), child.wait()).await??;
+    assert_eq!(status.code(), Some(130));
+    let mut rest = Vec::new();
+    while let Some(line) = stderr.next_line().await? {
+        rest.push(line);
+    }
+    assert!(rest.iter().any(|l| l == "sent 0, rcvd 6"), "{:?}", rest);
+    Ok(())
+}
+
+#[tokio::test]
+async fn also_fans_input_out_to_every_destination() -> anyhow::Result<()> {
+    let mut ports = Vec::new();
+    let mut servers = Vec::new();
+    for _ in 0..2 {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        ports.push(listener.local_addr()?.port().to_string());
+        servers.push(tokio::spawn(async move {
+            let (mut socket, _peer) = listener.accept().await?;
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await?;
+            socket.write_all(&received).await?;
+            anyhow::Ok(received)
+        }));
+    }
+    // Nothing listens here, which must not hold up the other two
+    let refused = format!("127.0.0.1:{}", free_port());
+
+    let mut child = rcat()
+        .args(["127.0.0.1", &ports[0], "--also"])
+        .arg(format!("127.0.0.1:{}", ports[1]))
+        .args(["--also", &refused])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"ping\n").await?;
+    drop(stdin);
+
+    for server in servers {
+        let received = time::timeout(Duration::from_secs(5), server).await???;
+        assert_eq!(received, b"ping\n");
+    }
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success(), "{:?}", out);
+    let stdout = String::from_utf8(out.stdout)?;
+    for port in &ports {
+        let line = format!("127.0.0.1:{}: ping\n", port);
+        assert!(stdout.contains(&line), "{:?}", stdout);
+    }
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains(&format!("Connecting to {} failed", refused)),
+        "{}",
+        stderr
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn also_applies_send_filters_to_every_destination() -> anyhow::Result<()> {
+    let mut ports = Vec::new();
+    let mut servers = Vec::new();
+    for _ in 0..2 {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        ports.push(listener.local_addr()?.port().to_string());
+        servers.push(tokio::spawn(async move {
+            let (mut socket, _peer) = listener.accept().await?;
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await?;
+            anyhow::Ok(received)
+        }));
+    }
+
+    let mut child = rcat()
+        .args(["-C", "--eof-marker", ".\\r\\n", "--annotate"])
+        .args(["127.0.0.1", &ports[0], "--also"])
+        .arg(format!("127.0.0.1:{}", ports[1]))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin");
+    stdin.write_all(b"a\nb\n").await?;
+    drop(stdin);
+
+    for server in servers {
+        let received = time::timeout(Duration::from_secs(5), server).await???;
+        assert_eq!(received, b"a\r\nb\r\n.\r\n");
+    }
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success(), "{:?}", out);
+    // The taps see what went to each destination
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let marker = ">> 3 bytes: .\\r\\n";
+    assert_eq!(stderr.matches(marker).count(), 2, "{}", stderr);
+
+    // What the fan-out cannot honour is refused rather than ignored
+    let out = rcat()
+        .args(["--hexdump", "127.0.0.1", &ports[0], "--also", "127.0.0.1:1"])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    assert!(!out.status.success());
+    Ok(())
+}
+
+#[tokio::test]
+async fn also_stops_reading_a_silent_destination_after_w() -> anyhow::Result<()> {
+    let talker = TcpListener::bind("127.0.0.1:0").await?;
+    let talker_port = talker.local_addr()?.port().to_string();
+    tokio::spawn(async move {
+        let (mut socket, _peer) = talker.accept().await?;
+        socket.write_all(b"hello\n").await?;
+        anyhow::Ok(())
+    });
+    // Accepts and then says nothing, never closing
+    let silent = TcpListener::bind("127.0.0.1:0").await?;
+    let silent_port = silent.local_addr()?.port().to_string();
+    tokio::spawn(async move {
+        let (_socket, _peer) = silent.accept().await?;
+        time::sleep(Duration::from_secs(30)).await;
+        anyhow::Ok(())
+    });
+
+    let out = rcat()
+        .args(["-v", "-w", "1", "127.0.0.1", &talker_port, "--also"])
+        .arg(format!("127.0.0.1:{}", silent_port))
+        .stdin(Stdio::null())
+        .output();
+    let out = time::timeout(Duration::from_secs(5), out).await??;
+    assert!(out.status.success(), "{:?}", out);
+    let stdout = String::from_utf8(out.stdout)?;
+    assert_eq!(stdout, format!("127.0.0.1:{}: hello\n", talker_port));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let idle = format!("127.0.0.1:{} idle for 1s, closing", silent_port);
+    assert!(stderr.contains(&idle), "{}", stderr);
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_conns_stops_the_listener() -> anyhow::Result<()> {
+    let port = free_port();
+    let child = rcat()
+        .args(["-l", "-k", "--max-conns", "2", "-p", &port.to_string()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    for msg in [&b"one\n"[..], b"two\n"] {
+        let mut client = connect_retry(port).await?;
+        client.write_all(msg).await?;
+        client.shutdown().await?;
+        // The listener closes once it has seen our FIN
+        let mut rest = Vec::new();
+        time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest)).await??;
+    }
+    let out = time::timeout(Duration::from_secs(5), child.wait_with_output()).await??;
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"one\ntwo\n");
+    let third = tokio::net::TcpStream::connect(("127.0.0.1", port)).await;
+    assert!(third.is_err(), "a third connection was accepted");
+
+    let args = cli::Args::try_parse_from(["r-cat", "-l", "--max-conns", "2", "-p", "1"]);
+    assert!(args.is_err(), "--max-conns needs -k");
+    Ok(())
+}
+
+#[tokio::test]
+async fn accept_rate_spaces_out_accepts() -> anyhow::Result<()> {
+    let port = free_port();
+    let child = rcat()
+        .args([
+            "-l",
+            "-k",
+            "--accept-rate",
+            "5",
+            "--max-conns",
+            "6",
+            "-p",
+            &port.to_string(),
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let started = std::time::Instant::now();
+    // All six connect at once; the listener takes one every 200ms
+    let mut clients = vec![connect_retry(port).await?];
+    for _ in 1..6 {
+        clients.push(tokio::net::TcpStream::connect(("127.0.0.1", port)).await?);
+    }
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.write_all(format!("c{}\n", i).as_bytes()).await?;
+        client.shutdown().await?;
+    }
+    let out = time::timeout(Duration::from_secs(10), child.wait_with_output()).await??;
+    assert!(out.status.success());
+    assert!(
+        started.elapsed() >= Duration::from_millis(900),
+        "six accepts took {:?}",
+        started.elapsed()
+    );
+    let mut lines: Vec<&str> = std::str::from_utf8(&out.stdout)?.lines().collect();
+    lines.sort_unstable();
+    assert_eq!(lines, ["c0", "c1", "c2", "c3", "c4", "c5"]);
+
+    let args = cli::Args::try_parse_from(["r-cat", "-l", "--accept-rate", "5", "-p", "1"]);
+    assert!(args.is_err(), "--accept-rate needs -k");
+    let args = cli::Args::parse_from(["r-cat", "-l", "-k", "-u", "--accept-rate", "5", "-p", "1"]);
+    assert!(r_cat::net::Config::try_from(&args).is_err(), "TCP only");
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_conns_caps_chat_clients() -> anyhow::Result<()> {
+    let port = free_port();
+    let mut child = rcat()
+        .args([
+            "-l",
+            "-k",
+            "--chat",
+            "--max-conns",
+            "1",
+            "-p",
+            &port.to_string(),
+        ])
+        .stdin(Stdio::null())
+        .spawn()?;
+    let first = connect_retry(port).await?;
+    // Hold the only slot: the next client is disconnected at once
+    let mut second = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+    let mut buf = [0u8; 16];
+    let n = time::timeout(Duration::from_secs(5), second.read(&mut buf)).await?;
+    assert!(matches!(n, Ok(0) | Err(_)), "{:?}", n);
+    drop(first);
+    child.kill().await?;
+    Ok(())
 }